@@ -0,0 +1,40 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// A Bloom filter: a fixed size bit set answering "maybe seen" or "surely not seen".
+/// Memory doesn't grow with the inserted items, but an unseen item is reported as seen with probability `fp_rate`
+/// (as long as no more than `capacity` items are inserted, after which the rate worsens).
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize, fp_rate: f64) -> Self {
+        // optimal sizes: m = -n ln(p) / ln(2)^2, k = m/n ln(2)
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity.max(1) as f64) * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let hashes = ((bits as f64 / capacity.max(1) as f64) * ln2).round().max(1.0) as u64;
+
+        Self { bits: vec![0; bits.div_ceil(64) as usize], hashes }
+    }
+
+    /// Marks the item as seen. Returns false if it was (probably) already seen.
+    pub fn insert(&mut self, item: &impl Hash) -> bool {
+        // double hashing: the k positions are h1 + i * h2
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        h1.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let len = self.bits.len() as u64 * 64;
+        let mut new = false;
+        for i in 0..self.hashes {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % len;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        new
+    }
+}
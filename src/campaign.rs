@@ -0,0 +1,276 @@
+//! Campaigns (`campaign` mode): the Filter targets listed in a manifest, scraped one after the other,
+//! each into its own output and all together into a deduplicated master output.
+//!
+//! The manifest is a small subset of TOML: `key = "value"` lines, and a `[[target]]` table for each target.
+//! The keys before the first table are the defaults of every target, e.g. a category shared by many regions:
+//!
+//! ```toml
+//! category = "ristoranti"
+//!
+//! [[target]]
+//! region = "lazio"
+//! city = "roma"
+//!
+//! [[target]]
+//! region = "toscana"
+//! ```
+
+use crate::{cli::{FilterMode, FilterOptions}, error::ScraperError};
+
+const KEYS: [&str; 3] = ["region", "city", "category"];
+
+#[derive(Default, Clone)]
+struct Fields {
+    region: Option<String>,
+    city: Option<String>,
+    category: Option<String>,
+}
+
+impl Fields {
+    fn get_mut(&mut self, key: &str) -> Option<&mut Option<String>> {
+        match key {
+            "region" => Some(&mut self.region),
+            "city" => Some(&mut self.city),
+            "category" => Some(&mut self.category),
+            _ => None,
+        }
+    }
+}
+
+/// The Filter scrapes of the manifest targets, all with the given options. An empty value unsets a default.
+pub fn parse_targets(manifest: &str, options: &FilterOptions) -> Result<Vec<FilterMode>, ScraperError> {
+    let mut defaults = Fields::default();
+    let mut targets = Vec::<Fields>::new();
+    let mut seen = Vec::new();
+
+    for (i, line) in manifest.lines().enumerate() {
+        let error = |message: String| ScraperError::Parse(format!("manifest, riga {}: {message}", i + 1));
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "[[target]]" {
+            targets.push(Fields::default());
+            seen.clear();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(error(format!("attesa una riga `chiave = \"valore\"` o `[[target]]`, trovato `{line}`")));
+        };
+        let key = key.trim();
+        let fields = targets.last_mut().unwrap_or(&mut defaults);
+        let Some(field) = fields.get_mut(key) else {
+            return Err(error(format!("chiave `{key}` sconosciuta, quelle disponibili sono {}", KEYS.join(", "))));
+        };
+        let Some(value) = parse_string(value.trim()) else {
+            return Err(error(format!("il valore di `{key}` deve essere una stringa tra virgolette")));
+        };
+        if seen.contains(&key) {
+            return Err(error(format!("chiave `{key}` ripetuta")));
+        }
+        seen.push(key);
+        *field = Some(value);
+    }
+
+    if targets.is_empty() {
+        return Err(ScraperError::Parse("il manifest non ha alcun `[[target]]`".to_string()));
+    }
+
+    let non_empty = |value: Option<String>| value.filter(|v| !v.is_empty());
+    targets.into_iter()
+        .enumerate()
+        .map(|(i, target)| {
+            let or_default = |value: Option<String>, default: &Option<String>| non_empty(value.or_else(|| default.clone()));
+            Ok(FilterMode {
+                region: or_default(target.region, &defaults.region)
+                    .ok_or_else(|| ScraperError::Parse(format!("il target {} del manifest non ha una regione", i + 1)))?,
+                city: or_default(target.city, &defaults.city),
+                category: or_default(target.category, &defaults.category),
+                options: options.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The line without its `#` comment, if any, leaving alone the `#` inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// A TOML basic (`"..."`, with `\"` and `\\` escapes) or literal (`'...'`) string.
+fn parse_string(value: &str) -> Option<String> {
+    if let Some(literal) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return Some(literal.to_string()).filter(|l| !l.contains('\''));
+    }
+
+    let basic = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = basic.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('"' | '\\') => string.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => string.push(c),
+        }
+    }
+    Some(string)
+}
+
+/// The targets completed so far and their outputs, saved to `<output>.campaign` after each target,
+/// so that a stopped campaign can go on with `--resume`.
+pub struct CampaignState {
+    path: std::path::PathBuf,
+    /// the targets without an output file had nothing to write
+    completed: Vec<(String, Option<std::path::PathBuf>)>,
+}
+
+impl CampaignState {
+    /// The state of the previous runs with `resume`, otherwise a fresh one replacing it.
+    pub fn load(path: std::path::PathBuf, resume: bool) -> Result<Self, ScraperError> {
+        let mut completed = Vec::new();
+        if resume && path.exists() {
+            for line in std::fs::read_to_string(&path)?.lines().filter(|l| !l.starts_with('#')) {
+                // the target names have no spaces, while the paths might
+                let Some(("target", rest)) = line.split_once(' ') else { continue; };
+                let (target, output) = rest.split_once(' ').map_or((rest, None), |(target, output)| (target, Some(output.into())));
+                completed.push((target.to_string(), output));
+            }
+            println!("Ripresa della campagna: {} target gia' completati.", completed.len());
+        }
+
+        Ok(Self { path, completed })
+    }
+
+    /// The output of the target if it was already completed, `Some(None)` if it was completed without one.
+    pub fn output_of(&self, target: &str) -> Option<Option<&std::path::Path>> {
+        self.completed.iter()
+            .find(|(t, _)| t == target)
+            .map(|(_, output)| output.as_deref())
+    }
+
+    /// Records the target as completed, once its output is written, or without an output when there was nothing to write.
+    pub fn complete(&mut self, target: &str, output: Option<&std::path::Path>) -> Result<(), ScraperError> {
+        self.completed.push((target.to_string(), output.map(std::path::Path::to_path_buf)));
+
+        let mut content = format!("# {} campaign state\n", env!("CARGO_PKG_NAME"));
+        for (target, output) in &self.completed {
+            match output {
+                Some(output) => content.push_str(&format!("target {target} {}\n", output.display())),
+                None => content.push_str(&format!("target {target}\n")),
+            }
+        }
+        std::fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn options() -> FilterOptions {
+        let crate::Cli { mode: crate::cli::CliMode::Filter(params), .. } = crate::Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]) else {
+            unreachable!("filter is the Filter mode");
+        };
+        params.options
+    }
+
+    /// region, city and category
+    type Target = (String, Option<String>, Option<String>);
+
+    fn targets(manifest: &str) -> Result<Vec<Target>, ScraperError> {
+        let targets = parse_targets(manifest, &options())?;
+        Ok(targets.into_iter().map(|t| (t.region, t.city, t.category)).collect())
+    }
+
+    fn target(region: &str, city: Option<&str>, category: Option<&str>) -> Target {
+        (region.to_string(), city.map(str::to_string), category.map(str::to_string))
+    }
+
+    #[test]
+    fn targets_get_the_defaults() {
+        let manifest = r#"
+            category = "ristoranti"
+            city = "roma"
+
+            [[target]]
+            region = "lazio"
+
+            [[target]]
+            region = "toscana"
+            city = "firenze"
+            category = "pizzerie"
+
+            [[target]]
+            region = "umbria"
+            city = ""
+        "#;
+        assert_eq!(targets(manifest).unwrap(), [
+            target("lazio", Some("roma"), Some("ristoranti")),
+            target("toscana", Some("firenze"), Some("pizzerie")),
+            // an empty value unsets the default
+            target("umbria", None, Some("ristoranti")),
+        ]);
+    }
+
+    #[test]
+    fn parses_the_strings_and_comments() {
+        let manifest = r#"
+            # a campaign
+            [[target]] # the first
+            region = "lazio" # the region
+            city = 'C:\roma # nord'
+            category = "bar \"da mario\" # \\"
+        "#;
+        assert_eq!(targets(manifest).unwrap(), [target("lazio", Some("C:\\roma # nord"), Some("bar \"da mario\" # \\"))]);
+
+        assert!(targets("[[target]]\nregion = \"la\\zio\"").is_err());
+        assert!(targets("[[target]]\nregion = lazio").is_err());
+        assert!(targets("[[target]]\nregion = \"la\"zio\"").is_err());
+        assert!(targets("[[target]]\nregion = 'la'zio'").is_err());
+    }
+
+    #[test]
+    fn rejects_the_malformed_manifests() {
+        let error = |manifest: &str| targets(manifest).unwrap_err().to_string();
+
+        assert!(error("[[target]]\nregion = \"lazio\"\nregion = \"toscana\"").contains("riga 3"));
+        assert!(error("city = \"roma\"\ncity = \"milano\"\n[[target]]\nregion = \"lazio\"").contains("ripetuta"));
+        assert!(error("[[target]]\nprovince = \"rm\"").contains("province"));
+        assert!(error("[[target]]\nregion").contains("riga 2"));
+        assert!(error("region = \"lazio\"").contains("[[target]]"));
+        assert!(error("[[target]]\nregion = \"lazio\"\n[[target]]\ncity = \"roma\"").contains("target 2"));
+        assert!(error("region = \"\"\n[[target]]").contains("target 1"));
+
+        // the same key in another target is fine
+        assert_eq!(targets("[[target]]\nregion = \"lazio\"\n[[target]]\nregion = \"toscana\"").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn state_keeps_the_completed_targets() {
+        let path = std::env::temp_dir().join(format!("paginegialle-scraper-test-{}-state.campaign", std::process::id()));
+        let mut state = CampaignState::load(path.clone(), false).unwrap();
+        state.complete("lazio-roma", Some(std::path::Path::new("out/output lazio.csv"))).unwrap();
+        state.complete("toscana--bar", None).unwrap();
+
+        let state = CampaignState::load(path.clone(), true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state.output_of("lazio-roma"), Some(Some(std::path::Path::new("out/output lazio.csv"))));
+        assert_eq!(state.output_of("toscana--bar"), Some(None));
+        assert_eq!(state.output_of("toscana"), None);
+    }
+}
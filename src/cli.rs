@@ -1,4 +1,4 @@
-use crate::DEFAULT_PAGE_LIMIT;
+use crate::{DEFAULT_CATEGORY_ERROR_LIMIT, DEFAULT_EXTERNAL_CONCURRENCY, DEFAULT_PAGE_LIMIT, DEFAULT_REQUESTS_BATCH};
 
 #[derive(clap::Parser)]
 #[command(version, about = "Scrapes PagineGialle businesses data into a csv file. Puntuactions should be replaced with _")]
@@ -7,17 +7,413 @@ pub struct Cli {
   #[command(subcommand)]
   pub mode: CliMode,
 
-  /// output filename (without the .csv extension)
+  /// output filename (without the extension).
+  /// While scraping, the entries found so far are written to `<output>.partial.csv`, removed once the output is saved
   #[arg(short, long = "output", default_value = "output")]
   pub output_file: String,
 
-  /// maximum pages to be scraped for each query
-  #[arg(short = 'l', long = "limit", default_value_t = DEFAULT_PAGE_LIMIT)]
+  /// output filename (without the extension) built from placeholders: {region}, {city}, {category} (the query in Search mode),
+  /// {date} (today, YYYY-MM-DD) and {count} (businesses scraped), e.g. `{region}_{category}_{date}`.
+  /// In Batch and Campaign mode each row or target gets its own file, the --combined one and the master have only {date} and {count}
+  #[arg(long, conflicts_with = "output_file")]
+  pub output_template: Option<String>,
+
+  /// output file format. vCard writes a contact for each business, with its name, phones, website and address.
+  /// JSON objects have the --fields columns, empty ones being null, and the address as an object with the raw address
+  /// and, when it has a CAP, its street, cap and city
+  #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+  pub format: OutputFormat,
+
+  /// start the CSV output with a UTF-8 BOM, so that Excel shows the accented characters correctly
+  #[arg(long)]
+  pub bom: bool,
+
+  /// character encoding of the CSV output, for tools that can't read UTF-8. The conversion is lossy:
+  /// characters missing from the encoding are transliterated to ascii (e.g. `ł` -> `l`) or replaced by `?`
+  #[arg(long, value_enum, default_value_t = OutputEncoding::Utf8, conflicts_with = "bom")]
+  pub encoding: OutputEncoding,
+
+  /// compress the output files with gzip, adding `.gz` to their names. The merge mode reads `.csv.gz` files too
+  #[arg(long, conflicts_with_all = ["resume", "state_dir"])]
+  pub compress_output: bool,
+
+  /// create the missing parent directories of the output file
+  #[arg(long)]
+  pub mkdir: bool,
+
+  /// maximum pages to be scraped for each query (at least 1)
+  #[arg(short = 'l', long = "limit", default_value_t = DEFAULT_PAGE_LIMIT, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
   pub page_limit: usize,
 
+  /// first page to scrape for each query, to skip the pages already scraped
+  #[arg(long, default_value_t = 0)]
+  pub page_start: usize,
+
+  /// page to stop at for each query (excluded). Overrides --limit
+  #[arg(long)]
+  pub page_end: Option<usize>,
+
+  /// scrape only the first page of each query, reporting how many businesses each comune/category has there.
+  /// A cheap coverage check to plan deeper scrapes, the scraped businesses are saved as a sample
+  #[arg(long, conflicts_with_all = ["page_limit", "page_end"])]
+  pub probe: bool,
+
+  /// only count the listings of each comune and category, writing a `comune,category,entries` CSV instead of the businesses.
+  /// Combine with --probe for a cheap estimate. Only in the filter, batch and campaign modes
+  #[arg(long)]
+  pub count_only: bool,
+
+  /// continue an interrupted run: the entries of the existing CSV output are kept, and the urls listed in its
+  /// `<output>.checkpoint` file are not requested again. Without a checkpoint, the urls in the source_url column count as done
+  #[arg(long, conflicts_with = "count_only")]
+  pub resume: bool,
+
+  /// keep the progress of the run in this directory, in a state file named after a hash of the command line:
+  /// running the same command again continues where it stopped, as --resume does, and the state adds up the stats of every run.
+  /// For campaigns run in chunks over several days
+  #[arg(long, conflicts_with = "count_only")]
+  pub state_dir: Option<String>,
+
+  /// scrape once more the comuni without any result, in case their empty pages were transient failures (Filter mode only)
+  #[arg(long)]
+  pub retry_empty: bool,
+
   /// show debugging info
   #[arg(short, long)]
   pub debug: bool,
+
+  /// add to each entry the url of the page it was scraped from
+  #[arg(long)]
+  pub include_source_url: bool,
+
+  /// follow the PagineGialle redirect wrapper of each website with a HEAD request, storing the real website url
+  #[arg(long)]
+  pub resolve_websites: bool,
+
+  /// check with a HEAD request whether each website is reachable, writing the status code it answers with,
+  /// or `dead`, in the website_status column. Best combined with --resolve-websites
+  #[arg(long)]
+  pub validate_websites: bool,
+
+  /// remove the dead websites (no answer, not found or server error) from their entries, which are kept
+  #[arg(long, requires = "validate_websites")]
+  pub drop_dead_websites: bool,
+
+  /// keep every scraped row, duplicates included, both while scraping and merging
+  #[arg(long)]
+  pub no_dedup: bool,
+
+  /// deduplicate on name and phones with a fixed size Bloom filter instead of keeping every unique row in a set,
+  /// bounding the memory of nationwide scrapes. About 1 in 1000 unique rows is wrongly dropped as a duplicate,
+  /// and duplicates always keep the first row seen, ignoring --merge-strategy
+  #[arg(long, conflicts_with = "no_dedup")]
+  pub approx_dedup: bool,
+
+  /// write next to the output a `<output>.duplicates.csv` file with the rows sharing the same dedup key, numbered by cluster,
+  /// with the category (or merged file) each comes from. Deduplication is unaffected, combine with --no-dedup to keep them all
+  #[arg(long)]
+  pub report_duplicates: bool,
+
+  /// which row to keep when two duplicates collide, both while scraping and merging
+  #[arg(long, value_enum, default_value_t = MergeStrategy::First)]
+  pub merge_strategy: MergeStrategy,
+
+  /// file of phone numbers, one per line, to leave out of the output: any row with one of them is dropped.
+  /// Separators and the +39 prefix are ignored when comparing
+  #[arg(long)]
+  pub exclude_phones_file: Option<String>,
+
+  /// keep only the phones that look like italian numbers (landline, mobile or toll free), and drop the rows left without any
+  #[arg(long)]
+  pub strict_phones: bool,
+
+  /// comma separated columns to write, in the given order (e.g. name,phones,website). By default all the columns,
+  /// except the ones filled in by an option that isn't given (e.g. score without --score)
+  #[arg(long, value_delimiter = ',')]
+  pub fields: Option<Vec<String>>,
+
+  /// trim names and addresses, collapsing repeated whitespace and dropping trailing separators
+  #[arg(long)]
+  pub normalize: bool,
+
+  /// keep the name, address and phones as scraped, before any formatting or normalization, in the name_raw, address_raw
+  /// and phones_raw fields. They are written by default in JSON, and in the other formats only when listed in --fields
+  #[arg(long)]
+  pub preserve_raw: bool,
+
+  /// title-case the business names (e.g. `PIZZERIA DA MARIO` -> `Pizzeria Da Mario`). Requires --normalize
+  #[arg(long, requires = "normalize")]
+  pub title_case_names: bool,
+
+  /// split each address into the street, cap and city columns. Addresses without a CAP are kept whole in the street column
+  #[arg(long)]
+  pub split_address: bool,
+
+  /// sort the phones into the mobile_phones (numbers starting with 3) and landline_phones (starting with 0) columns,
+  /// e.g. to pick the numbers for SMS campaigns. The whatsapp number counts as a mobile
+  #[arg(long)]
+  pub split_phone_types: bool,
+
+  /// add the score column, with how complete each entry is as a lead: a point per phone (up to three), two for whatsapp, website and contact url
+  #[arg(long)]
+  pub score: bool,
+
+  /// how to write whatsapp numbers: plain digits, `https://wa.me/` links, or digits with the link in the whatsapp_link column
+  #[arg(long, value_enum, default_value_t = WhatsappFormat::Digits)]
+  pub whatsapp_format: WhatsappFormat,
+
+  /// key used to sort the output rows. `none` keeps the collection order, which is faster on huge datasets
+  #[arg(long, value_enum, default_value_t = SortKey::Name)]
+  pub sort_by: SortKey,
+
+  /// don't sort the output rows, same as `--sort-by none`
+  #[arg(long, conflicts_with = "sort_by")]
+  pub no_sort: bool,
+
+  /// split the output into one file per category or comune (e.g. `output_ristoranti.csv`)
+  #[arg(long, value_enum)]
+  pub split_by: Option<SplitKey>,
+
+  /// what to do with the output file when nothing is found
+  #[arg(long, value_enum, default_value_t = EmptyOutput::Header)]
+  pub output_empty_file: EmptyOutput,
+
+  /// don't ask for confirmation before a large all-categories Filter scrape. Required when stdin isn't a terminal
+  #[arg(short, long)]
+  pub yes: bool,
+
+  /// request the pages of each category in random order, rather than comune after comune
+  #[arg(long)]
+  pub shuffle: bool,
+
+  /// seed of the randomized options (--shuffle). When not given a random one is used and printed, to repeat the run
+  #[arg(long)]
+  pub seed: Option<u64>,
+
+  /// Accept-Language header sent with every request, so that the pages don't change with the machine location
+  #[arg(long, default_value = "it-IT")]
+  pub accept_language: String,
+
+  /// fetch the robots.txt of the scraped site and skip the pages it disallows
+  #[arg(long)]
+  pub respect_robots: bool,
+
+  /// maximum idle connections kept open for reuse per host (reqwest default: unlimited). Up to --paginegialle-concurrency requests
+  /// are in flight at once, so values above it don't add reuse, while lower values close the extra connections between request bursts
+  #[arg(long)]
+  pub pool_max_idle_per_host: Option<usize>,
+
+  /// maximum requests in flight at once to PagineGialle
+  #[arg(long, default_value_t = DEFAULT_REQUESTS_BATCH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+  pub paginegialle_concurrency: usize,
+
+  /// maximum requests in flight at once to each other host (urls mode, and the redirects of --resolve-websites), so that a slow site doesn't hold up the PagineGialle requests
+  #[arg(long, default_value_t = DEFAULT_EXTERNAL_CONCURRENCY, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+  pub external_concurrency: usize,
+
+  /// when PagineGialle answers HTTP 429 (too many requests), halve the request rate and ask the page again,
+  /// raising the rate back step by step after 30s without 429s. The rate changes are shown with --debug
+  #[arg(long)]
+  pub throttle_on_429: bool,
+
+  /// use HTTP/2 with prior knowledge: connections speak HTTP/2 right away, with no ALPN or HTTP/1.1 upgrade negotiation,
+  /// multiplexing the in-flight requests over few connections. Requests fail against servers that expect the negotiation,
+  /// as many https ones do. Without it the requests use HTTP/1.1
+  #[arg(long)]
+  pub http2: bool,
+
+  /// skip the pages larger than this many bytes, rather than loading them whole in memory. They are reported as failed
+  #[arg(long)]
+  pub max_page_bytes: Option<usize>,
+
+  /// DANGEROUS: don't verify TLS certificates. Only needed behind proxies intercepting TLS traffic
+  #[arg(long)]
+  pub danger_accept_invalid_certs: bool,
+
+  /// cookies.txt file (Netscape format, as exported by browsers or written by curl) to load the cookies from, if it exists,
+  /// and to save them to at the end of the run. The cookies set by the site are always kept for the whole run
+  #[arg(long)]
+  pub cookies_file: Option<String>,
+
+  /// print the plan derived from the arguments before scraping: where the comuni and categories come from,
+  /// how many urls and requests, and the concurrency and time limits
+  #[arg(long)]
+  pub explain: bool,
+
+  /// time each request, printing the slowest urls at the end of the run along with the average and median duration,
+  /// to tell which comuni or categories are slow and tune the timeouts and concurrency
+  #[arg(long)]
+  pub profile: bool,
+
+  /// POST the scraped businesses to this url as they are collected, in batches of NDJSON lines (one JSON object per business).
+  /// Duplicates are not sent again, failed batches are retried and reported in the summary.
+  /// The businesses are filtered and formatted like the output file, but without --resolve-websites and --validate-websites
+  #[arg(long)]
+  pub webhook: Option<String>,
+
+  /// businesses sent in each --webhook request
+  #[arg(long, default_value_t = 100, requires = "webhook", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+  pub webhook_batch_size: usize,
+
+  /// longest time a business waits before being sent to the --webhook, when the batch is not full (e.g. 5s, 1m)
+  #[arg(long, default_value = "5s", requires = "webhook", value_parser = parse_duration)]
+  pub webhook_flush_interval: std::time::Duration,
+
+  /// only send the businesses to the --webhook, without writing the output file
+  #[arg(long, requires = "webhook", conflicts_with_all = ["resume", "state_dir"])]
+  pub webhook_only: bool,
+
+  /// record the scraping requests and responses (timings, status, headers, sizes) in a HAR file, to inspect blocks and redirects
+  #[arg(long)]
+  pub har: Option<String>,
+
+  /// also save the response bodies in the --har file, which makes it as large as all the pages together
+  #[arg(long, requires = "har")]
+  pub har_bodies: bool,
+
+  /// write next to the output a `<output>.meta.json` file with the run parameters, duration and counts
+  #[arg(long)]
+  pub manifest: bool,
+
+  /// maximum duration of the whole run (e.g. 600s, 10m, 1h). Once exceeded, no new requests are sent and the results collected so far are saved
+  #[arg(long, value_parser = parse_duration)]
+  pub max_runtime: Option<std::time::Duration>,
+
+  /// fail (exit code 4) when fewer unique businesses than this are found, as a guardrail for automated runs:
+  /// a thin result usually means a wrong category, blocked requests or broken selectors. 0 disables the check
+  #[arg(long, default_value_t = 0)]
+  pub min_entries: usize,
+
+  /// abort when no listing is found for this long (e.g. 90s, 5m), saving the results collected so far
+  /// and exiting with an error. Protects unattended runs from hanging on requests that never succeed
+  #[arg(long, value_parser = parse_duration)]
+  pub stall_timeout: Option<std::time::Duration>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+  Csv,
+  /// `.vcf` address book contacts
+  Vcard,
+  /// an array of objects
+  Json,
+  /// an object with the array of objects of each category (e.g. `{"bar": [...], "ristoranti": [...]}`),
+  /// a business found in many categories being in the array of each one
+  JsonByCategory,
+}
+
+impl OutputFormat {
+  pub fn extension(self) -> &'static str {
+    match self {
+      OutputFormat::Csv => "csv",
+      OutputFormat::Vcard => "vcf",
+      OutputFormat::Json | OutputFormat::JsonByCategory => "json",
+    }
+  }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+  /// business name, then address
+  Name,
+  /// address, then business name
+  Address,
+  /// comune taken from the address, then business name
+  Comune,
+  /// richest leads first (see --score), then business name
+  Score,
+  /// don't sort
+  None,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+  /// keep the row found first
+  First,
+  /// keep the row found last
+  Last,
+  /// keep the row with more non-empty optional fields
+  MostComplete,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+  Utf8,
+  /// Windows-1252, the superset of ISO-8859-1 used by Windows tools
+  #[value(alias = "latin1", alias = "iso-8859-1")]
+  Windows1252,
+}
+
+impl OutputEncoding {
+  pub fn encoding(self) -> &'static encoding_rs::Encoding {
+    match self {
+      OutputEncoding::Utf8 => encoding_rs::UTF_8,
+      OutputEncoding::Windows1252 => encoding_rs::WINDOWS_1252,
+    }
+  }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum WhatsappFormat {
+  Digits,
+  Link,
+  Both,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum SplitKey {
+  /// category of the scraped page (Filter mode only). A business found in many categories is written to the file of each one
+  Category,
+  /// comune taken from the address
+  Comune,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyOutput {
+  /// write a file with only the header row
+  Header,
+  /// don't write anything, leaving a pre-existing output untouched
+  Skip,
+  /// don't write anything, and delete a pre-existing output
+  Delete,
+}
+
+impl Cli {
+  /// Key to sort the output rows on, `--no-sort` being a shorthand for `--sort-by none`.
+  pub fn sort_key(&self) -> SortKey {
+    if self.no_sort { SortKey::None } else { self.sort_by }
+  }
+
+  /// Whether the run continues a previous one, with `--resume` or `--state-dir`.
+  pub fn resumes(&self) -> bool {
+    self.resume || self.state_dir.is_some()
+  }
+
+  /// Pages to scrape for each query: `--page-start..--page-end`, or `--limit` pages from the start (only one with `--probe`).
+  pub fn page_range(&self) -> std::ops::Range<usize> {
+    if self.probe {
+      return self.page_start..self.page_start + 1;
+    }
+    let end = self.page_end.unwrap_or(self.page_start + self.page_limit);
+    self.page_start..end
+  }
+}
+
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+  let s = s.trim();
+  let (value, multiplier) = if let Some(v) = s.strip_suffix('h') {
+    (v, 3600)
+  } else if let Some(v) = s.strip_suffix('m') {
+    (v, 60)
+  } else if let Some(v) = s.strip_suffix('s') {
+    (v, 1)
+  } else {
+    (s, 1)
+  };
+
+  value.parse::<u64>()
+    .map(|v| std::time::Duration::from_secs(v * multiplier))
+    .map_err(|_| format!("invalid duration `{s}`, expected a number followed by s, m or h"))
 }
 
 /*
@@ -37,13 +433,27 @@ pub struct Cli {
 #[derive(clap::Subcommand)]
 pub enum CliMode {
     /// Needs a search query, and optionally a city.
-    /// (urls of kind `https://www.paginegialle.it/<regione>/<citta>/<categoria>.html`)
+    /// (urls of kind `https://www.paginegialle.it/ricerca/<search>/[<citta>]`)
     Search(SearchMode),
     /// Needs a region name, and optionally a city and a category.
-    /// (urls of kind `https://www.paginegialle.it/ricerca/<search>/[<citta>]`)
+    /// (urls of kind `https://www.paginegialle.it/<regione>/<citta>/<categoria>.html`)
     Filter(FilterMode),
     /// Merges computed CSVs files into a single one, removing duplicates.
     Merge(MergeMode),
+    /// Scrapes an explicit list of PagineGialle listing urls read from a file, one per line.
+    Urls(UrlsMode),
+    /// Runs both a Search and a Filter scrape, deduplicating their results together.
+    Combined(CombinedMode),
+    /// Runs a Filter scrape for each `region,city,category` row read from stdin (CSV or TSV, city and category can be empty).
+    Batch(BatchMode),
+    /// Runs a Filter scrape for each target of a manifest file, writing each target to its own file and all of them
+    /// to a deduplicated master file. With --resume, a stopped campaign goes on with the targets not completed yet.
+    Campaign(CampaignMode),
+    /// Compares two computed CSVs, reporting added, removed and changed businesses (matched by name and phones).
+    Diff(DiffMode),
+    /// Saves a page as a test fixture, with its url and capture time.
+    #[command(hide = true)]
+    Capture(CaptureMode),
 }
 
 #[derive(clap::Args)]
@@ -68,6 +478,13 @@ pub struct FilterMode {
     /// Full list of categories: https://www.paginegialle.it/categorie.htm
     pub category: Option<String>,
 
+    #[command(flatten)]
+    pub options: FilterOptions,
+}
+
+/// Filter mode settings, shared with the Batch and Campaign modes.
+#[derive(clap::Args, Clone)]
+pub struct FilterOptions {
     #[arg(short, long)]
     /// if city provided is a province (example: Padova), setting this flag will scrape all cities in the province.
     /// If city is not a region or province, this flag does nothing
@@ -77,10 +494,120 @@ pub struct FilterMode {
     /// halven the cities list if scarping for whole regions or provinces, this will make the process faster (less requests) but will give less result.
     /// If parsing only for a single city, this flag does nothing
     pub big_cities_only: bool,
+
+    #[arg(long)]
+    /// scrape only the provincial capitals of the region, a fast and coarse sampling of the whole region.
+    /// If city is provided, this flag does nothing
+    pub capoluoghi_only: bool,
+
+    #[arg(long)]
+    /// without --category, scrape every subcategory too, rather than only the most popular categories.
+    /// Many more categories, and so requests: combine it with --probe or --count-only first
+    pub all_categories: bool,
+
+    #[arg(long)]
+    /// scrape only the first N comuni of the list, the biggest ones with --big-cities-only. Useful to test region scrapes cheaply
+    pub comuni_limit: Option<usize>,
+
+    #[arg(long)]
+    /// use the region exactly as given, already in the site's slug form (e.g. `emilia_romagna`), in the urls and the comuni API.
+    /// Otherwise it's slugified like the comuni (`Emilia Romagna` -> `emilia_romagna`), and small typos are corrected
+    pub raw_region: bool,
+
+    #[arg(long)]
+    /// seconds to wait before starting the requests of the next category, to be gentler on the site during all-category scrapes
+    pub category_pause_secs: Option<u64>,
+
+    #[arg(long, value_parser = parse_duration)]
+    /// time budget of each comune within a category (e.g. 30s, 2m). Once exceeded, the remaining pages of the comune are skipped
+    pub timeout_per_comune: Option<std::time::Duration>,
+
+    #[arg(long, default_value_t = DEFAULT_CATEGORY_ERROR_LIMIT)]
+    /// failed requests in a row after which the remaining pages of a category are skipped, and the scrape goes on with the next category.
+    /// The skipped categories are listed at the end. 0 never skips
+    pub category_error_limit: usize,
+
+    #[arg(long, default_value = "nome")]
+    /// column of the comuni CSV containing the comune name
+    pub comuni_name_column: String,
+
+    #[arg(long, default_value = "popolazione")]
+    /// column of the comuni CSV containing the comune population
+    pub comuni_population_column: String,
 }
 
 #[derive(clap::Args)]
 pub struct MergeMode {
     /// target CSVs folder to merge in one. Only CSVs files will be selected
     pub folder_path: String,   
-}
\ No newline at end of file
+
+    #[arg(long)]
+    /// sort each CSV on its own and merge them while writing, holding only one CSV in memory at a time.
+    /// Slower, for merging many large CSVs. The output is sorted by name with `--sort-by none`
+    pub low_memory: bool,
+}
+
+#[derive(clap::Args)]
+pub struct UrlsMode {
+    /// file containing the urls to scrape, one per line. Empty lines and lines starting with # are skipped
+    pub file_path: String,
+}
+
+#[derive(clap::Args)]
+pub struct CombinedMode {
+    #[arg(long)]
+    /// search query, should be a business category or a business name
+    pub query: String,
+
+    #[arg(long)]
+    /// location to search businesses in with the query (might be city or region)
+    pub location: Option<String>,
+
+    #[command(flatten)]
+    pub filter: FilterMode,
+}
+
+#[derive(clap::Args)]
+pub struct BatchMode {
+    #[arg(long)]
+    /// write all the rows results in a single deduplicated file, instead of one file for each row
+    pub combined: bool,
+
+    #[command(flatten)]
+    pub options: FilterOptions,
+}
+
+#[derive(clap::Args)]
+pub struct CampaignMode {
+    /// manifest listing the targets, in TOML: a `[[target]]` table for each target, with `region` and optionally `city` and `category`.
+    /// The keys before the first table are the defaults of every target (e.g. `category = "ristoranti"`)
+    pub manifest_path: String,
+
+    #[command(flatten)]
+    pub options: FilterOptions,
+}
+
+#[derive(clap::Args)]
+pub struct DiffMode {
+    /// older CSV to compare
+    pub old_path: String,
+    /// newer CSV to compare
+    pub new_path: String,
+
+    #[arg(long)]
+    /// print the whole diff as JSON instead of a summary
+    pub json: bool,
+
+    #[arg(long)]
+    /// break the summary down by category. The categories are only known for the rows scraped in Filter mode,
+    /// a business of many categories counting in each one
+    pub by_category: bool,
+}
+
+#[derive(clap::Args)]
+pub struct CaptureMode {
+    /// url of the page to capture
+    pub url: String,
+    /// fixture file to write
+    pub fixture_path: String,
+}
@@ -0,0 +1,301 @@
+//! Cookies kept across the requests of a run, so that consent and session cookies set by the site are sent back.
+//! reqwest's own cookie store needs a crate feature we can't enable, so the cookies are handled here:
+//! they are added to each request and taken from each response, except the intermediate responses of redirects.
+
+use std::sync::{LazyLock, Mutex};
+
+struct Cookie {
+    /// without a leading dot
+    domain: String,
+    /// whether the cookie is sent to the subdomains too, as it had a Domain attribute
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    /// unix timestamp, 0 for session cookies
+    expires: u64,
+    name: String,
+    value: String,
+}
+
+impl Cookie {
+    fn matches(&self, url: &reqwest::Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        let domain_matches = host == self.domain
+            || (self.include_subdomains && host.strip_suffix(&self.domain).is_some_and(|h| h.ends_with('.')));
+
+        domain_matches
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.is_expired()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= now()
+    }
+
+    /// Parses a `Set-Cookie` header received from the url.
+    fn parse(url: &reqwest::Url, header: &str) -> Option<Self> {
+        let mut attributes = header.split(';').map(str::trim);
+        let (name, value) = attributes.next()?.split_once('=')?;
+        if name.trim().is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            domain: url.host_str()?.to_string(),
+            include_subdomains: false,
+            // the default path is the directory of the url
+            path: url.path().rsplit_once('/').map(|(dir, _)| dir).filter(|d| !d.is_empty()).unwrap_or("/").to_string(),
+            secure: false,
+            expires: 0,
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        };
+
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    cookie.domain = value.trim().trim_start_matches('.').to_ascii_lowercase();
+                    cookie.include_subdomains = true;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "expires" => cookie.expires = parse_http_date(value).unwrap_or(0),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                _ => {}
+            }
+        }
+        // a site can't set cookies for other sites, nor for a whole public suffix
+        let host = url.host_str()?;
+        if host != cookie.domain && !host.ends_with(&format!(".{}", cookie.domain)) {
+            return None;
+        }
+        if cookie.include_subdomains && is_public_suffix(&cookie.domain) {
+            return None;
+        }
+
+        // Max-Age wins over Expires, a non positive one deletes the cookie
+        if let Some(max_age) = max_age {
+            cookie.expires = if max_age <= 0 { 1 } else { now() + max_age as u64 };
+        }
+
+        Some(cookie)
+    }
+}
+
+/// Whether the cookie path covers the request path, on whole segments: `/foo` covers `/foo/bar` but not `/foobar` (RFC 6265 §5.1.4).
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path.strip_prefix(cookie_path)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || cookie_path.ends_with('/'))
+}
+
+/// Whether the domain is a public suffix, which a `Domain` attribute can't name.
+/// Without the public suffix list, this covers the top level domains and the common second level ones.
+fn is_public_suffix(domain: &str) -> bool {
+    const SECOND_LEVEL: [&str; 12] = [
+        "co.uk", "org.uk", "ac.uk", "gov.uk", "com.au", "net.au", "co.jp", "com.br", "com.cn", "co.nz", "com.mt", "gov.it",
+    ];
+
+    !domain.contains('.') || SECOND_LEVEL.contains(&domain)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Parses the date of an `Expires` attribute (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, or `06-Nov-1994`) to a unix timestamp.
+fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+    let date = date.replace('-', " ");
+    let mut tokens = date.split_whitespace().filter(|t| !t.ends_with(','));
+    let day = tokens.next()?.parse::<i64>().ok()?;
+    let month = tokens.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? as i64 + 1;
+    let year = match tokens.next()?.parse::<i64>().ok()? {
+        y @ 0..70 => y + 2000,
+        y @ 70..100 => y + 1900,
+        y => y,
+    };
+    let mut time = tokens.next().unwrap_or("0:0:0").split(':').map(|t| t.parse::<i64>().unwrap_or(0));
+    let (hours, minutes, seconds) = (time.next()?, time.next().unwrap_or(0), time.next().unwrap_or(0));
+
+    // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    u64::try_from(days * 86400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
+
+static JAR: LazyLock<Mutex<Vec<Cookie>>> = LazyLock::new(Default::default);
+
+/// Adds the cookies matching the request url to the request.
+pub fn add_to(request: &mut reqwest::Request) {
+    let jar = JAR.lock().unwrap();
+    let cookies = jar.iter()
+        .filter(|c| c.matches(request.url()))
+        .map(|c| format!("{}={}", c.name, c.value))
+        .collect::<Vec<_>>();
+
+    if cookies.is_empty() {
+        return;
+    }
+    if let Ok(header) = reqwest::header::HeaderValue::from_str(&cookies.join("; ")) {
+        request.headers_mut().insert(reqwest::header::COOKIE, header);
+    }
+}
+
+/// Stores the cookies set by the response, replacing the ones with the same name, domain and path.
+pub fn store_from(response: &reqwest::Response) {
+    let mut jar = JAR.lock().unwrap();
+    for header in response.headers().get_all(reqwest::header::SET_COOKIE) {
+        let Some(cookie) = header.to_str().ok().and_then(|h| Cookie::parse(response.url(), h)) else { continue; };
+
+        jar.retain(|c| !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path));
+        if !cookie.is_expired() {
+            jar.push(cookie);
+        }
+    }
+}
+
+/// Sends the request with the cookies of the jar, storing the ones it sets.
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    add_to(&mut request);
+
+    let response = client.execute(request).await?;
+    store_from(&response);
+    Ok(response)
+}
+
+/// Loads the cookies of a Netscape `cookies.txt` file, as exported by browsers and written by curl.
+pub fn load(path: &std::path::Path) -> std::io::Result<usize> {
+    let content = std::fs::read_to_string(path)?;
+    let mut jar = JAR.lock().unwrap();
+    let before = jar.len();
+
+    for line in content.lines() {
+        // curl marks the HttpOnly cookies with a prefix on an otherwise commented line
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else { continue; };
+        let cookie = Cookie {
+            domain: domain.trim_start_matches('.').to_string(),
+            include_subdomains: include_subdomains.eq_ignore_ascii_case("TRUE"),
+            path: path.to_string(),
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            expires: expires.parse().unwrap_or(0),
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        if !cookie.is_expired() {
+            jar.push(cookie);
+        }
+    }
+
+    Ok(jar.len() - before)
+}
+
+/// Saves the cookies in the Netscape `cookies.txt` format, session cookies included so that the next run continues the session.
+pub fn save(path: &std::path::Path) -> std::io::Result<()> {
+    let jar = JAR.lock().unwrap();
+    let bool_field = |b: bool| if b { "TRUE" } else { "FALSE" };
+
+    let mut content = String::from("# Netscape HTTP Cookie File\n");
+    for cookie in jar.iter().filter(|c| !c.is_expired()) {
+        let domain = if cookie.include_subdomains { format!(".{}", cookie.domain) } else { cookie.domain.clone() };
+        content.push_str(&[
+            domain.as_str(),
+            bool_field(cookie.include_subdomains),
+            &cookie.path,
+            bool_field(cookie.secure),
+            &cookie.expires.to_string(),
+            &cookie.name,
+            &cookie.value,
+        ].join("\t"));
+        content.push('\n');
+    }
+
+    std::fs::write(path, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> reqwest::Url {
+        reqwest::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn parses_the_http_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2028 12:00:00 GMT"), Some(1835438400));
+        assert_eq!(parse_http_date("not a date"), None);
+        assert_eq!(parse_http_date("06 Foo 1994"), None);
+    }
+
+    #[test]
+    fn parses_the_set_cookie_header() {
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/ricerca/pizzerie"), "consent=yes; Path=/; Domain=.paginegialle.it; Secure; Max-Age=60").unwrap();
+        assert_eq!((cookie.name.as_str(), cookie.value.as_str()), ("consent", "yes"));
+        assert_eq!((cookie.domain.as_str(), cookie.include_subdomains), ("paginegialle.it", true));
+        assert_eq!(cookie.path, "/");
+        assert!(cookie.secure && cookie.expires > now());
+
+        // without attributes the cookie is for the host and the directory of the url only
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/ricerca/pizzerie"), "session=abc").unwrap();
+        assert_eq!((cookie.domain.as_str(), cookie.include_subdomains), ("www.paginegialle.it", false));
+        assert_eq!((cookie.path.as_str(), cookie.expires), ("/ricerca", 0));
+
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/"), "old=1; Expires=Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert!(cookie.is_expired());
+
+        assert!(Cookie::parse(&url("https://www.paginegialle.it/"), "no value").is_none());
+        assert!(Cookie::parse(&url("https://www.paginegialle.it/"), "=value").is_none());
+    }
+
+    #[test]
+    fn rejects_cookies_for_other_sites_and_public_suffixes() {
+        let page = url("https://www.paginegialle.it/");
+        assert!(Cookie::parse(&page, "a=1; Domain=example.com").is_none());
+        assert!(Cookie::parse(&page, "a=1; Domain=.it").is_none());
+        assert!(Cookie::parse(&page, "a=1; Domain=it").is_none());
+        assert!(Cookie::parse(&url("https://shop.example.co.uk/"), "a=1; Domain=co.uk").is_none());
+        assert!(Cookie::parse(&url("https://shop.example.co.uk/"), "a=1; Domain=example.co.uk").is_some());
+    }
+
+    #[test]
+    fn matches_the_path_on_whole_segments() {
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/"), "a=1; Path=/foo").unwrap();
+        assert!(cookie.matches(&url("https://www.paginegialle.it/foo")));
+        assert!(cookie.matches(&url("https://www.paginegialle.it/foo/bar")));
+        assert!(!cookie.matches(&url("https://www.paginegialle.it/foobar")));
+        assert!(!cookie.matches(&url("https://www.paginegialle.it/")));
+
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/"), "a=1; Path=/foo/").unwrap();
+        assert!(cookie.matches(&url("https://www.paginegialle.it/foo/bar")));
+        assert!(!cookie.matches(&url("https://www.paginegialle.it/foobar")));
+
+        let cookie = Cookie::parse(&url("https://www.paginegialle.it/"), "a=1; Domain=paginegialle.it; Secure").unwrap();
+        assert!(cookie.matches(&url("https://m.paginegialle.it/x")));
+        assert!(!cookie.matches(&url("http://www.paginegialle.it/")));
+        assert!(!cookie.matches(&url("https://notpaginegialle.it/")));
+    }
+}
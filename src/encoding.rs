@@ -0,0 +1,73 @@
+//! Transcoding of the UTF-8 output for tools that only read legacy encodings.
+
+use std::io::Write;
+
+use deunicode::deunicode_char;
+use encoding_rs::{EncoderResult, Encoding};
+
+/// Writer encoding the UTF-8 text written to it in another encoding.
+/// Characters that can't be represented are transliterated to ascii (e.g. `ł` -> `l`), or replaced by `?`.
+pub struct TranscodingWriter<W: Write> {
+    inner: W,
+    encoding: &'static Encoding,
+    /// bytes of a char split between two writes
+    pending: Vec<u8>,
+}
+
+impl<W: Write> TranscodingWriter<W> {
+    pub fn new(inner: W, encoding: &'static Encoding) -> Self {
+        Self { inner, encoding, pending: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn encode_char(&self, c: char, out: &mut Vec<u8>) {
+        if c.is_ascii() {
+            out.push(c as u8);
+            return;
+        }
+
+        let mut utf8 = [0; 4];
+        let mut encoded = [0; 8];
+        let mut encoder = self.encoding.new_encoder();
+        match encoder.encode_from_utf8_without_replacement(c.encode_utf8(&mut utf8), &mut encoded, true) {
+            (EncoderResult::InputEmpty, _, written) => out.extend_from_slice(&encoded[..written]),
+            _ => match deunicode_char(c) {
+                Some(ascii) if !ascii.is_empty() && ascii.is_ascii() => out.extend_from_slice(ascii.as_bytes()),
+                _ => out.push(b'?'),
+            },
+        }
+    }
+}
+
+impl<W: Write> Write for TranscodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.encoding == encoding_rs::UTF_8 {
+            return self.inner.write(buf);
+        }
+
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // an incomplete char at the end is completed by the next write
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+
+        let text = std::str::from_utf8(&self.pending[..valid]).expect("validated above");
+        let mut out = Vec::with_capacity(text.len());
+        for c in text.chars() {
+            self.encode_char(c, &mut out);
+        }
+        self.inner.write_all(&out)?;
+        self.pending.drain(..valid);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
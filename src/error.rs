@@ -0,0 +1,103 @@
+/// Everything that can go wrong while scraping, merging or writing the results.
+pub enum ScraperError {
+    /// a request failed
+    Network(reqwest::Error),
+    /// a request kept failing after all the retries
+    Fetch { url: String, attempts: u32, source: reqwest::Error },
+    /// a page was larger than `--max-page-bytes`
+    PageTooLarge { url: String, max_bytes: usize },
+    /// the comuni API couldn't be reached, with the url attempted
+    ComuniUnreachable { url: String, source: reqwest::Error },
+    /// the comuni API answered with data we can't use
+    ComuniApi(String),
+    /// a scraping selector is invalid
+    Selector(String),
+    /// too many consecutive pages looked like CAPTCHA or block pages
+    Blocked(usize),
+    /// no listing was found for the whole `--stall-timeout`
+    Stalled(std::time::Duration),
+    /// fewer unique entries than `--min-entries` were found
+    TooFewEntries { found: usize, min: usize },
+    /// a large all-categories scrape wasn't confirmed, with the number of requests it would send
+    NotConfirmed(usize),
+    /// the task collecting the scraped entries panicked or was cancelled
+    Collector(tokio::task::JoinError),
+    /// an argument or an input value couldn't be parsed or resolved
+    Parse(String),
+    Csv(csv::Error),
+    Json(crate::json::JsonError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ScraperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScraperError::Network(e) => write!(f, "Errore di rete: {e}"),
+            ScraperError::Fetch { url, attempts, source } => write!(f, "Impossibile scaricare {url} dopo {attempts} tentativi: {source}"),
+            ScraperError::PageTooLarge { url, max_bytes } => write!(f, "Pagina {url} saltata: supera il limite di {max_bytes} byte"),
+            ScraperError::ComuniUnreachable { url, source } => write!(f,
+                "Il servizio dei comuni non e' raggiungibile ({url}): {source}.\n\
+                Senza di esso si puo' cercare in una singola citta' (senza -a), nei soli capoluoghi della regione (--capoluoghi-only), \
+                o in una lista di url con la modalita' urls."
+            ),
+            ScraperError::ComuniApi(e) => write!(f, "Dati dei comuni non validi: {e}"),
+            ScraperError::Selector(e) => write!(f, "Selettori non validi:\n{e}"),
+            ScraperError::Blocked(pages) => write!(f, "{pages} pagine consecutive sembrano pagine di blocco o CAPTCHA: probabilmente PagineGialle sta limitando o bloccando le richieste. Riprova piu' tardi o riduci il numero di richieste."),
+            ScraperError::Stalled(timeout) => write!(f, "Nessun risultato negli ultimi {timeout:?}: lo scraping e' stato interrotto per stallo e i risultati parziali sono stati salvati."),
+            ScraperError::TooFewEntries { found, min } => write!(f, "Trovate solo {found} attivita', meno delle {min} richieste con --min-entries: lo scraping e' probabilmente fallito o mal configurato (categoria sbagliata, richieste bloccate o selettori non piu' validi, controlla con --debug)."),
+            ScraperError::NotConfirmed(requests) => write!(f, "Scraping di tutte le categorie ({requests} richieste) non confermato. Passa --yes per avviarlo senza conferma."),
+            ScraperError::Collector(e) => write!(f, "Il task di raccolta dei risultati e' fallito: {e}"),
+            ScraperError::Parse(e) => write!(f, "{e}"),
+            ScraperError::Csv(e) => write!(f, "Errore CSV: {e}"),
+            ScraperError::Json(e) => write!(f, "Errore JSON: {e}"),
+            ScraperError::Io(e) => write!(f, "Errore di I/O: {e}"),
+        }
+    }
+}
+
+impl ScraperError {
+    /// Exit code of the process: a stalled run gets its own, so that unattended jobs can tell it apart and retry later.
+    pub fn exit_code(&self) -> std::process::ExitCode {
+        match self {
+            ScraperError::Stalled(_) => std::process::ExitCode::from(3),
+            ScraperError::TooFewEntries { .. } => std::process::ExitCode::from(4),
+            _ => std::process::ExitCode::FAILURE,
+        }
+    }
+}
+
+// main prints the Debug representation of the returned error, so it's the same message
+impl std::fmt::Debug for ScraperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ScraperError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScraperError::Network(e) | ScraperError::Fetch { source: e, .. } | ScraperError::ComuniUnreachable { source: e, .. } => Some(e),
+            ScraperError::Collector(e) => Some(e),
+            ScraperError::Csv(e) => Some(e),
+            ScraperError::Json(e) => Some(e),
+            ScraperError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ScraperError {
+    fn from(e: reqwest::Error) -> Self { ScraperError::Network(e) }
+}
+
+impl From<csv::Error> for ScraperError {
+    fn from(e: csv::Error) -> Self { ScraperError::Csv(e) }
+}
+
+impl From<crate::json::JsonError> for ScraperError {
+    fn from(e: crate::json::JsonError) -> Self { ScraperError::Json(e) }
+}
+
+impl From<std::io::Error> for ScraperError {
+    fn from(e: std::io::Error) -> Self { ScraperError::Io(e) }
+}
@@ -0,0 +1,551 @@
+//! Gzip compression of the output files (`--compress-output`), and decompression of the `.csv.gz` files to merge.
+//! The compressor is a plain deflate with fixed Huffman codes: far from the best ratio, but CSVs shrink a lot anyway.
+
+use std::io::{self, Write};
+
+/// Back references reach this far.
+const WINDOW: usize = 32 * 1024;
+/// Input compressed at once in a block.
+const BLOCK: usize = 64 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates of the hash chain tried for each match: more compress better, and slower.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order of the code length code lengths in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn crc32_table() -> &'static [u32; 256] {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        table
+    })
+}
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let table = crc32_table();
+    !data.iter().fold(!crc, |c, &b| table[((c ^ b as u32) & 0xFF) as usize] ^ (c >> 8))
+}
+
+/// Bits packed least significant first, as deflate wants them.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count);
+        }
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol as u32, 8),
+            144..=255 => self.code(0x190 + symbol as u32 - 144, 9),
+            256..=279 => self.code(symbol as u32 - 256, 7),
+            _ => self.code(0xC0 + symbol as u32 - 280, 8),
+        }
+    }
+
+    fn back_reference(&mut self, length: usize, distance: usize) {
+        let i = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).expect("the length is at least 3");
+        self.literal(257 + i as u16);
+        self.bits((length - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i] as u32);
+
+        let i = DIST_BASE.iter().rposition(|&base| base as usize <= distance).expect("the distance is at least 1");
+        self.code(i as u32, 5);
+        self.bits((distance - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+    }
+}
+
+/// Writer compressing what is written to it into a gzip file. [`GzipWriter::finish`] has to be called to complete the file.
+pub struct GzipWriter<W: Write> {
+    inner: W,
+    /// the last [`WINDOW`] bytes already compressed, followed by the input still to compress
+    pending: Vec<u8>,
+    /// length of the already compressed part of `pending`
+    history: usize,
+    bits: BitWriter,
+    crc: u32,
+    size: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        // no name nor modification time, unknown OS
+        inner.write_all(&[0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF])?;
+        Ok(Self { inner, pending: Vec::new(), history: 0, bits: BitWriter::default(), crc: 0, size: 0 })
+    }
+
+    /// Compresses the pending input up to `end` into a block.
+    fn compress_block(&mut self, end: usize, last: bool) -> io::Result<()> {
+        let data = &self.pending[..end];
+        self.bits.bits(last as u32, 1);
+        // fixed Huffman codes
+        self.bits.bits(1, 2);
+
+        let hash = |i: usize| {
+            let h = (data[i] as u32) << 10 ^ (data[i + 1] as u32) << 5 ^ data[i + 2] as u32;
+            (h.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+        };
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        let mut prev = vec![usize::MAX; end];
+        let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+            if i + MIN_MATCH <= end {
+                let h = hash(i);
+                prev[i] = head[h];
+                head[h] = i;
+            }
+        };
+        for i in 0..self.history {
+            insert(i, &mut head, &mut prev);
+        }
+
+        let mut i = self.history;
+        while i < end {
+            let mut best = (0, 0);
+            if i + MIN_MATCH <= end {
+                let max = MAX_MATCH.min(end - i);
+                let mut candidate = head[hash(i)];
+                let mut chain = 0;
+                while candidate != usize::MAX && i - candidate <= WINDOW && chain < MAX_CHAIN {
+                    let length = data[candidate..].iter().zip(&data[i..i + max]).take_while(|(a, b)| a == b).count();
+                    if length > best.0 {
+                        best = (length, i - candidate);
+                        if length == max {
+                            break;
+                        }
+                    }
+                    candidate = prev[candidate];
+                    chain += 1;
+                }
+            }
+
+            if best.0 >= MIN_MATCH {
+                self.bits.back_reference(best.0, best.1);
+                for j in i..i + best.0 {
+                    insert(j, &mut head, &mut prev);
+                }
+                i += best.0;
+            } else {
+                self.bits.literal(data[i] as u16);
+                insert(i, &mut head, &mut prev);
+                i += 1;
+            }
+        }
+        // end of block
+        self.bits.literal(256);
+
+        self.inner.write_all(&self.bits.out)?;
+        self.bits.out.clear();
+
+        // only the window is needed by the next blocks
+        let keep_from = end.saturating_sub(WINDOW);
+        self.pending.drain(..keep_from);
+        self.history = end - keep_from;
+        Ok(())
+    }
+
+    /// Compresses the rest of the input and writes the gzip trailer, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress_block(self.pending.len(), true)?;
+        self.bits.align();
+        self.inner.write_all(&self.bits.out)?;
+        self.inner.write_all(&self.crc.to_le_bytes())?;
+        self.inner.write_all(&self.size.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.crc = crc32_update(self.crc, buf);
+        // the size is stored modulo 2^32
+        self.size = self.size.wrapping_add(buf.len() as u32);
+        self.pending.extend_from_slice(buf);
+        while self.pending.len() - self.history >= BLOCK {
+            self.compress_block(self.history + BLOCK, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gzip non valido: {message}"))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.count < count {
+            let byte = *self.data.get(self.pos).ok_or_else(|| invalid("dati troncati"))?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << count) - 1) as u32;
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        // the codes of each length follow the ones of the previous length
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("codice di Huffman sconosciuto"))
+    }
+}
+
+fn inflate_codes(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let i = symbol - 257;
+                let length = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let i = distances.decode(reader)? as usize;
+                if i >= DIST_BASE.len() {
+                    return Err(invalid("distanza non valida"));
+                }
+                let distance = DIST_BASE[i] as usize + reader.bits(DIST_EXTRA[i] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(invalid("distanza oltre l'inizio dei dati"));
+                }
+                // the reference can overlap the bytes it produces
+                let start = out.len() - distance;
+                for j in 0..length {
+                    out.push(out[start + j]);
+                }
+            }
+            _ => return Err(invalid("simbolo non valido")),
+        }
+    }
+}
+
+/// Decompresses a deflate stream, returning where it ends.
+fn inflate(data: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+    let mut reader = BitReader { data, pos: 0, buffer: 0, count: 0 };
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.pos..reader.pos + 4).ok_or_else(|| invalid("dati troncati"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                if length != !u16::from_le_bytes([header[2], header[3]]) as usize {
+                    return Err(invalid("lunghezza del blocco non valida"));
+                }
+                let start = reader.pos + 4;
+                out.extend_from_slice(data.get(start..start + length).ok_or_else(|| invalid("dati troncati"))?);
+                reader.pos = start + length;
+            }
+            1 => {
+                let mut lengths = [0; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_codes(&mut reader, out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let literals_count = reader.bits(5)? as usize + 257;
+                let distances_count = reader.bits(5)? as usize + 1;
+                let code_lengths_count = reader.bits(4)? as usize + 4;
+
+                let mut code_lengths = [0; 19];
+                for &i in &CODE_LENGTH_ORDER[..code_lengths_count] {
+                    code_lengths[i] = reader.bits(3)? as u8;
+                }
+                let code_lengths = Huffman::new(&code_lengths);
+
+                let mut lengths = Vec::with_capacity(literals_count + distances_count);
+                while lengths.len() < literals_count + distances_count {
+                    let (length, repeat) = match code_lengths.decode(&mut reader)? {
+                        symbol @ 0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or_else(|| invalid("ripetizione senza lunghezza"))?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        _ => (0, 11 + reader.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(length, repeat as usize));
+                }
+                if lengths.len() > literals_count + distances_count {
+                    return Err(invalid("troppe lunghezze dei codici"));
+                }
+
+                let (literals, distances) = lengths.split_at(literals_count);
+                inflate_codes(&mut reader, out, &Huffman::new(literals), &Huffman::new(distances))?;
+            }
+            _ => return Err(invalid("tipo di blocco non valido")),
+        }
+
+        if last {
+            return Ok(reader.pos);
+        }
+    }
+}
+
+/// Decompresses a gzip file, made of one or more members.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 18 || rest[..3] != [0x1F, 0x8B, 8] {
+            return Err(invalid("intestazione mancante"));
+        }
+        let flags = rest[3];
+        let mut pos = 10;
+        // extra field
+        if flags & 4 != 0 {
+            let length = rest.get(pos..pos + 2).ok_or_else(|| invalid("dati troncati"))?;
+            pos += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+        }
+        // file name and comment, zero terminated
+        for flag in [8, 16] {
+            if flags & flag != 0 {
+                pos += rest.get(pos..).and_then(|r| r.iter().position(|&b| b == 0)).ok_or_else(|| invalid("dati troncati"))? + 1;
+            }
+        }
+        // header crc
+        if flags & 2 != 0 {
+            pos += 2;
+        }
+
+        let start = out.len();
+        let end = pos + inflate(rest.get(pos..).ok_or_else(|| invalid("dati troncati"))?, &mut out)?;
+        let trailer = rest.get(end..end + 8).ok_or_else(|| invalid("dati troncati"))?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().expect("4 bytes"));
+        let size = u32::from_le_bytes(trailer[4..].try_into().expect("4 bytes"));
+        if crc != crc32_update(0, &out[start..]) || size != (out.len() - start) as u32 {
+            return Err(invalid("checksum errato"));
+        }
+        rest = &rest[end + 8..];
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut gzip = GzipWriter::new(Vec::new()).unwrap();
+        // written in pieces, as the csv writer does
+        for chunk in data.chunks(1000) {
+            gzip.write_all(chunk).unwrap();
+        }
+        gzip.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let csv = (0..5000)
+            .map(|i| format!("Pizzeria {i},Via Roma {},055 {:06},https://pizzeria{i}.it\n", i % 97, i * 7919 % 1_000_000))
+            .collect::<String>();
+        // spans several blocks, with matches reaching back into the previous one
+        assert!(csv.len() > 3 * BLOCK);
+        let compressed = compress(csv.as_bytes());
+        assert!(compressed.len() < csv.len() / 3);
+        assert_eq!(decompress(&compressed).unwrap(), csv.as_bytes());
+
+        // nothing to match
+        let noise = (0u32..100_000).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<_>>();
+        assert_eq!(decompress(&compress(&noise)).unwrap(), noise);
+
+        assert_eq!(decompress(&compress(b"")).unwrap(), b"");
+        assert_eq!(decompress(&compress(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")).unwrap(), b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+    }
+
+    #[test]
+    fn decompresses_gzip_output() {
+        // `gzip -9 -n` of the addresses, with dynamic Huffman codes
+        let compressed = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 37, 142, 177, 14, 130, 64, 16, 5, 123, 190, 226, 149, 80, 24, 247, 206, 96, 180, 21, 37, 161, 32,
+            18, 10, 250, 69, 175, 216, 228, 96, 205, 9, 13, 95, 239, 18, 251, 153, 201, 12, 194, 232, 117, 98, 56, 28, 80, 146, 35, 66, 45, 41,
+            204, 91, 64, 94, 55, 69, 86, 105, 250, 42, 6, 89, 22, 77, 162, 120, 76, 60, 175, 33, 6, 52, 13, 252, 233, 120, 51, 137, 200, 93,
+            206, 255, 70, 222, 183, 69, 214, 9, 111, 27, 227, 29, 34, 238, 171, 78, 138, 210, 32, 79, 206, 59, 180, 18, 121, 86, 228, 173, 133,
+            7, 97, 203, 24, 21, 109, 32, 124, 214, 113, 140, 242, 178, 13, 79, 251, 200, 117, 31, 233, 18, 47, 70, 119, 207, 34, 251, 1, 209,
+            173, 138, 175, 167, 0, 0, 0,
+        ];
+        let addresses = "Via Roma 1 - 50100 Firenze (FI)\n\
+            Corso Vittorio Emanuele II 23/B - 00186 Roma (RM)\n\
+            Piazza del Duomo 5 - 20121 Milano (MI)\n\
+            Viale della Repubblica 120 - 59100 Prato (PO)\n";
+        assert_eq!(String::from_utf8(decompress(&compressed).unwrap()).unwrap(), addresses);
+
+        // concatenated files are a single multi member one
+        let twice = [compressed.as_slice(), &compressed].concat();
+        assert_eq!(decompress(&twice).unwrap(), addresses.repeat(2).as_bytes());
+    }
+
+    /// Made by `gzip` 1.12: a CSV of listings with `-9` (several dynamic Huffman blocks), 4000 random bytes with `-1`
+    /// (stored blocks, file name in the header) and the CSV again with the default level, concatenated.
+    const MULTI_MEMBER: &[u8] = include_bytes!("../tests/fixtures/listings-multimember.csv.gz");
+
+    #[test]
+    fn decompresses_gzip_multi_member_files() {
+        let data = decompress(MULTI_MEMBER).unwrap();
+        assert_eq!(data.len(), 62963 + 4000 + 62963);
+        assert_eq!(crc32_update(0, &data), 673015933);
+
+        let (csv, rest) = data.split_at(62963);
+        assert!(csv.starts_with(b"name,address,phones\nPizzeria Il Forno 0,Via Roma 102 - "));
+        assert_eq!(csv.iter().filter(|&&b| b == b'\n').count(), 1001);
+        assert_eq!(&rest[4000..], csv);
+    }
+
+    /// Runs the system `gzip` with the arguments on the input, or `None` if it's not installed.
+    fn system_gzip(args: &[&str], input: &[u8]) -> Option<Vec<u8>> {
+        let mut child = std::process::Command::new("gzip")
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        Some(output.stdout)
+    }
+
+    #[test]
+    fn round_trips_with_the_system_gzip() {
+        let mut rng = crate::rng::SeededRng::new(42);
+        for round in 0..12 {
+            // text-like data with repetitions, or plain noise every few rounds
+            let len = rng.below(100_000) as usize;
+            let data = if round % 4 == 3 {
+                (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>()
+            } else {
+                (0..len).map(|_| b"Pizzeria, Via Roma 055 123456\n"[rng.below(8) as usize + (rng.below(4) as usize) * 6]).collect()
+            };
+            let level = format!("-{}", 1 + round % 9);
+
+            let Some(compressed) = system_gzip(&["-c", &level], &data) else { return; };
+            assert_eq!(decompress(&compressed).unwrap(), data, "gzip {level} of {len} bytes");
+            assert_eq!(system_gzip(&["-dc"], &compress(&data)).unwrap(), data, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn survives_mutated_files() {
+        // untrusted `.csv.gz` files are merged: any corruption has to be an error, never a panic or a hang
+        let mut rng = crate::rng::SeededRng::new(7);
+        let member = &MULTI_MEMBER[..MULTI_MEMBER.len() / 3];
+        for _ in 0..500 {
+            let mut mutated = member.to_vec();
+            for _ in 0..1 + rng.below(4) {
+                let i = rng.below(mutated.len() as u64) as usize;
+                mutated[i] ^= 1 << rng.below(8);
+            }
+            if rng.below(4) == 0 {
+                mutated.truncate(rng.below(mutated.len() as u64) as usize);
+            }
+            let _ = decompress(&mutated);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_data() {
+        let mut compressed = compress(b"Pizzeria Da Mario,055 123456\n");
+        assert!(decompress(&compressed[..compressed.len() - 3]).is_err());
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 1;
+        assert!(decompress(&compressed).is_err());
+        assert!(decompress(b"nome,telefono\n").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Recording of the scraping requests in the HAR 1.2 format (http://www.softwareishard.com/blog/har-12-spec/),
+//! readable by browser devtools and HAR viewers.
+
+use crate::{error::ScraperError, json};
+
+#[derive(serde::Serialize)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(serde::Serialize)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: &'a [Entry],
+}
+
+#[derive(serde::Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    started_date_time: String,
+    /// total milliseconds, from sending the request to the end of the body
+    time: f64,
+    request: Request,
+    response: Response,
+    cache: Cache,
+    timings: Timings,
+    /// why the request failed without a response
+    #[serde(rename = "_error", skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: &'static str,
+    url: String,
+    http_version: String,
+    cookies: [Header; 0],
+    headers: Vec<Header>,
+    query_string: [Header; 0],
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: [Header; 0],
+    headers: Vec<Header>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(serde::Serialize)]
+struct Cache {}
+
+#[derive(serde::Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+fn headers(headers: &reqwest::header::HeaderMap) -> Vec<Header> {
+    headers.iter()
+        .map(|(name, value)| Header { name: name.to_string(), value: String::from_utf8_lossy(value.as_bytes()).into_owned() })
+        .collect()
+}
+
+fn millis(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The request start as an ISO 8601 UTC date (e.g. `2025-03-01T10:20:30.123Z`).
+pub fn iso_date(time: std::time::SystemTime) -> String {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        day_secs / 3600, day_secs % 3600 / 60, day_secs % 60, since_epoch.subsec_millis(),
+    )
+}
+
+/// A request being recorded, from when it's sent.
+pub struct Recording {
+    url: String,
+    request_headers: Vec<Header>,
+    started: std::time::SystemTime,
+    start: std::time::Instant,
+    wait: std::time::Duration,
+    response: Option<Response>,
+}
+
+impl Recording {
+    pub fn start(request: &reqwest::Request) -> Self {
+        Self {
+            url: request.url().to_string(),
+            request_headers: headers(request.headers()),
+            started: std::time::SystemTime::now(),
+            start: std::time::Instant::now(),
+            wait: std::time::Duration::ZERO,
+            response: None,
+        }
+    }
+
+    /// Records the response status and headers, once they are received.
+    pub fn response(&mut self, response: &reqwest::Response) {
+        self.wait = self.start.elapsed();
+
+        // redirects are followed by the client, so only the final response is known: its url stands for the redirect target
+        let final_url = response.url().to_string();
+        self.response = Some(Response {
+            status: response.status().as_u16(),
+            status_text: response.status().canonical_reason().unwrap_or_default().to_string(),
+            http_version: format!("{:?}", response.version()),
+            cookies: [],
+            headers: headers(response.headers()),
+            content: Content {
+                size: -1,
+                mime_type: response.headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                text: None,
+            },
+            redirect_url: if final_url != self.url { final_url } else { String::new() },
+            headers_size: -1,
+            body_size: -1,
+        });
+    }
+
+    /// Completes the entry with the body, whose text is kept only if `keep_body` is set,
+    /// or with the error that prevented getting the page.
+    pub fn finish(self, body: Result<&str, String>, keep_body: bool) -> Entry {
+        let total = self.start.elapsed();
+        let error = body.as_ref().err().cloned();
+        // no response at all, as in the HAR files of the browsers
+        let mut response = self.response.unwrap_or_else(|| Response {
+            status: 0,
+            status_text: String::new(),
+            http_version: String::new(),
+            cookies: [],
+            headers: Vec::new(),
+            content: Content { size: 0, mime_type: String::new(), text: None },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        });
+
+        if let Ok(body) = body {
+            response.content.size = body.len() as i64;
+            response.body_size = body.len() as i64;
+            response.content.text = keep_body.then(|| body.to_string());
+        }
+
+        Entry {
+            started_date_time: iso_date(self.started),
+            time: millis(total),
+            request: Request {
+                method: "GET",
+                url: self.url,
+                // the request goes out with the version the response comes back with
+                http_version: response.http_version.clone(),
+                cookies: [],
+                headers: self.request_headers,
+                query_string: [],
+                headers_size: -1,
+                body_size: 0,
+            },
+            response,
+            cache: Cache {},
+            timings: Timings { send: 0.0, wait: millis(self.wait), receive: millis(total.saturating_sub(self.wait)) },
+            error,
+        }
+    }
+}
+
+/// Writes the recorded entries, sorted by start time.
+pub fn write(path: &std::path::Path, entries: &mut [Entry]) -> Result<(), ScraperError> {
+    entries.sort_by(|a, b| a.started_date_time.cmp(&b.started_date_time));
+
+    let har = Har {
+        log: Log {
+            version: "1.2",
+            creator: Creator { name: env!("CARGO_PKG_NAME"), version: env!("CARGO_PKG_VERSION") },
+            entries,
+        },
+    };
+    std::fs::write(path, json::to_string(&har)?)?;
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Minimal compact JSON serializer for serde, used for the JSON outputs of the tool.
+
+use serde::ser::{self, Serialize};
+
+#[derive(Debug)]
+pub struct JsonError(String);
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl ser::Error for JsonError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        JsonError(msg.to_string())
+    }
+}
+
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, JsonError> {
+    let mut serializer = JsonSerializer { out: String::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+fn escape(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct JsonSerializer {
+    out: String,
+}
+
+/// Tracks whether a separator is needed before the next element of a sequence or map.
+pub struct Compound<'a> {
+    ser: &'a mut JsonSerializer,
+    first: bool,
+    // variants are wrapped in an object, which must be closed too
+    variant: bool,
+}
+
+impl Compound<'_> {
+    fn separator(&mut self) {
+        if !self.first {
+            self.ser.out.push(',');
+        }
+        self.first = false;
+    }
+
+    fn key(&mut self, key: &str) {
+        self.separator();
+        escape(&mut self.ser.out, key);
+        self.ser.out.push(':');
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut JsonSerializer {
+    type Ok = ();
+    type Error = JsonError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), JsonError> {
+        self.out.push_str(if v { "true" } else { "false" });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), JsonError> { self.serialize_i64(v as i64) }
+    fn serialize_i16(self, v: i16) -> Result<(), JsonError> { self.serialize_i64(v as i64) }
+    fn serialize_i32(self, v: i32) -> Result<(), JsonError> { self.serialize_i64(v as i64) }
+    fn serialize_i64(self, v: i64) -> Result<(), JsonError> {
+        self.out.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), JsonError> { self.serialize_u64(v as u64) }
+    fn serialize_u16(self, v: u16) -> Result<(), JsonError> { self.serialize_u64(v as u64) }
+    fn serialize_u32(self, v: u32) -> Result<(), JsonError> { self.serialize_u64(v as u64) }
+    fn serialize_u64(self, v: u64) -> Result<(), JsonError> {
+        self.out.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), JsonError> { self.serialize_f64(v as f64) }
+    fn serialize_f64(self, v: f64) -> Result<(), JsonError> {
+        if v.is_finite() {
+            self.out.push_str(&v.to_string());
+        } else {
+            self.out.push_str("null");
+        }
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), JsonError> {
+        escape(&mut self.out, &v.to_string());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), JsonError> {
+        escape(&mut self.out, v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), JsonError> {
+        use ser::SerializeSeq;
+        let mut seq = self.serialize_seq(Some(v.len()))?;
+        for b in v {
+            seq.serialize_element(b)?;
+        }
+        seq.end()
+    }
+
+    fn serialize_none(self) -> Result<(), JsonError> { self.serialize_unit() }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), JsonError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), JsonError> {
+        self.out.push_str("null");
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), JsonError> { self.serialize_unit() }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), JsonError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), JsonError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<(), JsonError> {
+        self.out.push('{');
+        escape(&mut self.out, variant);
+        self.out.push(':');
+        value.serialize(&mut *self)?;
+        self.out.push('}');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, JsonError> {
+        self.out.push('[');
+        Ok(Compound { ser: self, first: true, variant: false })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, JsonError> { self.serialize_seq(Some(len)) }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, JsonError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Compound<'a>, JsonError> {
+        self.out.push('{');
+        escape(&mut self.out, variant);
+        self.out.push_str(":[");
+        Ok(Compound { ser: self, first: true, variant: true })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, JsonError> {
+        self.out.push('{');
+        Ok(Compound { ser: self, first: true, variant: false })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, JsonError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Compound<'a>, JsonError> {
+        self.out.push('{');
+        escape(&mut self.out, variant);
+        self.out.push_str(":{");
+        Ok(Compound { ser: self, first: true, variant: true })
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.separator();
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), JsonError> {
+        self.ser.out.push(']');
+        if self.variant {
+            self.ser.out.push('}');
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), JsonError> { ser::SerializeSeq::end(self) }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), JsonError> { ser::SerializeSeq::end(self) }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), JsonError> { ser::SerializeSeq::end(self) }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), JsonError> {
+        // json keys must be strings, so the key is serialized and quoted if it isn't one already
+        let key = to_string(key)?;
+        self.separator();
+        if key.starts_with('"') {
+            self.ser.out.push_str(&key);
+        } else {
+            escape(&mut self.ser.out, &key);
+        }
+        self.ser.out.push(':');
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), JsonError> {
+        self.ser.out.push('}');
+        if self.variant {
+            self.ser.out.push('}');
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), JsonError> {
+        self.key(key);
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), JsonError> { ser::SerializeMap::end(self) }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), JsonError> {
+        self.key(key);
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), JsonError> { ser::SerializeMap::end(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Serialize)]
+    enum Status {
+        Alive,
+        Dead(u16),
+        Moved { to: &'static str },
+    }
+
+    #[derive(serde::Serialize)]
+    struct Row {
+        name: &'static str,
+        website: Option<&'static str>,
+        phones: Vec<&'static str>,
+        statuses: Vec<Status>,
+        ratio: f64,
+    }
+
+    #[test]
+    fn serializes_compactly() {
+        let row = Row {
+            name: "Bar \"Centrale\"\n\tsnc \\ \u{1}",
+            website: None,
+            phones: vec!["055 123456", "347 1234567"],
+            statuses: vec![Status::Alive, Status::Dead(404), Status::Moved { to: "https://bar.it" }],
+            ratio: f64::NAN,
+        };
+        assert_eq!(to_string(&row).unwrap(), concat!(
+            r#"{"name":"Bar \"Centrale\"\n\tsnc \\ \u0001","website":null,"phones":["055 123456","347 1234567"],"#,
+            r#""statuses":["Alive",{"Dead":404},{"Moved":{"to":"https://bar.it"}}],"ratio":null}"#,
+        ));
+    }
+
+    #[test]
+    fn quotes_the_map_keys() {
+        let counts = std::collections::BTreeMap::from([(1, "uno"), (2, "due")]);
+        assert_eq!(to_string(&counts).unwrap(), r#"{"1":"uno","2":"due"}"#);
+        assert_eq!(to_string(&std::collections::BTreeMap::<String, ()>::new()).unwrap(), "{}");
+        assert_eq!(to_string(&Vec::<u8>::new()).unwrap(), "[]");
+    }
+}
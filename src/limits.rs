@@ -0,0 +1,253 @@
+//! Concurrency limits by host: PagineGialle and every external host get their own limit,
+//! so that a slow external site can't hold up the PagineGialle requests, and the other way around.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, LocalBoxStream};
+use futures::{FutureExt, Stream, StreamExt};
+
+/// At most this many futures wait in the queues: past it, the stream isn't read until some of them start,
+/// so that a slow external host can't drain the whole stream into its queue.
+const LOOK_AHEAD: usize = 256;
+
+/// The hosts sharing a concurrency limit.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum HostGroup {
+    PagineGialle,
+    External(String),
+}
+
+impl HostGroup {
+    pub fn of(url: &str) -> Self {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .unwrap_or_default();
+
+        if host == "paginegialle.it" || host.ends_with(".paginegialle.it") {
+            HostGroup::PagineGialle
+        } else {
+            HostGroup::External(host)
+        }
+    }
+}
+
+/// Runs the futures of the stream concurrently like `buffer_unordered`, but with a limit for each host group:
+/// up to `paginegialle` PagineGialle futures at once, and up to `external` for each other host.
+/// Futures whose host is at its limit wait in a queue, while the stream keeps being read for the other hosts
+/// until a PagineGialle future has to wait: with only PagineGialle urls, the stream is read just like `buffer_unordered` does,
+/// while the urls of a slow external host pile up in the queue rather than holding up the PagineGialle ones behind them,
+/// up to [`LOOK_AHEAD`] waiting futures.
+pub fn buffer_by_host<'a, T: 'a>(
+    stream: impl Stream<Item = (HostGroup, impl std::future::Future<Output = T> + 'a)> + 'a,
+    paginegialle: usize,
+    external: usize,
+) -> BufferByHost<'a, T> {
+    BufferByHost {
+        stream: Some(stream.map(|(group, future)| (group, future.boxed_local())).boxed_local()),
+        queued: HashMap::new(),
+        queued_count: 0,
+        in_flight: HashMap::new(),
+        running: FuturesUnordered::new(),
+        paginegialle,
+        external,
+    }
+}
+
+pub struct BufferByHost<'a, T> {
+    /// `None` once exhausted
+    stream: Option<LocalBoxStream<'a, (HostGroup, LocalBoxFuture<'a, T>)>>,
+    /// futures waiting for their host to be below its limit, without empty queues
+    queued: HashMap<HostGroup, VecDeque<LocalBoxFuture<'a, T>>>,
+    /// futures in all the queues
+    queued_count: usize,
+    in_flight: HashMap<HostGroup, usize>,
+    running: FuturesUnordered<LocalBoxFuture<'a, (HostGroup, T)>>,
+    paginegialle: usize,
+    external: usize,
+}
+
+impl<'a, T: 'a> BufferByHost<'a, T> {
+    fn limit(&self, group: &HostGroup) -> usize {
+        match group {
+            HostGroup::PagineGialle => self.paginegialle,
+            HostGroup::External(_) => self.external,
+        }
+        .max(1)
+    }
+
+    /// Starts the queued futures of the hosts below their limit.
+    fn start_queued(&mut self) {
+        let groups = self.queued.keys().cloned().collect::<Vec<_>>();
+        for group in groups {
+            while self.in_flight.get(&group).copied().unwrap_or(0) < self.limit(&group) {
+                let Some(future) = self.queued.get_mut(&group).and_then(VecDeque::pop_front) else { break; };
+                *self.in_flight.entry(group.clone()).or_insert(0) += 1;
+                self.queued_count -= 1;
+
+                let tag = group.clone();
+                self.running.push(future.map(move |output| (tag, output)).boxed_local());
+            }
+            if self.queued.get(&group).is_some_and(VecDeque::is_empty) {
+                self.queued.remove(&group);
+            }
+        }
+    }
+}
+
+impl<'a, T: 'a> Stream for BufferByHost<'a, T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = &mut *self;
+        // read the stream while no PagineGialle future is waiting, and the queues aren't full
+        while !this.queued.contains_key(&HostGroup::PagineGialle) && this.queued_count < LOOK_AHEAD {
+            let Some(stream) = this.stream.as_mut() else { break; };
+            match stream.poll_next_unpin(cx) {
+                Poll::Ready(Some((group, future))) => {
+                    this.queued.entry(group).or_default().push_back(future);
+                    this.queued_count += 1;
+                    this.start_queued();
+                }
+                Poll::Ready(None) => this.stream = None,
+                Poll::Pending => break,
+            }
+        }
+        this.start_queued();
+
+        match this.running.poll_next_unpin(cx) {
+            Poll::Ready(Some((group, output))) => {
+                if let Some(in_flight) = this.in_flight.get_mut(&group) {
+                    *in_flight -= 1;
+                }
+                // a slot is free: a waiting future can start, and the stream can be read again
+                cx.waker().wake_by_ref();
+                Poll::Ready(Some(output))
+            }
+            Poll::Ready(None) if this.stream.is_none() && this.queued.is_empty() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio::sync::oneshot;
+
+    fn external(host: &str) -> HostGroup {
+        HostGroup::External(host.to_string())
+    }
+
+    #[test]
+    fn groups_the_hosts() {
+        assert!(HostGroup::of("https://www.paginegialle.it/ricerca/pizzerie") == HostGroup::PagineGialle);
+        assert!(HostGroup::of("https://paginegialle.it/") == HostGroup::PagineGialle);
+        assert!(HostGroup::of("https://notpaginegialle.it/") == external("notpaginegialle.it"));
+        assert!(HostGroup::of("https://example.com/a") == external("example.com"));
+    }
+
+    #[tokio::test]
+    async fn slow_external_hosts_dont_hold_up_paginegialle() {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..3).map(|_| oneshot::channel::<()>()).unzip();
+        let slow = receivers.into_iter().map(|rx| (external("slow.it"), async move { rx.await.ok(); "slow" }.boxed_local()));
+        let fast = (0..3).map(|_| (HostGroup::PagineGialle, async { "pg" }.boxed_local()));
+
+        let mut outputs = buffer_by_host(futures::stream::iter(slow.chain(fast)), 2, 1);
+        for _ in 0..3 {
+            assert_eq!(outputs.next().await, Some("pg"));
+        }
+        assert_eq!(outputs.next().now_or_never(), None);
+
+        for sender in senders {
+            sender.send(()).unwrap();
+        }
+        assert_eq!(outputs.collect::<Vec<_>>().await, ["slow"; 3]);
+    }
+
+    #[tokio::test]
+    async fn keeps_each_host_below_its_limit() {
+        let in_flight = Rc::new(Cell::new([0usize; 3]));
+        let most = Rc::new(Cell::new([0usize; 3]));
+        let futures = (0..30).map(|i| {
+            let (in_flight, most) = (in_flight.clone(), most.clone());
+            let (group, slot) = match i % 3 {
+                0 => (HostGroup::PagineGialle, 0),
+                1 => (external("a.it"), 1),
+                _ => (external("b.it"), 2),
+            };
+            let future = async move {
+                let mut counts = in_flight.get();
+                counts[slot] += 1;
+                in_flight.set(counts);
+                let mut peaks = most.get();
+                peaks[slot] = peaks[slot].max(counts[slot]);
+                most.set(peaks);
+
+                for _ in 0..3 {
+                    tokio::task::yield_now().await;
+                }
+                let mut counts = in_flight.get();
+                counts[slot] -= 1;
+                in_flight.set(counts);
+                i
+            };
+            (group, future)
+        });
+
+        let mut outputs = buffer_by_host(futures::stream::iter(futures), 4, 2).collect::<Vec<_>>().await;
+        outputs.sort();
+        assert_eq!(outputs, (0..30).collect::<Vec<_>>());
+        assert_eq!(most.get(), [4, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn wakes_up_when_a_future_completes() {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..4).map(|_| oneshot::channel::<usize>()).unzip();
+        let futures = receivers.into_iter().map(|rx| (HostGroup::PagineGialle, async move { rx.await.unwrap() }));
+
+        // the futures are completed from another task, one at a time
+        tokio::spawn(async move {
+            for (i, sender) in senders.into_iter().enumerate() {
+                tokio::task::yield_now().await;
+                sender.send(i).unwrap();
+            }
+        });
+
+        let outputs = buffer_by_host(futures::stream::iter(futures), 1, 1).collect::<Vec<_>>().await;
+        assert_eq!(outputs, [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn ends_with_the_stream_and_the_queues() {
+        let empty = futures::stream::iter(Vec::<(HostGroup, futures::future::Ready<()>)>::new());
+        assert_eq!(buffer_by_host(empty, 1, 1).next().await, None);
+
+        // all the futures of a host are queued behind the first when the stream ends
+        let futures = (0..5).map(|i| (external("a.it"), async move { i }));
+        let mut outputs = buffer_by_host(futures::stream::iter(futures), 1, 1);
+        for i in 0..5 {
+            assert_eq!(outputs.next().await, Some(i));
+        }
+        assert_eq!(outputs.next().await, None);
+        assert_eq!(outputs.next().await, None);
+    }
+
+    #[test]
+    fn doesnt_drain_the_stream_into_the_queues() {
+        let read = Rc::new(Cell::new(0));
+        let counter = read.clone();
+        let futures = futures::stream::iter(0..100_000)
+            .inspect(move |_| counter.set(counter.get() + 1))
+            .map(|_| (external("slow.it"), futures::future::pending::<()>()));
+
+        let mut outputs = buffer_by_host(futures, 1, 1);
+        assert_eq!(outputs.next().now_or_never(), None);
+        assert_eq!(read.get(), LOOK_AHEAD + 1);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::{HashMap, HashSet}, io::Write};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, io::Write};
 
 use clap::Parser;
 use deunicode::deunicode;
@@ -6,104 +6,410 @@ use futures::StreamExt;
 
 mod cli;
 use cli::*;
-
-const PAGINEGIALLE_URL: &'static str = "https://www.paginegialle.it";
-const PAGINEGIALLE_CATEGORIE_URL: &'static str = "https://www.paginegialle.it/categorie.htm";
-const COMUNI_API_URL: &'static str = "https://axqvoqvbfjpaamphztgd.functions.supabase.co/comuni/";
+mod regions;
+mod parse;
+mod json;
+mod error;
+mod bloom;
+mod robots;
+mod encoding;
+mod har;
+mod cookies;
+mod limits;
+mod template;
+mod webhook;
+mod phone;
+mod throttle;
+mod campaign;
+mod gzip;
+mod rng;
+use bloom::BloomFilter;
+use error::ScraperError;
+use parse::Selectors;
+use phone::{PhoneKind, PhoneNumber};
+use template::OutputTemplate;
+
+const PAGINEGIALLE_URL: &str = "https://www.paginegialle.it";
+const PAGINEGIALLE_CATEGORIE_URL: &str = "https://www.paginegialle.it/categorie.htm";
+const COMUNI_API_URL: &str = "https://axqvoqvbfjpaamphztgd.functions.supabase.co/comuni/";
 const DEFAULT_PAGE_LIMIT: usize = 5;
 const DEFAULT_REQUESTS_BATCH: usize = 50;
-
-#[derive(Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+const DEFAULT_EXTERNAL_CONCURRENCY: usize = 4;
+const DEFAULT_CATEGORY_ERROR_LIMIT: usize = 10;
+const LARGE_PAGE_LIMIT: usize = 50;
+const BLOCKED_PAGES_THRESHOLD: usize = 10;
+// sizing of the --approx-dedup filter, about 3.6MB
+const APPROX_DEDUP_CAPACITY: usize = 2_000_000;
+const APPROX_DEDUP_FP_RATE: f64 = 0.001;
+// all-categories Filter scrapes sending at least this many requests must be confirmed
+const CONFIRM_REQUESTS_THRESHOLD: usize = 1000;
+// scraped entries waiting to be collected, before the scraping waits for the collector
+const RESULTS_CHANNEL_CAPACITY: usize = 1024;
+// average listings below which a page number is not worth requesting
+const NEAR_EMPTY_PAGE_YIELD: f64 = 1.0;
+// requests listed by --profile
+const PROFILE_SLOWEST_REQUESTS: usize = 10;
+// runs merged at once by --low-memory, each holding a file open
+const MERGE_FAN_IN: usize = 64;
+const BOOTSTRAP_RETRIES: u32 = 3;
+const BOOTSTRAP_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const WEBSITE_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct BusinessEntry {
     name: String,
     address: String,
-    phones: String,
+    #[serde(serialize_with = "phone::serialize_packed", deserialize_with = "phone::deserialize_packed")]
+    phones: Vec<PhoneNumber>,
     whatsapp: Option<String>,
     website: Option<String>,
     contact_url: Option<String>,
+    #[serde(default)]
+    facebook: Option<String>,
+    #[serde(default)]
+    instagram: Option<String>,
+    #[serde(default)]
+    source_url: Option<String>,
+    /// `https://wa.me/` link of the whatsapp number, only filled in with `--whatsapp-format both`
+    #[serde(default)]
+    whatsapp_link: Option<String>,
+    /// address parts, only filled in with `--split-address`. An address that can't be split is kept whole in the street
+    #[serde(default)]
+    street: Option<String>,
+    #[serde(default)]
+    cap: Option<String>,
+    #[serde(default)]
+    city: Option<String>,
+    /// [`BusinessEntry::score`], only filled in with `--score`
+    #[serde(default)]
+    score: Option<u32>,
+    /// phones by type, only filled in with `--split-phone-types`
+    #[serde(default)]
+    mobile_phones: Option<String>,
+    #[serde(default)]
+    landline_phones: Option<String>,
+    /// status code the website answered with, or `dead`, only filled in with `--validate-websites`
+    #[serde(default)]
+    website_status: Option<String>,
+    /// the fields as scraped, before any formatting or normalization, only filled in with `--preserve-raw`
+    #[serde(default)]
+    name_raw: Option<String>,
+    #[serde(default)]
+    address_raw: Option<String>,
+    #[serde(default)]
+    phones_raw: Option<String>,
+    /// absolute url of the logo shown in the listing
+    #[serde(default)]
+    logo_url: Option<String>,
+    /// categories of the pages the entry was scraped from, sorted, only known in Filter mode.
+    /// A business listed in many categories keeps all of them once its duplicates are merged
+    #[serde(default, serialize_with = "serialize_categories", deserialize_with = "deserialize_categories")]
+    categories: Vec<String>,
 }
 
-// TODO: consider caching these (they are static data)
-async fn get_all_categories() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    // THIS ONLY GETS THE MOST POPULAR CATEGORIES
-    let html = reqwest::get(PAGINEGIALLE_CATEGORIE_URL)
-        .await?.text().await?;
-    let document = scraper::Html::parse_document(&html);
+/// The categories of an entry are packed in a single CSV field, as its phones are.
+const CATEGORIES_SEPARATOR: &str = " | ";
 
-    let category_selector = scraper::Selector::parse(".categorie__item")?;
-    
-    let categories = document.select(&category_selector)
-        .map(|e| e.text().collect::<String>().trim().to_string())
-        .map(|s| s.to_lowercase().replace(|c: char| c.is_whitespace() || c.is_ascii_punctuation(), "_"))
-        .collect();
+fn serialize_categories<S: serde::Serializer>(categories: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&categories.join(CATEGORIES_SEPARATOR))
+}
 
-    Ok(categories)
+fn deserialize_categories<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let packed = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(packed.split(CATEGORIES_SEPARATOR).map(str::trim).filter(|c| !c.is_empty()).map(str::to_string).collect())
+}
+
+// social links are only present on some listings of the same business,
+// and the same business can be found on many pages, so both are left out of the dedup identity
+impl PartialEq for BusinessEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+        && self.address == other.address
+        && self.phones == other.phones
+        && self.whatsapp == other.whatsapp
+        && self.website == other.website
+        && self.contact_url == other.contact_url
+    }
+}
+
+impl Eq for BusinessEntry {}
+
+impl std::hash::Hash for BusinessEntry {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.address.hash(state);
+        self.phones.hash(state);
+        self.whatsapp.hash(state);
+        self.website.hash(state);
+        self.contact_url.hash(state);
+    }
+}
+
+/// Fetches the bootstrap data (comuni and categories) needed before scraping, retrying with exponential backoff
+/// on network and server errors, as a single transient failure would abort the whole run.
+async fn fetch_with_retry(client: &reqwest::Client, url: &str) -> Result<String, ScraperError> {
+    // bootstrap data is static, so it's fetched only once per run even when scraping many targets
+    static CACHE: std::sync::LazyLock<std::sync::Mutex<HashMap<String, String>>> = std::sync::LazyLock::new(Default::default);
+
+    if let Some(text) = CACHE.lock().unwrap().get(url) {
+        return Ok(text.clone());
+    }
+
+    let text = fetch_with_retry_uncached(client, url).await?;
+    CACHE.lock().unwrap().insert(url.to_string(), text.clone());
+    Ok(text)
+}
+
+async fn fetch_with_retry_uncached(client: &reqwest::Client, url: &str) -> Result<String, ScraperError> {
+    let mut delay = BOOTSTRAP_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let res = cookies::send(client.get(url)).await
+            .and_then(|r| if r.status().is_server_error() { r.error_for_status() } else { Ok(r) });
+
+        let err = match res {
+            Ok(res) => match res.text().await {
+                Ok(text) => return Ok(text),
+                Err(e) => e,
+            }
+            Err(e) => e,
+        };
+
+        if attempt >= BOOTSTRAP_RETRIES {
+            return Err(ScraperError::Fetch { url: url.to_string(), attempts: attempt, source: err });
+        }
+
+        eprintln!("Tentativo {attempt} fallito per {url}: {err}. Nuovo tentativo tra {delay:?}...");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// The random number generator of the run, seeded with `--seed` or randomly.
+/// A random seed is printed when the run uses randomness, so that the run can be reproduced.
+fn seeded_rng(cli: &Cli) -> rng::SeededRng {
+    use std::hash::{BuildHasher, Hasher};
+
+    let seed = cli.seed.unwrap_or_else(|| {
+        // the std hasher keys are random for each process
+        let seed = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        if cli.shuffle {
+            println!("Seed casuale: {seed} (passa --seed {seed} per ripetere la stessa esecuzione)");
+        }
+        seed
+    });
+
+    rng::SeededRng::new(seed)
+}
+
+/// Shuffles the order of the urls within each category, so that the comuni are not requested one after the other.
+/// Categories are kept in order, as `--category-pause-secs` relies on them being contiguous.
+fn shuffle_urls(urls: &mut [String], rng: &mut rng::SeededRng) {
+    for chunk in urls.chunk_by_mut(|a, b| category_from_url(a) == category_from_url(b)) {
+        rng.shuffle(chunk);
+    }
+}
+
+/// Drops the urls disallowed by the `robots.txt` of their site, returning how many were dropped.
+/// Each `robots.txt` is fetched once per run; a missing one allows everything.
+async fn retain_robots_allowed(client: &reqwest::Client, urls: &mut Vec<String>) -> Result<usize, ScraperError> {
+    static CACHE: std::sync::LazyLock<std::sync::Mutex<HashMap<String, robots::Robots>>> = std::sync::LazyLock::new(Default::default);
+
+    let parsed = urls.iter().filter_map(|u| reqwest::Url::parse(u).ok()).collect::<Vec<_>>();
+    for url in &parsed {
+        let origin = url.origin().ascii_serialization();
+        if CACHE.lock().unwrap().contains_key(&origin) {
+            continue;
+        }
+
+        let res = cookies::send(client.get(format!("{origin}/robots.txt"))).await?;
+        let text = if res.status().is_success() { res.text().await? } else { String::new() };
+        CACHE.lock().unwrap().insert(origin, robots::Robots::parse(&text));
+    }
+
+    let cache = CACHE.lock().unwrap();
+    let before = urls.len();
+    urls.retain(|u| match reqwest::Url::parse(u) {
+        Ok(url) => cache.get(&url.origin().ascii_serialization()).is_none_or(|r| r.allows(url.path())),
+        Err(_) => true,
+    });
+    Ok(before - urls.len())
+}
+
+/// A PagineGialle category, with the slug used in its urls and the name shown on the site.
+#[derive(Clone)]
+struct Category {
+    /// e.g. `ristoranti_e_trattorie`
+    slug: String,
+    /// e.g. `Ristoranti e Trattorie`
+    name: String,
+}
 
-    // THIS GETS ALL CATEGORIES
-    // This collects too many pages.
-    // let category_page_selector = scraper::Selector::parse(".categorie__item--show a")?;
-    // let subcategory_selector = scraper::Selector::parse(".categorie-macro__box-corr__itm a")?;
-    // let categories = document.select(&category_page_selector)
-    //     .map(|e| e.attr("href").unwrap_or_default())
-    //     .filter(|e| !e.is_empty())
-    //     .collect::<Vec<_>>();
+/// The category name in the form used in the urls (e.g. `Ristoranti pizzerie` -> `ristoranti_pizzerie`).
+fn category_slug(name: &str) -> String {
+    name.to_lowercase().replace(|c: char| c.is_whitespace() || c.is_ascii_punctuation(), "_")
+}
+
+/// Suggests the Filter mode when the search query is a known category, as category pages give cleaner results.
+async fn suggest_category(client: &reqwest::Client, params: &SearchMode) {
+    // the suggestion is only a nicety, a failure to get the categories is not worth reporting
+    let Ok(categories) = get_all_categories(client, false).await else { return; };
+
+    let slug = category_slug(params.query.trim());
+    if let Some(category) = categories.iter().find(|c| c.slug == slug) {
+        println!(
+            "`{}` e' una categoria: la modalita' filter (`filter <regione> [citta] --category {slug}`) usa le pagine della categoria, con risultati piu' precisi.",
+            category.name,
+        );
+    }
+}
+
+/// Fetches a comuni list, telling apart the comuni service being unreachable.
+async fn fetch_comuni(client: &reqwest::Client, url: &str) -> Result<String, ScraperError> {
+    fetch_with_retry(client, url).await.map_err(|e| match e {
+        ScraperError::Fetch { url, source, .. } => ScraperError::ComuniUnreachable { url, source },
+        e => e,
+    })
+}
+
+/// The categories on the PagineGialle categories page: only the most popular ones,
+/// or with `all` also every subcategory listed in the page of each macro category.
+/// The list is fetched once per run, as batch rows may ask for it many times.
+async fn get_all_categories(client: &reqwest::Client, all: bool) -> Result<Vec<Category>, ScraperError> {
+    static CACHE: std::sync::LazyLock<std::sync::Mutex<HashMap<bool, Vec<Category>>>> = std::sync::LazyLock::new(Default::default);
+
+    if let Some(categories) = CACHE.lock().unwrap().get(&all) {
+        return Ok(categories.clone());
+    }
+
+    let html = fetch_with_retry(client, PAGINEGIALLE_CATEGORIE_URL).await?;
+    let document = scraper::Html::parse_document(&html);
+
+    let selector = |s: &str| scraper::Selector::parse(s).map_err(|e| ScraperError::Selector(format!("categories: `{s}` ({e})")));
+    let category_selector = selector(".categorie__item")?;
+
+    let mut categories = document.select(&category_selector)
+        .map(|e| e.text().collect::<String>().trim().to_string())
+        .map(|name| Category { slug: category_slug(&name), name })
+        .collect::<Vec<_>>();
 
-    // let mut subcategories = Vec::new();
+    if all {
+        let category_page_selector = selector(".categorie__item--show a")?;
+        let subcategory_selector = selector(".categorie-macro__box-corr__itm a")?;
+        let pages = document.select(&category_page_selector)
+            .filter_map(|e| e.attr("href"))
+            .filter(|href| !href.is_empty())
+            .filter_map(|href| reqwest::Url::parse(PAGINEGIALLE_CATEGORIE_URL).and_then(|base| base.join(href)).ok())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        println!("Ricerca delle sottocategorie in {} pagine di categorie...", pages.len());
 
-    // // TODO: this is not async
-    // for subcategory_url in categories {
-    //     let html = client.get(subcategory_url).send().await?.text().await?;
-    //     let document = scraper::Html::parse_document(&html);
+        // the pages are fetched concurrently like the listing pages, and their subcategories are kept in the pages order
+        let subcategories = futures::stream::iter(&pages)
+            .map(|url| fetch_with_retry(client, url))
+            .buffered(DEFAULT_REQUESTS_BATCH)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut seen = categories.iter().map(|c| c.slug.clone()).collect::<HashSet<_>>();
+        for (url, html) in pages.iter().zip(subcategories) {
+            let html = match html {
+                Ok(html) => html,
+                Err(e) => {
+                    eprintln!("Sottocategorie di {url} saltate: {e}");
+                    continue;
+                }
+            };
+            let document = scraper::Html::parse_document(&html);
+            for e in document.select(&subcategory_selector) {
+                let name = e.text().collect::<String>().trim().to_string();
+                let slug = category_slug(&name);
+                if !name.is_empty() && seen.insert(slug.clone()) {
+                    categories.push(Category { slug, name });
+                }
+            }
+        }
+    }
 
-    //     document.select(&subcategory_selector)
-    //     .map(|e| e.text().collect::<String>().trim().to_string())
-    //     .map(|s| s.to_lowercase().replace(|c: char| c.is_whitespace() || c.is_ascii_punctuation(), "_"))
-    //     .for_each(|c| subcategories.push(c));
-    // }
+    CACHE.lock().unwrap().insert(all, categories.clone());
+    Ok(categories)
 }
 
 fn sanitize_comune_str(comune: &str) -> String {
-    let s = comune.trim_end_matches(|c: char| c.is_ascii_punctuation())
-        .replace(|c: char| c.is_whitespace(), "_")
-        .replace(|c: char| c.is_ascii_punctuation(), "_")
-        .to_lowercase();
+    // transliterate first, as it might produce punctuation too (e.g. `ʼ`);
+    // runs of separators become a single underscore (`Sant'Agata de' Goti` -> `sant_agata_de_goti`)
+    deunicode(comune)
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
 
-    deunicode(&s)
+/// Builds a PagineGialle url from its path segments, percent-encoding anything that isn't url safe.
+fn paginegialle_url(segments: &[&str]) -> String {
+    let mut url = reqwest::Url::parse(PAGINEGIALLE_URL).expect("PAGINEGIALLE_URL is a valid url");
+    url.path_segments_mut()
+        .expect("PAGINEGIALLE_URL can have a path")
+        .clear()
+        .extend(segments);
+
+    url.to_string()
 }
 
-fn parse_comuni_names_from_csv(comuni_csv: &str, filter_for_big_cities: bool) -> Vec<String> {
-    let comuni_reader = csv::ReaderBuilder::new()
+fn parse_comuni_names_from_csv(comuni_csv: &str, params: &FilterOptions) -> Result<Vec<String>, ScraperError> {
+    let mut comuni_reader = csv::ReaderBuilder::new()
         .delimiter(b';')
+        .flexible(true)
         .from_reader(comuni_csv.as_bytes());
-    
-    #[derive(serde::Deserialize, Debug, Default)]
-    struct Comune {
-        nome: String,
-        popolazione: usize,
+
+    let headers = comuni_reader.headers()?.clone();
+    let records = comuni_reader.into_records().collect::<Result<Vec<_>, _>>()?;
+
+    // no comuni at all (e.g. the city is not a province)
+    if records.is_empty() {
+        return Ok(vec![]);
     }
 
-    if filter_for_big_cities {
-        // we try to filter out cities with fewer inhabitatns, to get fewer requests to make
+    let column = |name: &str| headers.iter()
+        .position(|h| h == name)
+        .ok_or_else(|| ScraperError::ComuniApi(format!("la colonna `{name}` non e' presente nel CSV dei comuni (colonne trovate: {})", headers.iter().collect::<Vec<_>>().join(", "))));
 
-        let mut comuni = comuni_reader.into_deserialize::<Comune>()
-            .map(|e| e.unwrap_or_default())
-            .collect::<Vec<_>>();
+    let name_idx = column(&params.comuni_name_column)?;
+    let population_idx = column(&params.comuni_population_column)?;
 
-        comuni.sort_by_key(|c| std::cmp::Reverse(c.popolazione));
-        comuni.drain(comuni.len()/2..);
+    // rows without a name (e.g. blank lines with only spaces) would make urls without a comune
+    let mut comuni = records.iter()
+        .map(|r| (
+            r.get(name_idx).unwrap_or_default().trim(),
+            r.get(population_idx).and_then(|p| p.trim().parse::<usize>().ok()).unwrap_or_default(),
+        ))
+        .filter(|(nome, _)| !nome.is_empty())
+        .collect::<Vec<_>>();
 
-        comuni.into_iter()
-            .map(|c| sanitize_comune_str(&c.nome))
-            .collect()
-    } else {
-        comuni_reader.into_deserialize::<Comune>()
-            .map(|c| c.unwrap_or_default())
-            .map(|c| sanitize_comune_str(&c.nome))
-            .collect::<Vec<_>>()
+    if params.big_cities_only {
+        // we try to filter out cities with fewer inhabitatns, to get fewer requests to make
+        // ties on the population are broken on the name, so that the same comuni are kept on every run
+        comuni.sort_by(|(nome_a, pop_a), (nome_b, pop_b)| pop_b.cmp(pop_a).then_with(|| nome_a.cmp(nome_b)));
+        // the bigger half is kept for odd counts, so that a single comune is never dropped
+        comuni.truncate(comuni.len().div_ceil(2));
     }
+
+    Ok(comuni.into_iter()
+        .map(|(nome, _)| sanitize_comune_str(nome))
+        .collect())
+}
+
+/// Where the comuni and the categories of a Filter scrape come from, for `--explain`.
+struct FilterOrigin {
+    comuni: &'static str,
+    categories: &'static str,
+    categories_count: usize,
 }
 
-async fn generate_urls_with_filter_mode(params: &FilterMode, limit: usize, debug: bool) -> Result<(Vec<String>, Vec<String>), Box<dyn std::error::Error>> {
+async fn generate_urls_with_filter_mode(client: &reqwest::Client, params: &FilterMode, pages: std::ops::Range<usize>, debug: bool) -> Result<(Vec<String>, Vec<String>, FilterOrigin), ScraperError> {
     /*
         Casi:
         1. Solo regione, cerca in tutte le provincie
@@ -111,22 +417,34 @@ async fn generate_urls_with_filter_mode(params: &FilterMode, limit: usize, debug
         3. Regione e citta, cerca solo nel comune
     */
 
-    let comuni = match &params.city {
+    // the region is slugified like the comuni, rather than put in the urls as typed
+    let region = if params.options.raw_region {
+        params.region.clone()
+    } else {
+        regions::resolve_region(&params.region).map_err(ScraperError::Parse)?
+    };
+
+    let mut comuni_origin = "citta' singola";
+    let mut comuni = match &params.city {
+        // ricerca per singola citta: la lista dei comuni non serve, funziona anche con il servizio dei comuni irraggiungibile
+        Some(city) if !params.options.all_regions_cities => vec![sanitize_comune_str(city)],
+
         Some(city) => {
-            // cerca in una sola citta oppure controlla se e' provincia
+            // controlla se e' provincia
 
             // fetch comuni list from api
             // let comuni_url = format!("{COMUNI_API_URL}/provincia/{city}?format=csv&onlyname=true");
             let comuni_url = format!("{COMUNI_API_URL}/provincia/{city}?format=csv");
-            let comuni_csv = reqwest::get(comuni_url).await?.text().await?;
+            let comuni_csv = fetch_comuni(client, &comuni_url).await?;
             
-            let comuni = parse_comuni_names_from_csv(&comuni_csv, params.big_cities_only);
+            let comuni = parse_comuni_names_from_csv(&comuni_csv, &params.options)?;
 
-            if comuni.is_empty() || !params.all_regions_cities {
-                // ricerca per singola citta
-                vec![city.clone()]
+            if comuni.is_empty() {
+                // non e' una provincia, ricerca per singola citta
+                vec![sanitize_comune_str(city)]
             } else {
                 // ricerca per tutta la provincia
+                comuni_origin = "API dei comuni (provincia)";
                 comuni
             }
         }
@@ -134,22 +452,40 @@ async fn generate_urls_with_filter_mode(params: &FilterMode, limit: usize, debug
         None => {
             // cerca in tutta la regione
 
-            let comuni_url = format!("{COMUNI_API_URL}/regione/{region}?format=csv", region = params.region);
-            let comuni_csv = reqwest::get(comuni_url).await?.text().await?;
-            parse_comuni_names_from_csv(&comuni_csv, params.big_cities_only)
+            if params.options.capoluoghi_only {
+                // solo i capoluoghi di provincia, non serve chiedere la lista dei comuni
+                comuni_origin = "capoluoghi di provincia (lista interna)";
+                regions::capoluoghi(&region)
+            } else {
+                comuni_origin = "API dei comuni (regione)";
+                let comuni_url = format!("{COMUNI_API_URL}/regione/{region}?format=csv");
+                let comuni_csv = fetch_comuni(client, &comuni_url).await?;
+                parse_comuni_names_from_csv(&comuni_csv, &params.options)?
+            }
         }
     };
     
-    let categories = if let Some(category) = &params.category {
-        vec![category.clone()]
+    if let Some(limit) = params.options.comuni_limit.filter(|&l| l < comuni.len()) {
+        println!("--comuni-limit: {} comuni esclusi, verranno cercati i primi {limit}.", comuni.len() - limit);
+        comuni.truncate(limit);
+    }
+
+    let (categories, categories_origin) = if let Some(category) = &params.category {
+        // a category given by the user is already a slug, its name is not known
+        (vec![Category { slug: category.clone(), name: category.clone() }], "specificata con --category")
     } else {
         println!("Nessuna categoria specificata. Saranno ricercate ditte per TUTTE le categorie seguenti (potrebbe impiegare molto tempo).");
-        get_all_categories().await?
+        if params.options.all_categories {
+            (get_all_categories(client, true).await?, "pagine delle categorie e sottocategorie di PagineGialle")
+        } else {
+            (get_all_categories(client, false).await?, "pagina delle categorie di PagineGialle")
+        }
     };
+    let origin = FilterOrigin { comuni: comuni_origin, categories: categories_origin, categories_count: categories.len() };
     
     if debug {
         println!("Comuni da ricercare:\n{comuni:?}\n");
-        println!("Categorie da ricercare:\n{categories:?}\n");
+        println!("Categorie da ricercare:\n{:?}\n", categories.iter().map(|c| &c.name).collect::<Vec<_>>());
     }
 
     println!("Cercando in {} comuni e {} categorie...", comuni.len(), categories.len());
@@ -157,295 +493,3541 @@ async fn generate_urls_with_filter_mode(params: &FilterMode, limit: usize, debug
     let mut urls = Vec::new();
     for category in categories {
         for comune in &comuni {
-            let base = format!("{PAGINEGIALLE_URL}/{region}/{comune}/{category}/", region = params.region);
-            for i in 0..limit {
-                let url = format!("{base}/p-{i}.html");
+            for i in pages.clone() {
+                let url = paginegialle_url(&[&region, comune, &category.slug, &format!("p-{i}.html")]);
                 urls.push(url);
             }
         }
     }
 
-    Ok((urls, comuni))
+    Ok((urls, comuni, origin))
+}
+
+/// Asks the user to confirm an all-categories scrape sending many requests, unless `--yes` was passed.
+fn confirm_all_categories(cli: &Cli, requests: usize) -> Result<(), ScraperError> {
+    use std::io::{IsTerminal, Write};
+
+    if cli.yes || requests < CONFIRM_REQUESTS_THRESHOLD {
+        return Ok(());
+    }
+    // nobody can answer, scripted runs have to pass --yes
+    if !std::io::stdin().is_terminal() {
+        return Err(ScraperError::NotConfirmed(requests));
+    }
+
+    print!("Verranno inviate {requests} richieste per tutte le categorie. Continuare? [s/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "s" | "si" | "y" | "yes" => Ok(()),
+        _ => Err(ScraperError::NotConfirmed(requests)),
+    }
+}
+
+/// A region as the search query means a Filter scrape was meant (e.g. `search lazio ristoranti`).
+fn validate_search_mode(params: &SearchMode) -> Result<(), ScraperError> {
+    if regions::is_region(&params.query) {
+        let location = params.location.as_deref().unwrap_or("<categoria>");
+        return Err(ScraperError::Parse(format!(
+            "`{}` e' una regione: la modalita' search cerca un'attivita' o una categoria, opzionalmente in un luogo. \
+            Per cercare una categoria in una regione usa `filter {} [citta] --category {location}`.",
+            params.query, params.query,
+        )));
+    }
+    Ok(())
 }
 
-fn generate_urls_with_search_mode(params: &SearchMode, limit: usize) -> Vec<String> {
-    let mut base = format!("{PAGINEGIALLE_URL}/ricerca/{}", params.query);
+/// Search urls: `ricerca/<query>[/<location>]/p-<i>`.
+fn generate_urls_with_search_mode(params: &SearchMode, pages: std::ops::Range<usize>) -> Vec<String> {
+    let mut base = vec!["ricerca", params.query.as_str()];
     if let Some(city) = &params.location {
-        base.push('/');
-        base.push_str(&city);
+        base.push(city);
     } else {
         println!("Nessuna citta' provveduta; la ricerca verra' eseguita in tutta Italia.")
     }
     
     let mut urls = Vec::new();
-    for i in 0..limit {
-        let url = format!("{base}/p-{i}");
-        urls.push(url);
+    for i in pages {
+        let page = format!("p-{i}");
+        let mut segments = base.clone();
+        segments.push(&page);
+        urls.push(paginegialle_url(&segments));
     }
 
     urls
 }
 
-fn extract_text_from_html(element: &scraper::ElementRef, selector: &scraper::Selector) -> String {
-    let html = element.select(selector)
-        .next()
-        .map(|e| e.inner_html().trim().to_string())
-        .unwrap_or_default();
+/// Category slug of a Filter mode page url (`<region>/<comune>/<category>/p-<i>.html`).
+fn category_from_url(url: &str) -> Option<String> {
+    url.split('/')
+        .filter(|s| !s.is_empty())
+        .rev()
+        .nth(1)
+        .map(|s| s.to_string())
+}
 
-    let frag = scraper::Html::parse_fragment(&html);
-    let mut tokens = Vec::new();
+/// Comune slug of a Filter mode page url (`<region>/<comune>/<category>/p-<i>.html`).
+fn comune_from_url(url: &str) -> Option<String> {
+    url.split('/')
+        .filter(|s| !s.is_empty())
+        .rev()
+        .nth(2)
+        .map(|s| s.to_string())
+}
 
-    // https://users.rust-lang.org/t/removing-html-tags-from-a-string-obtained-from-select-crate/45000
-    for node in frag.tree {
-        if let scraper::node::Node::Text(text) = node {
-            let trimmed = text.trim();
-            if !trimmed.is_empty() {
-                tokens.push(trimmed.to_string()); 
-            }
-        }
-    }
+/// Page number of a Filter mode page url (`<region>/<comune>/<category>/p-<i>.html`).
+fn page_from_url(url: &str) -> Option<usize> {
+    url.rsplit('/')
+        .next()?
+        .strip_prefix("p-")?
+        .strip_suffix(".html")?
+        .parse()
+        .ok()
+}
+
+fn read_urls_from_file(params: &UrlsMode) -> Result<Vec<String>, ScraperError> {
+    let content = std::fs::read_to_string(&params.file_path)?;
 
-    tokens.join(" ")
+    let urls = content.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect();
+
+    Ok(urls)
 }
 
-fn merge_csvs(params: &MergeMode, output: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
-    let folder = std::fs::read_dir(&params.folder_path)?;
+impl BusinessEntry {
+    /// Output columns order, which is a stable contract for consumers:
+    /// new fields must be appended at the end.
+    const CSV_HEADER: [&'static str; 22] = [
+        "name", "address", "phones", "whatsapp", "website", "contact_url", "facebook", "instagram", "source_url", "whatsapp_link",
+        "street", "cap", "city", "score", "mobile_phones", "landline_phones", "website_status", "name_raw", "address_raw", "phones_raw",
+        "logo_url", "categories",
+    ];
+
+    /// The entry fields, in the same order as [`Self::CSV_HEADER`].
+    /// The phones are packed as in the CSV files, the other fields are borrowed.
+    fn csv_record(&self) -> [Cow<'_, str>; 22] {
+        fn field(f: &Option<String>) -> Cow<'_, str> {
+            Cow::Borrowed(f.as_deref().unwrap_or_default())
+        }
 
-    let mut entries = HashSet::new();
-    let mut count = 0;
-    for csv_entry in folder {
-        let csv_entry = csv_entry?;
-        let csv_name = csv_entry.path();
+        [
+            Cow::Borrowed(&self.name),
+            Cow::Borrowed(&self.address),
+            Cow::Owned(phone::pack(&self.phones)),
+            field(&self.whatsapp),
+            field(&self.website),
+            field(&self.contact_url),
+            field(&self.facebook),
+            field(&self.instagram),
+            field(&self.source_url),
+            field(&self.whatsapp_link),
+            field(&self.street),
+            field(&self.cap),
+            field(&self.city),
+            Cow::Owned(self.score.map(|score| score.to_string()).unwrap_or_default()),
+            field(&self.mobile_phones),
+            field(&self.landline_phones),
+            field(&self.website_status),
+            field(&self.name_raw),
+            field(&self.address_raw),
+            field(&self.phones_raw),
+            field(&self.logo_url),
+            Cow::Owned(self.categories.join(CATEGORIES_SEPARATOR)),
+        ]
+    }
 
-        // skip non csv files
-        if let Some(ext) = csv_name.extension() {
-            if ext != "csv" { continue; }
-        } else { continue; }
+    /// Splits the address into street, CAP and city (e.g. `Via Roma 1 - 50100 Firenze (FI)`).
+    /// Without a CAP the address can't be split, and it's kept whole in the street.
+    fn split_address(&mut self) {
+        match AddressParts::parse(&self.address) {
+            Some(parts) => {
+                self.street = parts.street;
+                self.cap = Some(parts.cap);
+                self.city = parts.city;
+            }
+            None => self.street = Some(self.address.trim().to_string()).filter(|s| !s.is_empty()),
+        }
+    }
 
-        let cvs_data = csv::Reader::from_path(csv_entry.path())?;
+    /// Sorts the phones into mobile (`3...`) and landline (`0...`) ones, other numbers (e.g. toll free) being in neither.
+    /// The whatsapp number, almost always a mobile, is added to the mobiles when it isn't among the phones.
+    fn split_phone_types(&mut self) {
+        let mut mobiles = Vec::new();
+        let mut landlines = Vec::new();
+        for phone in &self.phones {
+            match phone.kind {
+                PhoneKind::Mobile => mobiles.push(phone.raw.clone()),
+                PhoneKind::Landline => landlines.push(phone.raw.clone()),
+                PhoneKind::TollFree | PhoneKind::Other => {}
+            }
+        }
 
-        for row in cvs_data.into_deserialize::<BusinessEntry>() {
-            count += 1;
-            entries.insert(row?);
+        // the whatsapp number is taken from a wa.me link, so it usually has the international prefix without the +
+        let whatsapp = self.whatsapp.as_deref()
+            .filter(|w| w.chars().all(|c| c.is_ascii_digit()))
+            .map(|w| w.strip_prefix("39").filter(|_| w.len() > 10).unwrap_or(w));
+        if let Some(whatsapp) = whatsapp.filter(|w| w.starts_with('3')) {
+            if !mobiles.iter().any(|m| phone::normalize(m) == whatsapp) {
+                mobiles.push(whatsapp.to_string());
+            }
         }
+
+        let join = |phones: Vec<String>| Some(phones.join(" | ")).filter(|p| !p.is_empty());
+        self.mobile_phones = join(mobiles);
+        self.landline_phones = join(landlines);
     }
 
-    println!("All rows read. Found = {count}, uniques = {}", entries.len());
+    /// Rewrites the whatsapp number in the chosen format. Numbers that don't look like phone numbers are left as they are.
+    fn format_whatsapp(&mut self, format: WhatsappFormat) {
+        let Some(digits) = &self.whatsapp else { return; };
 
-    let mut entries = Vec::from_iter(entries.into_iter());
-    entries.sort_by_key(|e| (e.name.to_lowercase(), e.address.to_lowercase()));
-    entries.dedup_by(|a, b| a == b);
+        // E.164 numbers have at most 15 digits, italian ones at least 6 without prefix
+        if !(6..=15).contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return;
+        }
 
-    let mut csv_writer = csv::WriterBuilder::new()
-        .flexible(false)
-        .from_path(output)?;
+        // wa.me links need the international prefix, italian numbers without it are at most 10 digits long
+        let link = if digits.len() <= 10 { format!("https://wa.me/39{digits}") } else { format!("https://wa.me/{digits}") };
 
-    for entry in entries {
-        csv_writer.serialize(entry)?;
+        match format {
+            WhatsappFormat::Digits => {}
+            WhatsappFormat::Link => self.whatsapp = Some(link),
+            WhatsappFormat::Both => self.whatsapp_link = Some(link),
+        }
     }
-    csv_writer.flush()?;
 
-    Ok(())
-}
+    /// Trims name and address, collapsing internal whitespace and dropping trailing separators,
+    /// and optionally title-cases the name.
+    fn normalize(&mut self, title_case: bool) {
+        fn clean(s: &str) -> String {
+            s.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim_end_matches([',', ';', ':', '-', ' '])
+                .to_string()
+        }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    
-    // TODO: write file in real time?
-    let output_filename = cli.output_file;
-    let mut output_path = std::path::PathBuf::new();
-    output_path.push(output_filename);
-    output_path.set_extension("csv");
+        self.name = clean(&self.name);
+        self.address = clean(&self.address);
 
-    let (urls, comuni) = match cli.mode {
-        CliMode::Search(ref params) => {
-            (generate_urls_with_search_mode(params, cli.page_limit), vec![])
+        if title_case {
+            self.name = self.name.split(' ')
+                .map(|w| {
+                    let mut chars = w.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
         }
-        CliMode::Filter(ref params) => {
-            generate_urls_with_filter_mode(params, cli.page_limit, cli.debug).await?
+    }
+
+    /// Drops the phones that aren't valid italian numbers, returning whether any is left.
+    fn retain_valid_phones(&mut self) -> bool {
+        self.phones.retain(PhoneNumber::is_valid);
+        !self.phones.is_empty()
+    }
+
+    /// Combines two records of the same business: the empty fields are filled from the other record,
+    /// the phones are joined and the longer address is kept.
+    fn merge(mut self, other: BusinessEntry) -> BusinessEntry {
+        let mut seen = HashSet::new();
+        self.phones = std::mem::take(&mut self.phones)
+            .into_iter()
+            .chain(other.phones)
+            .filter(|p| seen.insert(p.normalized.clone()))
+            .collect();
+
+        if self.name.is_empty() {
+            self.name = other.name;
         }
-        CliMode::Merge(ref params) => {
-            return merge_csvs(params, &output_path);
+        if other.address.len() > self.address.len() {
+            self.address = other.address;
         }
-    };
-    
-    if cli.debug {
-        println!("Url generati: {urls:?}\n");
+
+        let fill = |field: &mut Option<String>, other: Option<String>| {
+            if field.as_deref().is_none_or(str::is_empty) {
+                *field = other;
+            }
+        };
+        fill(&mut self.whatsapp, other.whatsapp);
+        fill(&mut self.website, other.website);
+        fill(&mut self.contact_url, other.contact_url);
+        fill(&mut self.facebook, other.facebook);
+        fill(&mut self.instagram, other.instagram);
+        fill(&mut self.source_url, other.source_url);
+        fill(&mut self.whatsapp_link, other.whatsapp_link);
+        fill(&mut self.street, other.street);
+        fill(&mut self.cap, other.cap);
+        fill(&mut self.city, other.city);
+        self.score = self.score.or(other.score);
+        fill(&mut self.mobile_phones, other.mobile_phones);
+        fill(&mut self.landline_phones, other.landline_phones);
+        fill(&mut self.website_status, other.website_status);
+        fill(&mut self.name_raw, other.name_raw);
+        fill(&mut self.address_raw, other.address_raw);
+        fill(&mut self.phones_raw, other.phones_raw);
+        fill(&mut self.logo_url, other.logo_url);
+        for category in other.categories {
+            if let Err(i) = self.categories.binary_search(&category) {
+                self.categories.insert(i, category);
+            }
+        }
+        self
     }
-    println!("Richieste da effettuare: {}", urls.len());
 
-    let timer_start = std::time::Instant::now();
+    fn has_phone_in(&self, phones: &HashSet<String>) -> bool {
+        !phones.is_empty() && self.phones.iter().any(|p| phones.contains(&p.normalized))
+    }
 
-    // https://stackoverflow.com/questions/51044467/how-can-i-perform-parallel-asynchronous-http-get-requests-with-reqwest/51047786#51047786
-    let client = reqwest::Client::new();
+    /// Number of non-empty optional fields.
+    fn populated_fields(&self) -> usize {
+        [&self.whatsapp, &self.website, &self.contact_url, &self.facebook, &self.instagram]
+            .iter()
+            .filter(|f| f.as_ref().is_some_and(|f| !f.is_empty()))
+            .count()
+    }
 
-    // THIS JUST SENDS THE HTTP REQUESTS
-    let htmls = futures::stream::iter(&urls)
-    .enumerate()
-    .map(|(i, url)| {
-        // if i % (urls.len() / 100) == 0 {
-        //     let percentage = ((i as f32 / urls.len() as f32) * 100.0).round();
-        //     print!("\r{percentage:02}% completato, {i} richieste effetuate");
-        //     std::io::stdout().flush().unwrap();
-        // }
+    /// How useful the entry is as a lead: points for each way of contacting the business.
+    /// Only fields of the dedup identity are counted, so that duplicates get the same score.
+    fn score(&self) -> u32 {
+        let has = |field: &Option<String>| field.as_deref().is_some_and(|f| !f.is_empty());
+
+        // more than three numbers rarely reach anyone new
+        let phones = self.phones.len().min(3) as u32;
+        phones
+            + if has(&self.whatsapp) { 2 } else { 0 }
+            + if has(&self.website) { 2 } else { 0 }
+            + if has(&self.contact_url) { 2 } else { 0 }
+    }
 
-        // TODO: this just counts the requests sent, not the responses received...
-        let percentage = ((i as f32 / urls.len() as f32) * 100.0).round();
-        print!("\r{percentage:>2}% completato, {} richieste effetuate", i+1);
-        std::io::stdout().flush().unwrap();
+    /// The comune part of the address, which follows the CAP (e.g. `Via Roma 1 - 50100 Firenze (FI)`).
+    fn comune(&self) -> String {
+        let mut tokens = self.address.split_whitespace()
+            .skip_while(|t| !(t.len() == 5 && t.chars().all(|c| c.is_ascii_digit())))
+            .skip(1)
+            .take_while(|t| !t.starts_with('('))
+            .peekable();
 
-        let client = client.clone();
-        async move {
-            let res = client.get(url).send().await?;
-            let url = res.url().to_string();
-            Ok((res.text().await?, url))
+        if tokens.peek().is_none() { return String::new(); }
+        tokens.collect::<Vec<_>>().join(" ").to_lowercase()
+    }
+}
+
+/// Case insensitive comparison, without allocating the lowercased strings.
+fn cmp_lowercase(a: &str, b: &str) -> std::cmp::Ordering {
+    // lowercasing unicode chars one at a time is slow, most names are plain ascii
+    if a.is_ascii() && b.is_ascii() {
+        return a.bytes().map(|c| c.to_ascii_lowercase()).cmp(b.bytes().map(|c| c.to_ascii_lowercase()));
+    }
+    a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Orders the entries on the sort key, breaking ties on the fields of the dedup identity: duplicates compare equal.
+fn cmp_entries_identity(a: &BusinessEntry, b: &BusinessEntry, sort_by: SortKey) -> std::cmp::Ordering {
+    let key = match sort_by {
+        SortKey::Name | SortKey::None => cmp_lowercase(&a.name, &b.name).then_with(|| cmp_lowercase(&a.address, &b.address)),
+        SortKey::Address => cmp_lowercase(&a.address, &b.address).then_with(|| cmp_lowercase(&a.name, &b.name)),
+        SortKey::Comune => a.comune().cmp(&b.comune()).then_with(|| cmp_lowercase(&a.name, &b.name)),
+        SortKey::Score => b.score().cmp(&a.score()).then_with(|| cmp_lowercase(&a.name, &b.name)),
+    };
+    // the phones come next, so that businesses sharing name and address (e.g. branches) keep the same order on every run,
+    // then the first fields of the record, which are the ones of the identity
+    key.then_with(|| a.phones.cmp(&b.phones))
+        .then_with(|| a.csv_record()[..6].cmp(&b.csv_record()[..6]))
+}
+
+/// Orders the entries on the sort key, breaking ties on all the fields.
+fn cmp_entries(a: &BusinessEntry, b: &BusinessEntry, sort_by: SortKey) -> std::cmp::Ordering {
+    cmp_entries_identity(a, b, sort_by).then_with(|| a.csv_record().cmp(&b.csv_record()))
+}
+
+fn sort_entries(entries: &mut Vec<BusinessEntry>, sort_by: SortKey, dedup: bool) {
+    // ties on the sort key are broken on all the fields, so that the output doesn't depend on the set iteration order
+    match sort_by {
+        // the lowercased keys are computed once, comparing them is much faster than lowercasing at each comparison
+        // (see `bench_sort_entries`). Only the ties, e.g. branches sharing name and address, go through the whole comparison
+        SortKey::Name | SortKey::Address => {
+            let keys = entries.iter()
+                .map(|e| match sort_by {
+                    SortKey::Name => (e.name.to_lowercase(), e.address.to_lowercase()),
+                    _ => (e.address.to_lowercase(), e.name.to_lowercase()),
+                })
+                .collect::<Vec<_>>();
+            let mut order = (0..entries.len()).collect::<Vec<_>>();
+            order.sort_unstable_by(|&a, &b| keys[a].cmp(&keys[b]).then_with(|| cmp_entries(&entries[a], &entries[b], sort_by)));
+
+            let mut unsorted = std::mem::take(entries).into_iter().map(Some).collect::<Vec<_>>();
+            *entries = order.into_iter().map(|i| unsorted[i].take().expect("each index is in the order once")).collect();
         }
-    })
-    .buffer_unordered(DEFAULT_REQUESTS_BATCH);
+        SortKey::Score => entries.sort_unstable_by(|a, b| cmp_entries(a, b, sort_by)),
+        SortKey::Comune => entries.sort_by_cached_key(|e| (e.comune(), e.name.to_lowercase(), e.phones.clone(), e.csv_record().map(Cow::into_owned))),
+        // the entries come from a set, so they are already unique
+        SortKey::None => return,
+    }
+
+    if dedup {
+        entries.dedup_by(|a, b| a == b);
+    }
+}
 
-    let entries_selector = scraper::Selector::parse(".search-itm")?;
-    let business_name_selector = scraper::Selector::parse(".search-itm__rag")?;
-    let address_selector = scraper::Selector::parse(".search-itm__adr")?;
-    let phone_selector = scraper::Selector::parse(".search-itm__phone")?;
-    let whatsapp_selector = scraper::Selector::parse("a[data-pag=\"whatsapp\"]")?;
-    let contact_selector = scraper::Selector::parse("#contattaci_btn")?;
-    let website_selector = scraper::Selector::parse(".bttn.bttn--white.bttn--blank.shinystat_ssxl")?;
+/// The strategy picks which one of two duplicates to keep, and the kept one gets the fields it lacks from the other.
+fn merge_duplicates(old: BusinessEntry, new: BusinessEntry, strategy: MergeStrategy) -> BusinessEntry {
+    let keep_new = match strategy {
+        MergeStrategy::First => false,
+        MergeStrategy::Last => true,
+        MergeStrategy::MostComplete => new.populated_fields() > old.populated_fields(),
+    };
 
-    let (sender, receiver)  = std::sync::mpsc::channel();
+    let (kept, other) = if keep_new { (new, old) } else { (old, new) };
+    kept.merge(other)
+}
 
-    // scrape data from html text
-    // THIS PARSES THE HTTP RESPONSES TEXT
-    htmls.for_each(|response: Result<_, reqwest::Error>| async {
-    match response {
-        Ok((html, url)) => {
-            // TODO: would be a great idea to factor out into a function
-            let document = scraper::Html::parse_document(&html);
-            
-            let mut elements = document
-                .select(&entries_selector)
-                .peekable();
-
-            if elements.peek().is_none() {
-                match &cli.mode {
-                    CliMode::Search(_) => {
-                        // we don't care about errors here
-                    }
-                    CliMode::Filter(_) => {
-                        let comune = url.split('/').rev().nth(2).unwrap_or_default();
-                        sender.clone().send(Err(comune.to_string())).unwrap();
-                    }
+/// Collapses the listings of a page shown twice, e.g. both as sponsored and as organic result:
+/// those with the same name sharing a phone become the more complete of the two. Returns how many were collapsed.
+fn collapse_page_duplicates(entries: &mut Vec<BusinessEntry>) -> usize {
+    let before = entries.len();
+    let mut collapsed: Vec<BusinessEntry> = Vec::with_capacity(before);
+
+    for entry in entries.drain(..) {
+        let phones = entry.phones.iter().map(|p| p.normalized.clone()).collect::<HashSet<_>>();
+        let duplicate = collapsed.iter()
+            .position(|e| e.name.trim().eq_ignore_ascii_case(entry.name.trim()) && e.has_phone_in(&phones));
+
+        match duplicate {
+            Some(i) => {
+                let old = collapsed.remove(i);
+                collapsed.insert(i, merge_duplicates(old, entry, MergeStrategy::MostComplete));
+            }
+            None => collapsed.push(entry),
+        }
+    }
 
-                    _ => {}
-                }
+    *entries = collapsed;
+    before - entries.len()
+}
 
-                return;
+/// The collected entries: a set deduplicating them as they come, or, with `--no-dedup`, every single row.
+/// With `--approx-dedup`, only a Bloom filter of the name and phones of the seen entries is kept for deduplication.
+enum Entries {
+    Unique(HashSet<BusinessEntry>),
+    Approx(BloomFilter, Vec<BusinessEntry>),
+    All(Vec<BusinessEntry>),
+}
+
+impl Entries {
+    fn new(cli: &Cli) -> Self {
+        if cli.no_dedup {
+            Entries::All(Vec::new())
+        } else if cli.approx_dedup {
+            Entries::Approx(BloomFilter::new(APPROX_DEDUP_CAPACITY, APPROX_DEDUP_FP_RATE), Vec::new())
+        } else {
+            Entries::Unique(HashSet::new())
+        }
+    }
+
+    /// Inserts the entry; when a duplicate is already present, the strategy picks which one to keep,
+    /// and the kept one gets the fields it lacks from the other. Returns whether the entry is new, rather than a duplicate.
+    fn insert(&mut self, entry: BusinessEntry, strategy: MergeStrategy) -> bool {
+        let entries = match self {
+            Entries::Unique(entries) => entries,
+            Entries::All(entries) => {
+                entries.push(entry);
+                return true;
+            }
+            // the first one seen is always kept, as the older entry isn't around anymore to be merged with
+            Entries::Approx(seen, entries) => {
+                let new = seen.insert(&(&entry.name, &entry.phones));
+                if new {
+                    entries.push(entry);
+                }
+                return new;
             }
+        };
 
-            for element in elements {
-                let name = extract_text_from_html(&element, &business_name_selector);
-                // https://stackoverflow.com/questions/71864137/whats-the-ideal-way-to-trim-extra-spaces-from-a-string
-                let address = extract_text_from_html(&element, &address_selector).split_whitespace().collect::<Vec<_>>().join(" ");
-                let phones = extract_text_from_html(&element, &phone_selector);
+        let Some(old) = entries.take(&entry) else {
+            entries.insert(entry);
+            return true;
+        };
 
-                let phones = phones.split_whitespace()
-                    .collect::<Vec<_>>()
-                    .windows(2)
-                    .map(|w| format!("{}-{}", w[0], w[1]))
-                    .collect::<Vec<_>>()
-                    .join(" | ");
+        entries.insert(merge_duplicates(old, entry, strategy));
+        false
+    }
 
-                let whatsapp = element.select(&whatsapp_selector)
-                    .next()
-                    .map(|n| n.attr("href").map(|s| s.to_string()).unwrap_or_default())
-                    .map(|s| s.chars()
-                        .skip_while(|c| !c.is_numeric())
-                        .take_while(|c| c.is_numeric())
-                        .collect()
-                    );
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-                let contact_url = element.select(&contact_selector)
-                    .next()
-                    .map(|n| n.attr("href").map(|s| s.to_string()).unwrap_or_default());
+    fn len(&self) -> usize {
+        match self {
+            Entries::Unique(entries) => entries.len(),
+            Entries::Approx(_, entries) | Entries::All(entries) => entries.len(),
+        }
+    }
 
-                let website = element.select(&website_selector)
-                    .next()
-                    .map(|n| n.attr("href").map(|url| url.to_string()).unwrap_or_default());
+    /// Number of distinct phone numbers across all the entries: a business can have more phones, and a phone can be shared.
+    fn unique_phones(&self) -> usize {
+        let entries: Box<dyn Iterator<Item = &BusinessEntry>> = match self {
+            Entries::Unique(entries) => Box::new(entries.iter()),
+            Entries::Approx(_, entries) | Entries::All(entries) => Box::new(entries.iter()),
+        };
+        entries.flat_map(|e| e.phones.iter().map(|p| p.raw.as_str())).collect::<HashSet<_>>().len()
+    }
 
-                let entry = BusinessEntry { name, phones, address, whatsapp, contact_url, website };
-                sender.clone().send(Ok(entry)).unwrap();
-            }
+    fn into_vec(self) -> Vec<BusinessEntry> {
+        match self {
+            Entries::Unique(entries) => entries.into_iter().collect(),
+            Entries::Approx(_, entries) | Entries::All(entries) => entries,
         }
-        Err(e) => eprintln!("Errore non gestito per: {e}"),
     }
-    }).await;
+}
 
-    // the upper level sender is not used, it should be dropped so that the receiver knows when there are no more senders
-    drop(sender);
+/// Every row seen for each dedup key, to report the duplicates without having to remove them (`--report-duplicates`).
+struct Duplicates {
+    /// the key is the one of `--approx-dedup`, name and phones, rather than the whole dedup identity
+    approx: bool,
+    /// the rows of each key, as `source` followed by the record
+    rows: HashMap<Vec<String>, Vec<Vec<String>>>,
+}
 
-    let mut entries = HashSet::new();
-    let mut errors = HashMap::new();
+impl Duplicates {
+    const CSV_HEADER: [&'static str; 2] = ["cluster", "source"];
 
-    // receive data from tasks
-    while let Ok(res) = receiver.recv() {
-        match res {
-            Ok(entry) => {
-                if !entry.name.is_empty() && !entry.phones.is_empty() {
-                    entries.insert(entry);
-                }
-            }
-            Err(e) => {
-                // let count = errors.get(&e).map_or(1, |n| n+1);
-                // errors.insert(e, count);
+    fn new(cli: &Cli) -> Option<Self> {
+        cli.report_duplicates.then(|| Self { approx: cli.approx_dedup, rows: HashMap::new() })
+    }
+
+    /// Records a row, `source` telling where it comes from (its category, or its file when merging).
+    fn record(&mut self, entry: &BusinessEntry, source: &str) {
+        let record = entry.csv_record();
+        let key = if self.approx {
+            vec![entry.name.clone(), phone::pack(&entry.phones)]
+        } else {
+            // the first fields of the record are the ones of the identity
+            record[..6].iter().map(|f| f.to_string()).collect()
+        };
+
+        let row = std::iter::once(Cow::Borrowed(source)).chain(record).map(Cow::into_owned).collect();
+        self.rows.entry(key).or_default().push(row);
+    }
 
-                errors.entry(e)
-                    .and_modify(|n| { *n += 1; })
-                    .or_insert(1);
+    fn extend(&mut self, other: Duplicates) {
+        for (key, rows) in other.rows {
+            self.rows.entry(key).or_default().extend(rows);
+        }
+    }
+
+    /// Writes the keys seen more than once, each row with the number of its cluster. Returns the number of clusters.
+    fn write(self, output_path: &std::path::Path) -> Result<usize, ScraperError> {
+        let mut clusters = self.rows.into_iter()
+            .filter(|(_, rows)| rows.len() > 1)
+            .collect::<Vec<_>>();
+        clusters.sort();
+
+        let mut csv_writer = csv::Writer::from_path(output_path.with_extension("duplicates.csv"))?;
+        csv_writer.write_record(Self::CSV_HEADER.iter().chain(&BusinessEntry::CSV_HEADER))?;
+        for (cluster, (_, rows)) in clusters.iter().enumerate() {
+            let cluster = (cluster + 1).to_string();
+            for row in rows {
+                csv_writer.write_record(std::iter::once(&cluster).chain(row))?;
             }
         }
+        csv_writer.flush()?;
+
+        Ok(clusters.len())
     }
+}
+
+/// Website links pointing back to PagineGialle are redirect wrappers around the real website.
+fn is_wrapper_url(url: &str) -> bool {
+    url.starts_with('/') || url.contains("paginegialle.it")
+}
 
-    let not_found = errors.iter()
-        .filter(|(_, &val)| val == cli.page_limit)
-        .map(|(key, _)| key)
+/// Follows the redirect wrappers with HEAD requests, replacing them with the final url.
+/// Returns the number of resolved websites.
+async fn resolve_websites(client: &reqwest::Client, entries: &mut [BusinessEntry], concurrency: usize) -> usize {
+    let wrapped = entries.iter()
+        .enumerate()
+        .filter_map(|(i, e)| e.website.as_ref().filter(|w| is_wrapper_url(w)).map(|w| (i, w.clone())))
         .collect::<Vec<_>>();
-    
-    if !not_found.is_empty() {
-        eprint!("\r");
 
-        match cli.mode {
-            CliMode::Search(_)  => {}
-            CliMode::Filter(_) => {
-                if not_found.len() == comuni.len() {
-                    eprint!("Nessuna provincia ha ottenuto alcun risultato. Hai scelto una categoria di attivita' valida?");
-                    return Ok(());
-                } else {
-                    eprintln!("Nessun risultato per le seguenti provincie: {not_found:?}");
-                }
+    let resolved = futures::stream::iter(wrapped)
+        .map(|(i, url)| {
+            let client = client.clone();
+            async move {
+                let url = if url.starts_with('/') { format!("{PAGINEGIALLE_URL}{url}") } else { url };
+                let res = client.head(url).send().await;
+                (i, res.map(|r| r.url().to_string()))
             }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
 
-            _ => {}
+    let mut count = 0;
+    for (i, res) in resolved {
+        match res {
+            Ok(url) if !is_wrapper_url(&url) => {
+                entries[i].website = Some(url);
+                count += 1;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Impossibile risolvere il sito web: {e}"),
         }
     }
 
-    let time_took = std::time::Instant::now() - timer_start;
-    let minutes_took = time_took.as_secs() as f32 / 60.0;
-    println!("\nTempo impiegato: {time_took:?} ({minutes_took} minuti)");
-    println!("Scraping finito, salvataggio su file CSV...");
+    count
+}
+
+/// Status code the website answers a HEAD request with, following redirects, or `None` when it doesn't answer at all.
+/// Network and server errors are retried like the bootstrap requests.
+async fn website_status(client: &reqwest::Client, url: &str) -> Option<u16> {
+    let mut delay = BOOTSTRAP_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let status = match client.head(url).timeout(WEBSITE_CHECK_TIMEOUT).send().await {
+            // some servers don't implement HEAD
+            Ok(res) if matches!(res.status().as_u16(), 405 | 501) => {
+                client.get(url).timeout(WEBSITE_CHECK_TIMEOUT).send().await.ok().map(|r| r.status())
+            }
+            res => res.ok().map(|r| r.status()),
+        };
+
+        if status.is_some_and(|s| !s.is_server_error()) || attempt >= BOOTSTRAP_RETRIES {
+            return status.map(|s| s.as_u16());
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Checks whether the websites are reachable, filling their status. A website is dead when it doesn't answer,
+/// is not found (404, 410) or fails with a server error; with `--drop-dead-websites` the dead websites are removed from their entries.
+/// Returns the number of websites checked and of dead ones.
+async fn validate_websites(cli: &Cli, client: &reqwest::Client, entries: &mut [BusinessEntry]) -> (usize, usize) {
+    let urls = entries.iter().filter_map(|e| e.website.clone()).collect::<HashSet<_>>();
+
+    // each website is checked once, even when listed by many entries
+    let checks = futures::stream::iter(urls).map(|url| {
+        let absolute = if url.starts_with('/') { format!("{PAGINEGIALLE_URL}{url}") } else { url.clone() };
+        (limits::HostGroup::of(&absolute), async move {
+            let status = website_status(client, &absolute).await;
+            (url, status)
+        })
+    });
+    let statuses = limits::buffer_by_host(checks, cli.paginegialle_concurrency, cli.external_concurrency)
+        .collect::<HashMap<_, _>>()
+        .await;
+
+    let is_dead = |status: &Option<u16>| status.is_none_or(|s| matches!(s, 404 | 410) || s >= 500);
+    for entry in entries.iter_mut() {
+        let Some(status) = entry.website.as_ref().and_then(|w| statuses.get(w)) else { continue; };
+
+        entry.website_status = Some(match status {
+            Some(code) if !is_dead(status) => code.to_string(),
+            _ => "dead".to_string(),
+        });
+        if cli.drop_dead_websites && is_dead(status) {
+            entry.website = None;
+        }
+    }
+
+    (statuses.len(), statuses.values().filter(|s| is_dead(s)).count())
+}
+
+/// Settings of the write layer, shared by scraping and merging.
+struct OutputOptions {
+    format: OutputFormat,
+    bom: bool,
+    /// gzip the output files, see [`OutputOptions::file_path`]
+    compress: bool,
+    encoding: &'static encoding_rs::Encoding,
+    /// indexes into [`BusinessEntry::CSV_HEADER`] of the columns to write, in order
+    columns: Vec<usize>,
+    normalize: bool,
+    title_case_names: bool,
+    split_address: bool,
+    split_phone_types: bool,
+    score: bool,
+    whatsapp_format: WhatsappFormat,
+    /// normalized phones of the entries to leave out of the output
+    excluded_phones: HashSet<String>,
+    /// only the valid phones are kept, and the entries without any are left out of the output
+    strict_phones: bool,
+}
+
+impl OutputOptions {
+    fn from_cli(cli: &Cli) -> Result<Self, ScraperError> {
+        // vCard and JSON readers expect UTF-8
+        if cli.encoding != OutputEncoding::Utf8 && cli.format != OutputFormat::Csv {
+            return Err(ScraperError::Parse("--encoding e' supportato solo per l'output CSV".to_string()));
+        }
+
+        // the raw columns are only worth their space in JSON, the other formats get them only if asked with --fields
+        let raw_columns = cli.preserve_raw && matches!(cli.format, OutputFormat::Json | OutputFormat::JsonByCategory);
+        // the columns filled in by an option are only written with it, rather than left empty in every row
+        let enabled = |column: &str| match column {
+            "name_raw" | "address_raw" | "phones_raw" => raw_columns,
+            "source_url" => cli.include_source_url,
+            "whatsapp_link" => cli.whatsapp_format == WhatsappFormat::Both,
+            "street" | "cap" | "city" => cli.split_address,
+            "score" => cli.score,
+            "mobile_phones" | "landline_phones" => cli.split_phone_types,
+            "website_status" => cli.validate_websites,
+            _ => true,
+        };
+        let columns = match &cli.fields {
+            None => (0..BusinessEntry::CSV_HEADER.len())
+                .filter(|&i| enabled(BusinessEntry::CSV_HEADER[i]))
+                .collect(),
+            Some(fields) => fields.iter()
+                .map(|f| BusinessEntry::CSV_HEADER.iter()
+                    .position(|h| h == f)
+                    .ok_or_else(|| ScraperError::Parse(format!("Campo `{f}` sconosciuto. Campi validi: {}", BusinessEntry::CSV_HEADER.join(", "))))
+                )
+                .collect::<Result<_, _>>()?,
+        };
+
+        // one phone per line, empty lines and `#` comments are skipped
+        let excluded_phones = match &cli.exclude_phones_file {
+            None => HashSet::new(),
+            Some(path) => std::fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(phone::normalize)
+                .collect(),
+        };
+
+        Ok(Self {
+            format: cli.format,
+            bom: cli.bom,
+            compress: cli.compress_output,
+            encoding: cli.encoding.encoding(),
+            columns,
+            excluded_phones,
+            strict_phones: cli.strict_phones,
+            normalize: cli.normalize,
+            title_case_names: cli.title_case_names,
+            split_address: cli.split_address,
+            split_phone_types: cli.split_phone_types,
+            score: cli.score,
+            whatsapp_format: cli.whatsapp_format,
+        })
+    }
+
+    /// The file actually written for an output path: the path itself, or the path with `.gz` added when compressing.
+    fn file_path(&self, path: &std::path::Path) -> std::path::PathBuf {
+        if !self.compress {
+            return path.to_path_buf();
+        }
+        let mut path = path.as_os_str().to_owned();
+        path.push(".gz");
+        path.into()
+    }
+
+    /// Applies the formatting options to an entry about to be written.
+    fn apply(&self, entry: &mut BusinessEntry) {
+        if self.normalize {
+            entry.normalize(self.title_case_names);
+        }
+        if self.split_address {
+            entry.split_address();
+        }
+        // before the whatsapp number is turned into a link
+        if self.split_phone_types {
+            entry.split_phone_types();
+        }
+        entry.format_whatsapp(self.whatsapp_format);
+        if self.score {
+            entry.score = Some(entry.score());
+        }
+    }
+}
+
+/// Drops the entries having any of the excluded phones, returning how many were dropped.
+fn exclude_phones(entries: &mut Vec<BusinessEntry>, excluded: &HashSet<String>) -> usize {
+    if excluded.is_empty() {
+        return 0;
+    }
+
+    let before = entries.len();
+    entries.retain(|e| !e.has_phone_in(excluded));
+    before - entries.len()
+}
 
-    let mut entries = entries.into_iter().collect::<Vec<_>>();
-    entries.sort_by_key(|e| (e.name.to_lowercase(), e.address.to_lowercase()));
-    entries.dedup_by(|a, b| a == b);
+/// Keeps only the valid phones of the entries, dropping the entries left without any. Returns how many were dropped.
+fn strict_phones(entries: &mut Vec<BusinessEntry>) -> usize {
+    let before = entries.len();
+    entries.retain_mut(BusinessEntry::retain_valid_phones);
+    before - entries.len()
+}
+
+/// Writes the entries in the chosen output format.
+fn write_entries(path: &std::path::Path, entries: Vec<BusinessEntry>, options: &OutputOptions) -> Result<(), ScraperError> {
+    let mut writer = EntryWriter::create(path, options)?;
+    for entry in entries {
+        writer.write(entry, options)?;
+    }
+    writer.finish()
+}
+
+/// An output file, gzipped with `--compress-output`. [`OutputFile::finish`] has to be called once it's written.
+enum OutputFile {
+    Plain(std::fs::File),
+    Gzip(gzip::GzipWriter<std::fs::File>),
+}
+
+impl OutputFile {
+    fn create(path: &std::path::Path, options: &OutputOptions) -> Result<Self, ScraperError> {
+        let file = std::fs::File::create(options.file_path(path))?;
+        Ok(if options.compress { OutputFile::Gzip(gzip::GzipWriter::new(file)?) } else { OutputFile::Plain(file) })
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        use std::io::Write;
+
+        match self {
+            OutputFile::Plain(mut file) => file.flush(),
+            OutputFile::Gzip(gzip) => gzip.finish().map(drop),
+        }
+    }
+}
+
+impl std::io::Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(gzip) => gzip.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(gzip) => gzip.flush(),
+        }
+    }
+}
 
+/// An output file being written one entry at a time.
+enum EntryWriter {
     // TODO: add separator option
-    let mut csv_writer = csv::WriterBuilder::new()
-        .flexible(false)
-        .from_path(output_path)?;
+    Csv(Box<csv::Writer<encoding::TranscodingWriter<OutputFile>>>),
+    Vcard(std::io::BufWriter<OutputFile>),
+    /// an array of objects, `first` being set until the first one is written
+    Json { out: std::io::BufWriter<OutputFile>, first: bool },
+    /// the serialized entries of each category, written as an object of arrays once all are known
+    JsonByCategory { file: OutputFile, groups: std::collections::BTreeMap<String, Vec<String>> },
+}
+
+impl EntryWriter {
+    fn create(path: &std::path::Path, options: &OutputOptions) -> Result<Self, ScraperError> {
+        match options.format {
+            OutputFormat::Csv => {
+                let mut file = OutputFile::create(path, options)?;
+                // Excel needs the BOM to read the file as UTF-8 rather than in the local codepage
+                if options.bom {
+                    use std::io::Write;
+                    file.write_all("\u{feff}".as_bytes())?;
+                }
+
+                let mut csv_writer = csv::WriterBuilder::new()
+                    .flexible(false)
+                    .from_writer(encoding::TranscodingWriter::new(file, options.encoding));
+
+                // columns are written explicitly rather than serialized, so that their order doesn't depend on the struct declaration
+                csv_writer.write_record(options.columns.iter().map(|&i| BusinessEntry::CSV_HEADER[i]))?;
+                Ok(EntryWriter::Csv(Box::new(csv_writer)))
+            }
+            OutputFormat::Vcard => Ok(EntryWriter::Vcard(std::io::BufWriter::new(OutputFile::create(path, options)?))),
+            OutputFormat::Json => {
+                use std::io::Write;
+
+                let mut out = std::io::BufWriter::new(OutputFile::create(path, options)?);
+                out.write_all(b"[")?;
+                Ok(EntryWriter::Json { out, first: true })
+            }
+            OutputFormat::JsonByCategory => Ok(EntryWriter::JsonByCategory { file: OutputFile::create(path, options)?, groups: Default::default() }),
+        }
+    }
+
+    fn write(&mut self, mut entry: BusinessEntry, options: &OutputOptions) -> Result<(), ScraperError> {
+        use std::io::Write;
 
+        options.apply(&mut entry);
+
+        let record = entry.csv_record();
+        match self {
+            EntryWriter::Csv(csv_writer) => {
+                csv_writer.write_record(options.columns.iter().map(|&i| record[i].as_bytes()))?;
+            }
+            EntryWriter::Vcard(vcf) => {
+                vcf.write_all(vcard(&entry).as_bytes())?;
+            }
+            EntryWriter::Json { out, first } => {
+                if !*first {
+                    out.write_all(b",")?;
+                }
+                *first = false;
+                out.write_all(json::to_string(&JsonRecord { columns: &options.columns, record })?.as_bytes())?;
+            }
+            EntryWriter::JsonByCategory { groups, .. } => {
+                let json = json::to_string(&JsonRecord { columns: &options.columns, record })?;
+                // in each of its categories
+                if entry.categories.is_empty() {
+                    groups.entry(String::new()).or_default().push(json);
+                } else {
+                    for category in &entry.categories {
+                        groups.entry(category.clone()).or_default().push(json.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), ScraperError> {
+        use std::io::Write;
+
+        match self {
+            EntryWriter::Csv(csv_writer) => csv_writer.into_inner().map_err(|e| e.into_error())?.into_inner().finish()?,
+            EntryWriter::Vcard(vcf) => vcf.into_inner().map_err(|e| e.into_error())?.finish()?,
+            EntryWriter::Json { mut out, .. } => {
+                out.write_all(b"]")?;
+                out.into_inner().map_err(|e| e.into_error())?.finish()?;
+            }
+            EntryWriter::JsonByCategory { mut file, groups } => {
+                let categories = groups.iter()
+                    .map(|(category, entries)| Ok(format!("{}:[{}]", json::to_string(category)?, entries.join(","))))
+                    .collect::<Result<Vec<_>, ScraperError>>()?;
+                file.write_all(format!("{{{}}}", categories.join(",")).as_bytes())?;
+                file.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of an address, as split around its CAP.
+struct AddressParts {
+    street: Option<String>,
+    cap: String,
+    city: Option<String>,
+}
+
+impl AddressParts {
+    /// Splits an address like `Via Roma 1 - 50100 Firenze (FI)`, or returns `None` when it has no CAP.
+    fn parse(address: &str) -> Option<Self> {
+        let tokens = address.split_whitespace().collect::<Vec<_>>();
+        let cap_idx = tokens.iter().position(|t| t.len() == 5 && t.chars().all(|c| c.is_ascii_digit()))?;
+
+        let street = tokens[..cap_idx].join(" ");
+        let city = tokens[cap_idx + 1..].iter()
+            .take_while(|t| !t.starts_with('('))
+            .copied()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        Some(Self {
+            street: non_empty(street.trim_end_matches(|c: char| c == '-' || c == ',' || c.is_whitespace())),
+            cap: tokens[cap_idx].to_string(),
+            city: non_empty(&city),
+        })
+    }
+}
+
+/// The address of a JSON record: the raw one, with its parts when it can be split.
+#[derive(serde::Serialize)]
+struct JsonAddress<'a> {
+    raw: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    street: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    city: Option<String>,
+}
+
+impl<'a> JsonAddress<'a> {
+    fn new(raw: &'a str) -> Self {
+        match AddressParts::parse(raw) {
+            Some(parts) => Self { raw, street: parts.street, cap: Some(parts.cap), city: parts.city },
+            None => Self { raw, street: None, cap: None, city: None },
+        }
+    }
+}
+
+/// The chosen columns of an entry as a JSON object, empty fields being null.
+/// The address is an object with the raw address and, when it can be split, its street, cap and city.
+struct JsonRecord<'a> {
+    columns: &'a [usize],
+    record: [Cow<'a, str>; BusinessEntry::CSV_HEADER.len()],
+}
+
+impl serde::Serialize for JsonRecord<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for &i in self.columns {
+            let value = Some(&*self.record[i]).filter(|v| !v.is_empty());
+            match BusinessEntry::CSV_HEADER[i] {
+                "address" => map.serialize_entry("address", &value.map(JsonAddress::new))?,
+                // an array of the numbers, with their digits and type
+                "phones" => map.serialize_entry("phones", &phone::unpack(value.unwrap_or_default()))?,
+                "score" => map.serialize_entry("score", &value.and_then(|v| v.parse::<u32>().ok()))?,
+                "categories" => map.serialize_entry("categories", &value.unwrap_or_default().split(CATEGORIES_SEPARATOR).filter(|c| !c.is_empty()).collect::<Vec<_>>())?,
+                column => map.serialize_entry(column, &value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// Escapes a vCard property value.
+fn vcard_escape(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Appends a vCard content line, folded every 75 bytes as the format requires.
+fn push_vcard_line(card: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            card.push_str("\r\n ");
+            // the leading space of the continuation counts
+            len = 1;
+        }
+        card.push(c);
+        len += c.len_utf8();
+    }
+    card.push_str("\r\n");
+}
+
+/// The vCard 3.0 of the entry, skipping the empty fields. The businesses are imported as organizations.
+fn vcard(entry: &BusinessEntry) -> String {
+    let name = vcard_escape(&entry.name);
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:3.0".to_string(),
+        format!("FN:{name}"),
+        format!("N:{name};;;;"),
+        format!("ORG:{name}"),
+    ];
+    lines.extend(entry.phones.iter().map(|p| format!("TEL;TYPE=WORK,VOICE:{}", vcard_escape(&p.raw))));
+    if let Some(website) = entry.website.as_deref().filter(|w| !w.is_empty()) {
+        lines.push(format!("URL:{}", vcard_escape(website)));
+    }
+
+    // the split address goes into its components, otherwise the whole address is the street
+    let street = entry.street.as_deref().unwrap_or(&entry.address);
+    if !street.is_empty() || entry.cap.is_some() || entry.city.is_some() {
+        let part = |p: Option<&str>| vcard_escape(p.unwrap_or_default());
+        lines.push(format!("ADR;TYPE=WORK:;;{};{};;{};Italia", vcard_escape(street), part(entry.city.as_deref()), part(entry.cap.as_deref())));
+    }
+    lines.push("END:VCARD".to_string());
+
+    let mut card = String::new();
+    for line in lines {
+        push_vcard_line(&mut card, &line);
+    }
+    card
+}
+
+/// Groups the entries by the split key, each group going to `<output>_<key>.csv`.
+/// Entries without a key are kept in the main output file, entries of many categories go to the file of each one.
+fn split_entries(entries: Vec<BusinessEntry>, split_by: SplitKey, output: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<BusinessEntry>)> {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let path = |key: &str| if key.is_empty() {
+        output.to_path_buf()
+    } else {
+        output.with_file_name(format!("{stem}_{}.{}", sanitize_comune_str(key), output.extension().unwrap_or_default().to_string_lossy()))
+    };
+
+    // btreemap keeps the files order stable
+    let mut groups = std::collections::BTreeMap::<_, Vec<_>>::new();
     for entry in entries {
-        csv_writer.serialize(entry)?;
+        match split_by {
+            SplitKey::Category if entry.categories.len() > 1 => {
+                for category in &entry.categories {
+                    groups.entry(path(category)).or_default().push(entry.clone());
+                }
+            }
+            SplitKey::Category => {
+                let category = entry.categories.first().cloned().unwrap_or_default();
+                groups.entry(path(&category)).or_default().push(entry);
+            }
+            SplitKey::Comune => {
+                groups.entry(path(&entry.comune())).or_default().push(entry);
+            }
+        }
     }
-    csv_writer.flush()?;
-    
+
+    groups.into_iter().collect()
+}
+
+/// Reads an output CSV, decompressing it when it's a `.gz` one.
+fn read_csv(path: &std::path::Path) -> Result<Vec<BusinessEntry>, ScraperError> {
+    read_csv_encoded(path, encoding_rs::UTF_8)
+}
+
+/// Reads a CSV output written with `--encoding`. A BOM takes precedence over the given encoding.
+fn read_csv_encoded(path: &std::path::Path, encoding: &'static encoding_rs::Encoding) -> Result<Vec<BusinessEntry>, ScraperError> {
+    let mut csv_data = std::fs::read(path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        csv_data = gzip::decompress(&csv_data)?;
+    }
+    let (csv_text, _, _) = encoding.decode(&csv_data);
+    let rows = csv::Reader::from_reader(csv_text.as_bytes()).into_deserialize::<BusinessEntry>().collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+#[derive(serde::Serialize)]
+struct ChangedEntry {
+    old: BusinessEntry,
+    new: BusinessEntry,
+}
+
+#[derive(serde::Serialize, Default)]
+struct CsvDiff {
+    added: Vec<BusinessEntry>,
+    removed: Vec<BusinessEntry>,
+    changed: Vec<ChangedEntry>,
+}
+
+/// Compares two outputs, matching rows by name and phones.
+fn diff_csvs(params: &DiffMode) -> Result<(), ScraperError> {
+    let diff = CsvDiff::new(read_csv(std::path::Path::new(&params.old_path))?, read_csv(std::path::Path::new(&params.new_path))?);
+
+    if params.json {
+        println!("{}", json::to_string(&diff)?);
+        return Ok(());
+    }
+
+    println!("Aggiunte = {}, rimosse = {}, modificate = {}", diff.added.len(), diff.removed.len(), diff.changed.len());
+
+    if params.by_category {
+        for (category, [added, removed, changed]) in diff.by_category() {
+            let category = if category.is_empty() { "(sconosciuta)" } else { category };
+            println!("  {category}: aggiunte = {added}, rimosse = {removed}, modificate = {changed}");
+        }
+    }
+
+    Ok(())
+}
+
+impl CsvDiff {
+    fn new(old_rows: Vec<BusinessEntry>, new_rows: Vec<BusinessEntry>) -> Self {
+        let key = |e: &BusinessEntry| (e.name.clone(), e.phones.clone());
+
+        // distinct rows can share the key (e.g. two branches with the same switchboard), so each key keeps all its rows
+        let mut old = HashMap::<_, Vec<BusinessEntry>>::new();
+        for e in old_rows {
+            old.entry(key(&e)).or_default().push(e);
+        }
+
+        let mut diff = CsvDiff::default();
+        for new in new_rows {
+            let Some(candidates) = old.get_mut(&key(&new)).filter(|c| !c.is_empty()) else {
+                diff.added.push(new);
+                continue;
+            };
+            // an unchanged row is matched first, so that it isn't reported as changed against another row of its key
+            match candidates.iter().position(|old| old.csv_record() == new.csv_record()) {
+                Some(i) => { candidates.remove(i); }
+                None => diff.changed.push(ChangedEntry { old: candidates.remove(0), new }),
+            }
+        }
+        diff.removed = old.into_values().flatten().collect();
+        diff
+    }
+
+    /// [added, removed, changed] for each category, a business of many categories counting in each one.
+    fn by_category(&self) -> std::collections::BTreeMap<&str, [usize; 3]> {
+        let rows = self.added.iter().map(|e| (e, 0))
+            .chain(self.removed.iter().map(|e| (e, 1)))
+            .chain(self.changed.iter().map(|c| (&c.new, 2)));
+
+        let mut breakdown = std::collections::BTreeMap::<_, [usize; 3]>::new();
+        for (entry, column) in rows {
+            // the rows without a category are counted under an empty one
+            let categories = match entry.categories.as_slice() {
+                [] => vec![""],
+                categories => categories.iter().map(String::as_str).collect(),
+            };
+            for category in categories {
+                breakdown.entry(category).or_default()[column] += 1;
+            }
+        }
+        breakdown
+    }
+}
+
+fn merge_csvs(cli: &Cli, params: &MergeMode, output: &std::path::Path, options: &OutputOptions) -> Result<(), ScraperError> {
+    // files are read in name order, so that the same duplicate is kept on every run
+    let mut folder = std::fs::read_dir(&params.folder_path)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    folder.sort();
+
+    // skip non csv files, compressed ones excepted
+    let folder = folder.into_iter()
+        .filter(|f| f.extension().is_some_and(|ext| ext == "csv") || f.to_string_lossy().ends_with(".csv.gz"))
+        .collect::<Vec<_>>();
+
+    if params.low_memory {
+        if cli.report_duplicates {
+            return Err(ScraperError::Parse("--report-duplicates tiene in memoria tutte le righe, non puo' essere usato con --low-memory".to_string()));
+        }
+        return merge_csvs_low_memory(cli, &folder, output, options);
+    }
+
+    let mut entries = Entries::new(cli);
+    let mut duplicates = Duplicates::new(cli);
+    let mut count = 0;
+    for csv_name in folder {
+        let source = csv_name.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        for row in read_csv(&csv_name)? {
+            count += 1;
+            if let Some(duplicates) = &mut duplicates {
+                duplicates.record(&row, &source);
+            }
+            entries.insert(row, cli.merge_strategy);
+        }
+    }
+
+    println!("Tutte le righe lette. Trovate = {count}, uniche = {}, telefoni unici = {}", entries.len(), entries.unique_phones());
+    if let Some(duplicates) = duplicates {
+        println!("Gruppi di duplicati = {}", duplicates.write(output)?);
+    }
+
+    let mut entries = entries.into_vec();
+    if !options.excluded_phones.is_empty() {
+        let excluded = exclude_phones(&mut entries, &options.excluded_phones);
+        println!("Righe escluse per numero di telefono = {excluded}");
+    }
+    if options.strict_phones {
+        println!("Righe senza un numero di telefono valido = {}", strict_phones(&mut entries));
+    }
+    sort_entries(&mut entries, cli.sort_key(), !cli.no_dedup);
+
+    write_entries(output, entries, options)
+}
+
+/// The next entry of a sorted run, ordered so that the heap pops the smallest entry first,
+/// and between duplicates the one of the first file.
+struct RunHead {
+    entry: BusinessEntry,
+    run: usize,
+    sort_by: SortKey,
+}
+
+impl Ord for RunHead {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        cmp_entries_identity(&other.entry, &self.entry, self.sort_by).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for RunHead {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RunHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for RunHead {}
+
+/// External merge: each file is deduplicated and sorted on its own into a temporary run,
+/// then the runs are merged while writing, deduplicating the adjacent duplicates.
+/// Only [`MERGE_FAN_IN`] runs are open at once: with more of them, groups of runs are first merged into bigger runs.
+fn merge_csvs_low_memory(cli: &Cli, files: &[std::path::PathBuf], output: &std::path::Path, options: &OutputOptions) -> Result<(), ScraperError> {
+    // a merge needs an order, duplicates would otherwise never meet
+    let sort_by = if cli.sort_key() == SortKey::None { SortKey::Name } else { cli.sort_key() };
+
+    let runs_dir = std::env::temp_dir().join(format!("paginegialle-merge-{}", std::process::id()));
+    std::fs::create_dir_all(&runs_dir)?;
+    let result = merge_runs(cli, files, &runs_dir, sort_by, MERGE_FAN_IN, output, options);
+    std::fs::remove_dir_all(&runs_dir)?;
+    result
+}
+
+fn merge_runs(cli: &Cli, files: &[std::path::PathBuf], runs_dir: &std::path::Path, sort_by: SortKey, fan_in: usize, output: &std::path::Path, options: &OutputOptions) -> Result<(), ScraperError> {
+    let mut count = 0;
+    let mut runs = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let mut entries = Entries::new(cli);
+        for row in read_csv(file)? {
+            count += 1;
+            entries.insert(row, cli.merge_strategy);
+        }
+        let mut entries = entries.into_vec();
+        entries.sort_unstable_by(|a, b| cmp_entries(a, b, sort_by));
+
+        let run = runs_dir.join(format!("{i}.csv"));
+        let mut run_writer = csv::Writer::from_path(&run)?;
+        for entry in entries {
+            run_writer.serialize(entry)?;
+        }
+        run_writer.flush()?;
+        runs.push(run);
+    }
+
+    // consecutive runs are merged together, so that the runs stay in file order and the first file still wins
+    let mut pass = 0;
+    while runs.len() > fan_in {
+        let mut merged_runs = Vec::new();
+        for (i, group) in runs.chunks(fan_in).enumerate() {
+            let merged = runs_dir.join(format!("pass{pass}-{i}.csv"));
+            let mut run_writer = csv::Writer::from_path(&merged)?;
+            merge_sorted(cli, group, sort_by, |entry| Ok(run_writer.serialize(entry)?))?;
+            run_writer.flush()?;
+            for run in group {
+                std::fs::remove_file(run)?;
+            }
+            merged_runs.push(merged);
+        }
+        runs = merged_runs;
+        pass += 1;
+    }
+
+    let mut writer = EntryWriter::create(output, options)?;
+    let (mut uniques, mut excluded, mut invalid) = (0, 0, 0);
+    let mut write = |mut entry: BusinessEntry| -> Result<(), ScraperError> {
+        if entry.has_phone_in(&options.excluded_phones) {
+            excluded += 1;
+            return Ok(());
+        }
+        if options.strict_phones && !entry.retain_valid_phones() {
+            invalid += 1;
+            return Ok(());
+        }
+        uniques += 1;
+        writer.write(entry, options)
+    };
+    merge_sorted(cli, &runs, sort_by, &mut write)?;
+
+    println!("Tutte le righe lette. Trovate = {count}, uniche = {uniques}");
+    if !options.excluded_phones.is_empty() {
+        println!("Righe escluse per numero di telefono = {excluded}");
+    }
+    if options.strict_phones {
+        println!("Righe senza un numero di telefono valido = {invalid}");
+    }
+
+    writer.finish()
+}
+
+/// Merges the sorted runs into one sorted sequence of entries, merging the adjacent duplicates.
+fn merge_sorted(cli: &Cli, run_paths: &[std::path::PathBuf], sort_by: SortKey, mut write: impl FnMut(BusinessEntry) -> Result<(), ScraperError>) -> Result<(), ScraperError> {
+    let mut runs = run_paths.iter()
+        .map(|run| Ok(csv::Reader::from_path(run)?.into_deserialize::<BusinessEntry>()))
+        .collect::<Result<Vec<_>, ScraperError>>()?;
+
+    let mut heap = std::collections::BinaryHeap::new();
+    for (run, reader) in runs.iter_mut().enumerate() {
+        if let Some(entry) = reader.next().transpose()? {
+            heap.push(RunHead { entry, run, sort_by });
+        }
+    }
+
+    // duplicates are next to each other, so only the last entry is kept to be compared
+    let mut last: Option<BusinessEntry> = None;
+    while let Some(RunHead { entry, run, .. }) = heap.pop() {
+        if let Some(next) = runs[run].next().transpose()? {
+            heap.push(RunHead { entry: next, run, sort_by });
+        }
+
+        last = match last {
+            Some(last) if !cli.no_dedup && last == entry => Some(merge_duplicates(last, entry, cli.merge_strategy)),
+            Some(last) => {
+                write(last)?;
+                Some(entry)
+            }
+            None => Some(entry),
+        };
+    }
+    if let Some(last) = last {
+        write(last)?;
+    }
+    Ok(())
+}
+
+/// Saves the page html, preceded by a comment with its url and the capture unix timestamp.
+async fn capture_fixture(client: &reqwest::Client, params: &CaptureMode) -> Result<(), ScraperError> {
+    let res = cookies::send(client.get(&params.url)).await?;
+    let url = res.url().to_string();
+    let html = res.text().await?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let fixture = format!("<!-- url: {url}\n     captured: {timestamp} -->\n{html}");
+    std::fs::write(&params.fixture_path, fixture)?;
+
+    println!("Fixture salvata in {}", params.fixture_path);
+    Ok(())
+}
+
+/// Results of scraping a list of urls.
+struct Scrape {
+    entries: Entries,
+    /// every row of each dedup key, with `--report-duplicates`
+    duplicates: Option<Duplicates>,
+    /// empty pages for each comune (Filter mode only)
+    errors: HashMap<String, usize>,
+    /// non empty pages for each (comune, category), to detect results truncated by the page limit (Filter mode only)
+    full_pages: HashMap<(String, String), usize>,
+    /// entries found for each (comune, category) (Filter mode only)
+    found: HashMap<(String, String), usize>,
+    /// `comune/category` whose pages were skipped by `--timeout-per-comune` (Filter mode only)
+    cut_short: std::collections::BTreeSet<String>,
+    /// categories whose remaining pages were skipped after `--category-error-limit` failed requests in a row (Filter mode only)
+    failed_categories: std::collections::BTreeSet<String>,
+    /// requested urls whose page was skipped by `--max-page-bytes`
+    too_large: Vec<String>,
+    /// pages parsed and listings found for each (category, page number) (Filter mode only)
+    page_yield: HashMap<(String, usize), (usize, usize)>,
+    /// requested urls whose page was parsed, for the `--resume` checkpoint
+    completed: Vec<String>,
+    /// the requests and responses, with `--har`
+    har: Vec<har::Entry>,
+    /// how long each requested url took, with `--profile`
+    timings: Vec<(String, std::time::Duration)>,
+    /// the entries sent to the `--webhook`
+    webhook: webhook::Delivery,
+    time_limited: bool,
+    /// the scrape was aborted as the site is serving block pages
+    blocked: bool,
+    /// the scrape was aborted as nothing was found for the whole `--stall-timeout`
+    stalled: bool,
+    took: std::time::Duration,
+    /// where the entries were written as they were found, if anywhere
+    spool: Option<std::path::PathBuf>,
+}
+
+impl Scrape {
+    /// Comuni whose pages were all empty (Filter mode only).
+    fn not_found(&self, pages: usize) -> Vec<&String> {
+        self.errors.iter()
+            .filter(|(_, &val)| val == pages)
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Adds the results of another scrape to these ones.
+    fn absorb(&mut self, other: Scrape, strategy: MergeStrategy) {
+        for entry in other.entries.into_vec() {
+            self.entries.insert(entry, strategy);
+        }
+        match (&mut self.duplicates, other.duplicates) {
+            (Some(duplicates), Some(other)) => duplicates.extend(other),
+            (duplicates, other) => *duplicates = duplicates.take().or(other),
+        }
+        for (comune, count) in other.errors {
+            *self.errors.entry(comune).or_insert(0) += count;
+        }
+        for (key, count) in other.full_pages {
+            *self.full_pages.entry(key).or_insert(0) += count;
+        }
+        for (key, count) in other.found {
+            *self.found.entry(key).or_insert(0) += count;
+        }
+        self.cut_short.extend(other.cut_short);
+        self.failed_categories.extend(other.failed_categories);
+        self.too_large.extend(other.too_large);
+        for (key, (pages, listings)) in other.page_yield {
+            let page_yield = self.page_yield.entry(key).or_insert((0, 0));
+            page_yield.0 += pages;
+            page_yield.1 += listings;
+        }
+        self.completed.extend(other.completed);
+        self.har.extend(other.har);
+        self.timings.extend(other.timings);
+        self.webhook.absorb(other.webhook);
+        self.time_limited |= other.time_limited;
+        self.blocked |= other.blocked;
+        self.stalled |= other.stalled;
+        self.took += other.took;
+    }
+}
+
+/// Scrapes once more the comuni which had no results at all, as their empty pages might have been transient failures.
+async fn retry_empty(cli: &Cli, client: &reqwest::Client, selectors: &Selectors, scraped: &mut Scrape, urls: &[String], filter: &FilterOptions, deadline: Option<std::time::Instant>) -> Result<(), ScraperError> {
+    if scraped.time_limited || scraped.blocked || scraped.stalled {
+        return Ok(());
+    }
+
+    let not_found = scraped.not_found(cli.page_range().len())
+        .into_iter()
+        .cloned()
+        .collect::<HashSet<_>>();
+    let retry_urls = urls.iter()
+        .filter(|u| comune_from_url(u).is_some_and(|c| not_found.contains(&c)))
+        .cloned()
+        .collect::<Vec<_>>();
+    if retry_urls.is_empty() {
+        return Ok(());
+    }
+
+    println!("\nNuovo tentativo per {} comuni senza risultati...", not_found.len());
+    let retried = scrape(cli, client, selectors, &retry_urls, Some(filter), deadline, scraped.spool.as_deref()).await?;
+    let still_not_found = retried.not_found(cli.page_range().len()).len();
+    println!("\r{} comuni su {} hanno dato risultati al secondo tentativo.", not_found.len() - still_not_found, not_found.len());
+
+    // the retry replaces the first empty pages count of the retried comuni
+    for comune in &not_found {
+        scraped.errors.remove(comune);
+    }
+    scraped.absorb(retried, cli.merge_strategy);
     Ok(())
 }
+
+/// Reads the page text, giving up on pages larger than the limit before loading them whole in memory.
+async fn read_page(mut res: reqwest::Response, max_bytes: Option<usize>) -> Result<String, ScraperError> {
+    let Some(max_bytes) = max_bytes else {
+        return Ok(res.text().await?);
+    };
+
+    let too_large = |res: &reqwest::Response| ScraperError::PageTooLarge { url: res.url().to_string(), max_bytes };
+    if res.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Err(too_large(&res));
+    }
+
+    // taken before reading the body, which consumes the response
+    let content_type = res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    // the content length might be missing or wrong, so the limit is checked while reading too
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large(&res));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(decode_page(&body, content_type.as_deref()))
+}
+
+/// Decodes the page with the charset of its content type, UTF-8 if missing or unknown, as `reqwest::Response::text` does.
+fn decode_page(body: &[u8], content_type: Option<&str>) -> String {
+    let encoding = content_type
+        .and_then(|content_type| content_type.split(';').find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("charset").then(|| value.trim().trim_matches('"'))
+        }))
+        .and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(body).0.into_owned()
+}
+
+/// What the page parser sends to the collector.
+enum ScrapeEvent {
+    Entry(Box<BusinessEntry>),
+    /// a page of the comune had no entries (Filter mode only)
+    EmptyPage(String),
+}
+
+/// Collects the entries as they are scraped, deduplicating them, and counts the empty pages of each comune.
+/// With `--report-duplicates`, every row is recorded too before being deduplicated.
+/// With `--webhook`, the new entries are sent to the webhook as they come, the duplicates being left out.
+/// The new entries are also written to the spool file as they come, so that they are on disk while the scrape goes on.
+async fn collect(
+    mut receiver: tokio::sync::mpsc::Receiver<ScrapeEvent>,
+    mut entries: Entries,
+    mut duplicates: Option<Duplicates>,
+    webhook: Option<webhook::Webhook>,
+    mut spool: Option<csv::Writer<std::fs::File>>,
+    strategy: MergeStrategy,
+) -> (Entries, Option<Duplicates>, HashMap<String, usize>, webhook::Delivery) {
+    let mut errors = HashMap::new();
+
+    while let Some(event) = receiver.recv().await {
+        match event {
+            ScrapeEvent::Entry(entry) => {
+                if !entry.name.is_empty() && !entry.phones.is_empty() {
+                    if let Some(duplicates) = &mut duplicates {
+                        duplicates.record(&entry, entry.categories.first().map(String::as_str).unwrap_or_default());
+                    }
+                    // kept before being moved into the set, but only sent and spooled if it's not a duplicate
+                    let sent = webhook.as_ref().map(|_| (*entry).clone());
+                    let record = spool.as_ref().map(|_| entry.csv_record().map(Cow::into_owned));
+                    let new = entries.insert(*entry, strategy);
+                    if let (true, Some(webhook), Some(sent)) = (new, &webhook, sent) {
+                        webhook.send(&sent);
+                    }
+                    if let (true, Some(writer), Some(record)) = (new, &mut spool, record) {
+                        // the spool is given up on the first failure, the scrape itself is fine
+                        if let Err(e) = writer.write_record(record).and_then(|_| Ok(writer.flush()?)) {
+                            eprintln!("\rScrittura del file parziale interrotta: {e}");
+                            spool = None;
+                        }
+                    }
+                }
+            }
+            ScrapeEvent::EmptyPage(comune) => *errors.entry(comune).or_insert(0) += 1,
+        }
+    }
+
+    let delivery = match webhook {
+        Some(webhook) => webhook.finish().await,
+        None => webhook::Delivery::default(),
+    };
+    (entries, duplicates, errors, delivery)
+}
+
+/// Sends the requests for the urls and scrapes the responses.
+///
+/// Data flow:
+/// 1. the urls stream dispatches the requests, up to `--paginegialle-concurrency` at once to PagineGialle and `--external-concurrency`
+///    to each other host (see [`limits::buffer_by_host`]), stopping at the deadline, on a stall or when blocked;
+/// 2. each response is parsed as soon as it arrives, and its entries are sent over a bounded channel;
+/// 3. the [`collect`] task receives them concurrently, so that they are consumed as they are produced,
+///    and appends the new ones to the `spool` file (see [`create_spool`]);
+/// 4. once every response is parsed the sender is dropped, the collector finishes and its entries are returned.
+///
+/// `filter` is set when the urls come from the Filter mode generator, so that they are known to be per comune and category.
+async fn scrape(cli: &Cli, client: &reqwest::Client, selectors: &Selectors, urls: &[String], filter: Option<&FilterOptions>, deadline: Option<std::time::Instant>, spool: Option<&std::path::Path>) -> Result<Scrape, ScraperError> {
+    if cli.debug {
+        println!("Url generati: {urls:?}\n");
+    }
+    println!("Richieste da effettuare: {}", urls.len());
+
+    let timer_start = std::time::Instant::now();
+
+    let category_pause = filter.and_then(|f| f.category_pause_secs).map(std::time::Duration::from_secs);
+
+    // the pages of a comune are requested one after the other, so its budget starts with its first page
+    let comune_timeout = filter.and_then(|f| f.timeout_per_comune);
+    let mut comune_starts = HashMap::new();
+    let cut_short = std::cell::RefCell::new(std::collections::BTreeSet::new());
+    let throttle = cli.throttle_on_429.then(|| throttle::Throttle::new(cli.debug));
+    let throttle = throttle.as_ref();
+
+    // a category failing request after request (e.g. a slug the site errors on) is given up, rather than holding up the others
+    let category_error_limit = filter.map(|f| f.category_error_limit).filter(|&limit| limit > 0);
+    let failures_in_a_row = std::cell::RefCell::new(HashMap::<String, usize>::new());
+    let failed_categories = std::cell::RefCell::new(std::collections::BTreeSet::new());
+    let too_large = std::cell::RefCell::new(Vec::new());
+
+    // THIS JUST SENDS THE HTTP REQUESTS
+    // urls are generated category by category, so a category change means its batch is over
+    let mut last_category = None;
+    let htmls = futures::stream::iter(urls)
+    .filter(|url| {
+        if category_error_limit.is_some() && category_from_url(url).is_some_and(|c| failed_categories.borrow().contains(&c)) {
+            return futures::future::ready(false);
+        }
+        futures::future::ready(true)
+    })
+    .then(move |url| {
+        let category = category_from_url(url);
+        let pause = category_pause.filter(|_| last_category.is_some() && last_category != category);
+        last_category = category;
+        // only the PagineGialle requests are throttled
+        let throttle = throttle.filter(|_| limits::HostGroup::of(url) == limits::HostGroup::PagineGialle);
+
+        async move {
+            if let Some(pause) = pause {
+                tokio::time::sleep(pause).await;
+            }
+            if let Some(throttle) = throttle {
+                throttle.wait().await;
+            }
+            url
+        }
+    })
+    // checked after the category pause, which isn't part of the budget of the next comune
+    .filter(|url| {
+        let in_budget = comune_timeout.is_none_or(|timeout| {
+            let key = format!("{}/{}", comune_from_url(url).unwrap_or_default(), category_from_url(url).unwrap_or_default());
+            let start = *comune_starts.entry(key.clone()).or_insert_with(std::time::Instant::now);
+            let in_budget = start.elapsed() < timeout;
+            if !in_budget {
+                cut_short.borrow_mut().insert(key);
+            }
+            in_budget
+        });
+        futures::future::ready(in_budget)
+    })
+    .enumerate()
+    .map(|(i, url)| {
+        // if i % (urls.len() / 100) == 0 {
+        //     let percentage = ((i as f32 / urls.len() as f32) * 100.0).round();
+        //     print!("\r{percentage:02}% completato, {i} richieste effetuate");
+        //     std::io::stdout().flush().unwrap();
+        // }
+
+        // TODO: this just counts the requests sent, not the responses received...
+        let percentage = ((i as f32 / urls.len() as f32) * 100.0).round();
+        print!("\r{percentage:>2}% completato, {} richieste effetuate", i+1);
+        std::io::stdout().flush().unwrap();
+
+        let client = client.clone();
+        let group = limits::HostGroup::of(url);
+        let throttle = throttle.filter(|_| group == limits::HostGroup::PagineGialle);
+        (group, async move {
+            let mut request = match client.get(url).build() {
+                Ok(request) => request,
+                Err(e) => return (None, None, url, Err(e.into())),
+            };
+            cookies::add_to(&mut request);
+            let mut recording = cli.har.is_some().then(|| har::Recording::start(&request));
+
+            let start = std::time::Instant::now();
+            let page = async {
+                let mut attempt = 1;
+                let res = loop {
+                    let retry = request.try_clone();
+                    let res = client.execute(request).await?;
+                    let Some(throttle) = throttle.filter(|_| res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS) else { break res; };
+                    throttle.too_many_requests();
+
+                    // the page is asked again at the lowered rate, as many times as the bootstrap requests
+                    match retry.filter(|_| attempt < BOOTSTRAP_RETRIES) {
+                        Some(retry) => request = retry,
+                        None => break res,
+                    }
+                    throttle.wait().await;
+                    attempt += 1;
+                };
+                cookies::store_from(&res);
+                if let Some(recording) = &mut recording {
+                    recording.response(&res);
+                }
+                let final_url = res.url().to_string();
+                Ok((read_page(res, cli.max_page_bytes).await?, final_url))
+            }.await;
+
+            // from sending the request to the end of the body, failed requests included
+            let timing = cli.profile.then(|| (url.clone(), start.elapsed()));
+            let har_entry = recording.map(|r| r.finish(page.as_ref().map(|(html, _)| html.as_str()).map_err(ScraperError::to_string), cli.har_bodies));
+            (har_entry, timing, url, page)
+        })
+    });
+    let htmls = limits::buffer_by_host(htmls, cli.paginegialle_concurrency, cli.external_concurrency);
+
+    // the entries are collected by their own task while the pages are still being scraped
+    let (sender, receiver) = tokio::sync::mpsc::channel(RESULTS_CHANNEL_CAPACITY);
+    let webhook = cli.webhook.as_ref()
+        .map(|url| Ok::<_, ScraperError>(webhook::Webhook::start(
+            client.clone(), url.clone(), cli.webhook_batch_size, cli.webhook_flush_interval, OutputOptions::from_cli(cli)?,
+        )))
+        .transpose()?;
+    let spool_writer = spool
+        .map(|path| Ok::<_, ScraperError>(csv::WriterBuilder::new().has_headers(false).from_writer(std::fs::OpenOptions::new().append(true).open(path)?)))
+        .transpose()?;
+    let collector = tokio::spawn(collect(receiver, Entries::new(cli), Duplicates::new(cli), webhook, spool_writer, cli.merge_strategy));
+
+    // once the deadline fires, the stream stops dispatching new requests and we fall through to saving partial results
+    let time_limited = std::cell::Cell::new(false);
+    let deadline = async {
+        match deadline {
+            Some(deadline) => {
+                tokio::time::sleep_until(deadline.into()).await;
+                time_limited.set(true);
+            }
+            None => futures::future::pending().await,
+        }
+    };
+
+    // the watchdog fires when no listing has been found for the whole stall timeout
+    let last_progress = std::cell::Cell::new(std::time::Instant::now());
+    let stalled = std::cell::Cell::new(false);
+    let watchdog = async {
+        let Some(stall_timeout) = cli.stall_timeout else {
+            return futures::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep_until((last_progress.get() + stall_timeout).into()).await;
+            if last_progress.get().elapsed() >= stall_timeout {
+                stalled.set(true);
+                return;
+            }
+        }
+    };
+
+    // non empty pages for each (comune, category), to detect results truncated by the page limit
+    let full_pages = std::cell::RefCell::new(HashMap::new());
+    let found = std::cell::RefCell::new(HashMap::new());
+
+    // when too many consecutive pages look like block pages, we stop sending requests
+    let consecutive_blocked = std::cell::Cell::new(0);
+    let completed = std::cell::RefCell::new(Vec::new());
+    let page_yield = std::cell::RefCell::new(HashMap::new());
+    let har_entries = std::cell::RefCell::new(Vec::new());
+    let timings = std::cell::RefCell::new(Vec::new());
+
+    // scrape data from html text
+    // THIS PARSES THE HTTP RESPONSES TEXT
+    htmls.take_until(futures::future::select(Box::pin(deadline), Box::pin(watchdog)))
+    .take_while(|_| futures::future::ready(consecutive_blocked.get() < BLOCKED_PAGES_THRESHOLD))
+    .for_each(|(har_entry, timing, requested_url, response): (_, _, &String, Result<_, ScraperError>)| async {
+    har_entries.borrow_mut().extend(har_entry);
+    timings.borrow_mut().extend(timing);
+    if let (Some(limit), Some(category)) = (category_error_limit, category_from_url(requested_url)) {
+        let mut failures_in_a_row = failures_in_a_row.borrow_mut();
+        let failures = failures_in_a_row.entry(category.clone()).or_insert(0);
+        *failures = if response.is_ok() { 0 } else { *failures + 1 };
+        if *failures == limit && failed_categories.borrow_mut().insert(category.clone()) {
+            eprintln!("\rCategoria {category} saltata dopo {limit} richieste fallite di fila");
+        }
+    }
+    match response {
+        // the comune and category are taken from the requested url, as a redirect (e.g. a renamed comune) might change them
+        Ok((html, url)) => {
+            let document = scraper::Html::parse_document(&html);
+            // with --count-only the listings are only counted
+            let (page_entries, listings) = if cli.count_only {
+                (Vec::new(), parse::count_entries(&document, selectors))
+            } else {
+                let (mut page_entries, counts) = parse::parse_entries(&document, selectors, cli.preserve_raw);
+                let collapsed = collapse_page_duplicates(&mut page_entries);
+                if cli.debug {
+                    eprintln!("\r{url}: {counts} collapsed={collapsed}");
+                }
+                let listings = page_entries.len();
+                (page_entries, listings)
+            };
+
+            if listings > 0 {
+                last_progress.set(std::time::Instant::now());
+            }
+
+            if listings > 0 && filter.is_some() {
+                let key = (comune_from_url(requested_url).unwrap_or_default(), category_from_url(requested_url).unwrap_or_default());
+                *found.borrow_mut().entry(key.clone()).or_insert(0) += listings;
+                *full_pages.borrow_mut().entry(key).or_insert(0) += 1;
+            }
+
+            if listings == 0 && parse::looks_blocked(&document) {
+                consecutive_blocked.set(consecutive_blocked.get() + 1);
+                return;
+            }
+            consecutive_blocked.set(0);
+            completed.borrow_mut().push(requested_url.clone());
+
+            if let (Some(_), Some(category), Some(page)) = (filter, category_from_url(requested_url), page_from_url(requested_url)) {
+                let mut page_yield = page_yield.borrow_mut();
+                let (pages, page_listings) = page_yield.entry((category, page)).or_insert((0, 0));
+                *pages += 1;
+                *page_listings += listings;
+            }
+
+            if listings == 0 {
+                // only Filter mode urls are per comune, in the other modes we don't care about errors here
+                if filter.is_some() {
+                    let comune = comune_from_url(requested_url).unwrap_or_default();
+                    // a failed collector is reported once joined
+                    let _ = sender.send(ScrapeEvent::EmptyPage(comune)).await;
+                }
+
+                return;
+            }
+
+            for mut entry in page_entries {
+                entry.source_url = cli.include_source_url.then(|| url.clone());
+
+                entry.categories = filter.and(category_from_url(requested_url)).into_iter().collect();
+
+                let _ = sender.send(ScrapeEvent::Entry(Box::new(entry))).await;
+            }
+        }
+        Err(e @ ScraperError::PageTooLarge { .. }) => {
+            eprintln!("\r{e}");
+            too_large.borrow_mut().push(requested_url.clone());
+        }
+        Err(e) => eprintln!("Errore non gestito per: {e}"),
+    }
+    }).await;
+
+    // dropping the sender tells the collector that there are no more results
+    drop(sender);
+    let (entries, duplicates, errors, webhook) = collector.await.map_err(ScraperError::Collector)?;
+
+    Ok(Scrape {
+        entries,
+        duplicates,
+        errors,
+        full_pages: full_pages.into_inner(),
+        found: found.into_inner(),
+        cut_short: cut_short.into_inner(),
+        failed_categories: failed_categories.into_inner(),
+        too_large: too_large.into_inner(),
+        completed: completed.into_inner(),
+        page_yield: page_yield.into_inner(),
+        har: har_entries.into_inner(),
+        timings: timings.into_inner(),
+        webhook,
+        time_limited: time_limited.get(),
+        blocked: consecutive_blocked.get() >= BLOCKED_PAGES_THRESHOLD,
+        stalled: stalled.get(),
+        took: timer_start.elapsed(),
+        spool: spool.map(std::path::Path::to_path_buf),
+    })
+}
+
+/// Creates the spool file, where the entries are written as soon as they are found, with just the header.
+/// It holds the unsorted and unfiltered entries found so far, and it's removed once the output is written.
+fn create_spool(output_path: &std::path::Path) -> Result<std::path::PathBuf, ScraperError> {
+    let path = output_path.with_extension("partial.csv");
+    let mut writer = csv::Writer::from_path(&path)?;
+    writer.write_record(BusinessEntry::CSV_HEADER)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Stats added up over the runs sharing a `--state-dir` state.
+#[derive(Default)]
+struct StateStats {
+    runs: usize,
+    requests: usize,
+    took: std::time::Duration,
+}
+
+/// What a previous run already did, for `--resume` and `--state-dir`.
+struct Resume {
+    /// entries of the existing output
+    entries: Vec<BusinessEntry>,
+    /// urls that are not requested again
+    completed: HashSet<String>,
+    /// `<output>.checkpoint`, with only the completed urls, or the `--state-dir` state file
+    checkpoint_path: std::path::PathBuf,
+    /// stats of the previous runs, with `--state-dir`
+    stats: Option<StateStats>,
+}
+
+impl Resume {
+    fn load(cli: &Cli, output_path: &std::path::Path) -> Result<Self, ScraperError> {
+        if cli.format != OutputFormat::Csv || cli.split_by.is_some() {
+            return Err(ScraperError::Parse("--resume e --state-dir richiedono un singolo file di output CSV".to_string()));
+        }
+
+        let checkpoint_path = match &cli.state_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                std::path::Path::new(dir).join(format!("{:016x}.state", state_key(std::env::args_os())?))
+            }
+            None => output_path.with_extension("checkpoint"),
+        };
+        // read back as it was written
+        let entries = if output_path.exists() { read_csv_encoded(output_path, cli.encoding.encoding())? } else { Vec::new() };
+
+        let mut stats = cli.state_dir.is_some().then(StateStats::default);
+        let completed = if checkpoint_path.exists() {
+            let content = std::fs::read_to_string(&checkpoint_path)?;
+            match &mut stats {
+                Some(stats) => Self::parse_state(&content, stats),
+                None => content.lines().filter(|l| !l.is_empty()).map(String::from).collect(),
+            }
+        } else {
+            // the output was written without a checkpoint: the pages its entries come from are known only with --include-source-url
+            let completed = entries.iter()
+                .filter_map(|e| e.source_url.clone())
+                .collect::<HashSet<_>>();
+            if !entries.is_empty() && completed.is_empty() {
+                println!("Nessun checkpoint per {}: le attivita' salvate verranno mantenute, ma tutti gli url verranno richiesti.", output_path.display());
+            }
+            completed
+        };
+
+        println!("Ripresa: {} attivita' gia' salvate, {} url gia' completati.", entries.len(), completed.len());
+        if let Some(stats) = stats.as_ref().filter(|s| s.runs > 0) {
+            println!(
+                "Stato {}: {} esecuzioni precedenti, {} richieste in {:?}.",
+                checkpoint_path.display(), stats.runs, stats.requests, stats.took,
+            );
+        }
+        Ok(Self { entries, completed, checkpoint_path, stats })
+    }
+
+    /// Reads a state file, made of `<key> <value>` lines: the stats, and an `url` line for each completed url.
+    fn parse_state(content: &str, stats: &mut StateStats) -> HashSet<String> {
+        let mut completed = HashSet::new();
+        for line in content.lines().filter(|l| !l.starts_with('#')) {
+            let Some((key, value)) = line.split_once(' ') else { continue; };
+            match key {
+                "url" => { completed.insert(value.to_string()); }
+                "runs" => stats.runs = value.parse().unwrap_or_default(),
+                "requests" => stats.requests = value.parse().unwrap_or_default(),
+                "seconds" => stats.took = std::time::Duration::from_secs_f64(value.parse().unwrap_or_default()),
+                _ => {}
+            }
+        }
+        completed
+    }
+
+    /// Adds the entries of the previous run to the scraped ones, the previous ones coming first for the merge strategy.
+    /// With `--normalize` the previous entries were normalized when written, so the new ones are normalized too to match them.
+    fn restore_entries(&mut self, cli: &Cli, scraped: Entries) -> Entries {
+        let mut entries = Entries::new(cli);
+        for mut entry in self.entries.drain(..).chain(scraped.into_vec()) {
+            if cli.normalize {
+                entry.normalize(cli.title_case_names);
+            }
+            entries.insert(entry, cli.merge_strategy);
+        }
+        entries
+    }
+
+    /// Saves the urls completed by all runs so far, once their entries are in the output,
+    /// and with `--state-dir` the stats including this run's requests and duration.
+    fn write_checkpoint(mut self, completed: &[String], requests: usize, took: std::time::Duration) -> Result<(), ScraperError> {
+        self.completed.extend(completed.iter().cloned());
+
+        let mut completed = self.completed.into_iter().collect::<Vec<_>>();
+        completed.sort();
+
+        let content = match self.stats {
+            None => completed.join("\n") + "\n",
+            Some(stats) => {
+                let args = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+                let mut content = format!(
+                    "# {} state for: {args}\nruns {}\nrequests {}\nseconds {}\n",
+                    env!("CARGO_PKG_NAME"), stats.runs + 1, stats.requests + requests, (stats.took + took).as_secs_f64(),
+                );
+                for url in completed {
+                    content.push_str(&format!("url {url}\n"));
+                }
+                content
+            }
+        };
+        std::fs::write(&self.checkpoint_path, content)?;
+        Ok(())
+    }
+}
+
+/// Key of the `--state-dir` state of the run: a hash (64 bit FNV-1a, stable across builds) of the parsed command line,
+/// leaving out the arguments that don't change what's scraped.
+/// The arguments given are hashed by name, so that their order and spelling (e.g. `--limit=3` or `-l 3`) don't matter.
+fn state_key<I: IntoIterator<Item = T>, T: Into<std::ffi::OsString> + Clone>(args: I) -> Result<u64, ScraperError> {
+    fn hash_bytes(hash: &mut u64, bytes: &[u8]) {
+        for &byte in bytes.iter().chain(&[0]) {
+            *hash ^= u64::from(byte);
+            *hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn hash_matches(hash: &mut u64, command: &clap::Command, matches: &clap::ArgMatches) {
+        let mut ids = matches.ids()
+            .map(clap::Id::as_str)
+            // the argument groups list the arguments given, in the order they were given
+            .filter(|id| command.get_arguments().any(|arg| arg.get_id() == *id))
+            .filter(|id| !matches!(*id, "state_dir" | "resume"))
+            // the defaults are left out, so that a new option doesn't change the key of the previous states
+            .filter(|id| matches.value_source(id).is_some_and(|source| source != clap::parser::ValueSource::DefaultValue))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        for id in ids {
+            hash_bytes(hash, id.as_bytes());
+            for value in matches.get_raw(id).into_iter().flatten() {
+                hash_bytes(hash, value.as_encoded_bytes());
+            }
+        }
+        if let Some((name, matches)) = matches.subcommand() {
+            hash_bytes(hash, name.as_bytes());
+            if let Some(command) = command.find_subcommand(name) {
+                hash_matches(hash, command, matches);
+            }
+        }
+    }
+
+    let command = <Cli as clap::CommandFactory>::command();
+    let matches = command.clone()
+        .try_get_matches_from(args)
+        .map_err(|e| ScraperError::Parse(e.to_string()))?;
+    let mut hash = 0xcbf29ce484222325_u64;
+    hash_matches(&mut hash, &command, &matches);
+    Ok(hash)
+}
+
+/// How an output file was generated, saved next to it as `<output>.meta.json`.
+#[derive(serde::Serialize)]
+struct RunManifest {
+    version: &'static str,
+    args: Vec<String>,
+    /// unix timestamp of the end of the run
+    timestamp: u64,
+    requests: usize,
+    duration_secs: f64,
+    entries: usize,
+    comuni_with_empty_pages: usize,
+    time_limited: bool,
+    blocked: bool,
+    stalled: bool,
+}
+
+impl RunManifest {
+    fn new(requests: usize, scraped: &Scrape) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            args: std::env::args().collect(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            requests,
+            duration_secs: scraped.took.as_secs_f64(),
+            entries: scraped.entries.len(),
+            comuni_with_empty_pages: scraped.errors.len(),
+            time_limited: scraped.time_limited,
+            blocked: scraped.blocked,
+            stalled: scraped.stalled,
+        }
+    }
+
+    fn write(&self, output_path: &std::path::Path) -> Result<(), ScraperError> {
+        let path = output_path.with_extension("meta.json");
+        std::fs::write(path, json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Reports comuni without results and truncated ones, and the time taken.
+/// Returns false if the scrape found nothing at all and there is nothing to save.
+fn report(cli: &Cli, scraped: &Scrape, filter: bool, comuni_count: usize) -> bool {
+    if scraped.blocked {
+        // the empty pages are block pages, reporting comuni without results would be misleading
+        eprintln!("\r{}", ScraperError::Blocked(BLOCKED_PAGES_THRESHOLD));
+        return !scraped.entries.is_empty();
+    }
+
+    let not_found = scraped.not_found(cli.page_range().len());
+    
+    if !not_found.is_empty() {
+        eprint!("\r");
+
+        if filter {
+            if not_found.len() == comuni_count {
+                eprint!("Nessuna provincia ha ottenuto alcun risultato. Hai scelto una categoria di attivita' valida?");
+                return false;
+            } else {
+                eprintln!("Nessun risultato per le seguenti provincie: {not_found:?}");
+            }
+        }
+    }
+
+    if cli.probe {
+        let mut found = scraped.found.iter()
+            .map(|((comune, category), count)| (comune, category, count))
+            .collect::<Vec<_>>();
+        found.sort();
+
+        println!("\rAttivita' trovate nella prima pagina:");
+        for (comune, category, count) in found {
+            println!("{comune}/{category}: {count}");
+        }
+    } else {
+        // with --probe a single page is always full, truncation warnings would be noise
+        let mut truncated = scraped.full_pages.iter()
+            .filter(|(_, &pages)| pages >= cli.page_range().len())
+            .map(|((comune, category), _)| format!("{comune}/{category}"))
+            .collect::<Vec<_>>();
+
+        if !truncated.is_empty() {
+            truncated.sort();
+            eprintln!("\rTutte le {} pagine hanno dato risultati per: {truncated:?}", cli.page_range().len());
+            eprintln!("I risultati potrebbero essere troncati, prova ad aumentare --limit.");
+        }
+    }
+
+    if !cli.probe {
+        report_page_yield(cli, &scraped.page_yield);
+    }
+
+    if cli.profile {
+        report_slowest_requests(&scraped.timings);
+    }
+
+    if cli.webhook.is_some() {
+        let delivery = &scraped.webhook;
+        println!("\rAttivita' inviate al webhook: {}", delivery.sent);
+        if delivery.failed > 0 {
+            eprintln!("Invio al webhook fallito per {} attivita' ({} invii)", delivery.failed, delivery.failed_batches);
+        }
+    }
+
+    if !scraped.cut_short.is_empty() {
+        eprintln!("\rTempo per comune esaurito, pagine saltate per: {}", scraped.cut_short.iter().map(String::as_str).collect::<Vec<_>>().join(", "));
+    }
+
+    if !scraped.failed_categories.is_empty() {
+        eprintln!("\rCategorie saltate per errori: {}", scraped.failed_categories.iter().map(String::as_str).collect::<Vec<_>>().join(", "));
+    }
+
+    if !scraped.too_large.is_empty() {
+        eprintln!("\rPagine fallite perche' superano --max-page-bytes: {}", scraped.too_large.len());
+    }
+
+    if cli.count_only {
+        println!("\nAttivita' contate: {}", scraped.found.values().sum::<usize>());
+    } else {
+        println!("\nAttivita' trovate: {}, numeri di telefono unici: {}", scraped.entries.len(), scraped.entries.unique_phones());
+    }
+
+    let time_took = scraped.took;
+    let minutes_took = time_took.as_secs() as f32 / 60.0;
+    println!("Tempo impiegato: {time_took:?} ({minutes_took} minuti)");
+    if scraped.time_limited {
+        println!("Tempo massimo di esecuzione raggiunto: i risultati sono parziali.");
+    }
+    if scraped.stalled {
+        println!("Nessun risultato per {:?}: scraping interrotto per stallo, i risultati sono parziali.", cli.stall_timeout.unwrap_or_default());
+    }
+
+    true
+}
+
+/// Prints the slowest requests, with the average and median duration to compare them with.
+fn report_slowest_requests(timings: &[(String, std::time::Duration)]) {
+    if timings.is_empty() {
+        return;
+    }
+
+    let mut timings = timings.iter().collect::<Vec<_>>();
+    timings.sort_by_key(|(_, took)| std::cmp::Reverse(*took));
+    let average = timings.iter().map(|(_, took)| *took).sum::<std::time::Duration>() / timings.len() as u32;
+    let median = timings[timings.len() / 2].1;
+
+    println!("\rRichieste piu' lente (media {average:.2?}, mediana {median:.2?} su {} richieste):", timings.len());
+    for (url, took) in timings.iter().take(PROFILE_SLOWEST_REQUESTS) {
+        println!("{took:>10.2?} {url}");
+    }
+}
+
+/// Prints the average listings of each page number for every category, hinting whether `--limit` should change:
+/// a last page yielding as much as the first means results are cut, pages yielding almost nothing are wasted requests.
+fn report_page_yield(cli: &Cli, page_yield: &HashMap<(String, usize), (usize, usize)>) {
+    if page_yield.is_empty() {
+        return;
+    }
+
+    // (page number, pages parsed, listings) of each category
+    let mut categories = std::collections::BTreeMap::<&str, Vec<(usize, usize, usize)>>::new();
+    for ((category, page), (pages, listings)) in page_yield {
+        categories.entry(category).or_default().push((*page, *pages, *listings));
+    }
+
+    println!("\rResa per categoria (attivita' per pagina):");
+    for (category, mut counts) in categories {
+        counts.sort();
+
+        let pages = counts.iter().map(|(_, pages, _)| pages).sum::<usize>();
+        let listings = counts.iter().map(|(_, _, listings)| listings).sum::<usize>();
+        let averages = counts.iter()
+            .map(|(page, pages, listings)| (*page, *listings as f64 / *pages as f64))
+            .collect::<Vec<_>>();
+        let per_page = averages.iter().map(|(page, avg)| format!("p-{page}: {avg:.1}")).collect::<Vec<_>>().join(", ");
+        print!("{category}: media {:.1} su {pages} pagine [{per_page}]", listings as f64 / pages as f64);
+
+        let first = averages.first().map(|(_, avg)| *avg).unwrap_or_default();
+        let last = averages.last().filter(|(page, _)| *page + 1 == cli.page_range().end).map(|(_, avg)| *avg);
+        let near_empty = averages.iter().find(|(_, avg)| *avg < NEAR_EMPTY_PAGE_YIELD);
+        match (last, near_empty) {
+            (Some(last), None) if averages.len() > 1 && last >= first / 2.0 => println!(" -> probabilmente troncata, aumenta --limit"),
+            (_, Some((page, _))) if *page > cli.page_start => println!(" -> pagine quasi vuote da p-{page}, puoi ridurre --limit"),
+            _ => println!(),
+        }
+    }
+}
+
+async fn write_output(cli: &Cli, client: &reqwest::Client, entries: Entries, output_path: &std::path::Path, output_options: &OutputOptions) -> Result<(), ScraperError> {
+    if cli.webhook_only {
+        println!("Scraping finito, le attivita' sono state inviate solo al webhook.");
+        return Ok(());
+    }
+
+    println!("Scraping finito, salvataggio su file CSV...");
+
+    let mut entries = entries.into_vec();
+
+    if !output_options.excluded_phones.is_empty() {
+        let excluded = exclude_phones(&mut entries, &output_options.excluded_phones);
+        println!("Attivita' escluse per numero di telefono gia' presente: {excluded}");
+    }
+
+    if output_options.strict_phones {
+        println!("Attivita' escluse senza un numero di telefono valido: {}", strict_phones(&mut entries));
+    }
+
+    if cli.resolve_websites {
+        let resolved = resolve_websites(client, &mut entries, cli.external_concurrency).await;
+        println!("Siti web risolti: {resolved}");
+    }
+
+    if cli.validate_websites {
+        let (checked, dead) = validate_websites(cli, client, &mut entries).await;
+        println!("Siti web non raggiungibili: {dead} su {checked}");
+    }
+
+    sort_entries(&mut entries, cli.sort_key(), !cli.no_dedup);
+
+    if entries.is_empty() {
+        match cli.output_empty_file {
+            EmptyOutput::Header => {}
+            EmptyOutput::Skip => {
+                println!("Nessun risultato, il file di output non verra' scritto.");
+                return Ok(());
+            }
+            EmptyOutput::Delete => {
+                println!("Nessun risultato, il file di output verra' eliminato se presente.");
+                let output_file = output_options.file_path(output_path);
+                if output_file.exists() {
+                    std::fs::remove_file(output_file)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    let outputs = match cli.split_by {
+        Some(split_by) => split_entries(entries, split_by, output_path),
+        None => vec![(output_path.to_path_buf(), entries)],
+    };
+
+    for (path, entries) in outputs {
+        write_entries(&path, entries, output_options)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the number of listings found for each comune and category, instead of the businesses.
+fn write_counts(path: &std::path::Path, found: &HashMap<(String, String), usize>) -> Result<(), ScraperError> {
+    let mut found = found.iter().collect::<Vec<_>>();
+    found.sort();
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(["comune", "category", "entries"])?;
+    for ((comune, category), count) in found {
+        csv_writer.write_record([comune, category, &count.to_string()])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+/// Prints what the run is going to do, as derived from the arguments: where the comuni and categories come from,
+/// how many requests will be sent and how fast.
+fn explain(cli: &Cli, targets: &[(Vec<String>, Option<&FilterOptions>)], comuni: usize, origin: Option<&FilterOrigin>) {
+    let pages = cli.page_range();
+    let requests = targets.iter().map(|(urls, _)| urls.len()).sum::<usize>();
+    let filter = targets.iter().find_map(|(_, filter)| *filter);
+
+    println!("\nPiano:");
+    if let Some(origin) = origin {
+        println!("  comuni: {comuni} ({})", origin.comuni);
+        println!("  categorie: {} ({})", origin.categories_count, origin.categories);
+    }
+    // the urls mode requests the given urls as they are
+    if !matches!(cli.mode, CliMode::Urls(_)) {
+        println!("  pagine: da {} a {} ({} per ricerca)", pages.start, pages.end - 1, pages.len());
+    }
+    if targets.len() > 1 {
+        let counts = targets.iter()
+            .map(|(urls, filter)| format!("{} {}", urls.len(), if filter.is_some() { "filter" } else { "search" }))
+            .collect::<Vec<_>>();
+        println!("  url: {requests} ({})", counts.join(", "));
+    } else {
+        println!("  url: {requests}");
+    }
+    if cli.retry_empty && filter.is_some() {
+        println!("  richieste stimate: fino a {} (con --retry-empty)", requests * 2);
+    } else {
+        println!("  richieste stimate: {requests}");
+    }
+    println!(
+        "  concorrenza: {} richieste alla volta a PagineGialle, {} per ogni altro sito",
+        cli.paginegialle_concurrency, cli.external_concurrency,
+    );
+    if let Some(pause) = filter.and_then(|f| f.category_pause_secs) {
+        println!("  pausa tra le categorie: {pause}s");
+    }
+    if let Some(timeout) = filter.and_then(|f| f.timeout_per_comune) {
+        println!("  tempo per comune: {timeout:?}");
+    }
+    if let Some(max_runtime) = cli.max_runtime {
+        println!("  durata massima: {max_runtime:?}");
+    }
+    if let Some(stall_timeout) = cli.stall_timeout {
+        println!("  interruzione per stallo dopo: {stall_timeout:?}");
+    }
+    println!();
+}
+
+/// The Filter targets of a Batch or Campaign run, and where their results go.
+struct BatchPlan {
+    targets: Vec<FilterMode>,
+    /// each target is written to its own file
+    per_target: bool,
+    /// all the targets are written together to the output, deduplicated
+    combined: bool,
+    /// the targets completed so far, in Campaign mode
+    state: Option<campaign::CampaignState>,
+    /// end of the `--max-runtime` of the whole run, shared by all the targets
+    deadline: Option<std::time::Instant>,
+}
+
+/// The targets of the Batch mode: a `region,city,category` row read from stdin for each, `#` rows being skipped.
+fn read_batch_targets(params: &BatchMode) -> Result<Vec<FilterMode>, ScraperError> {
+    let input = std::io::read_to_string(std::io::stdin())?;
+    let delimiter = if input.lines().next().unwrap_or_default().contains('\t') { b'\t' } else { b',' };
+
+    let reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+
+    let non_empty = |s: Option<&str>| s.filter(|s| !s.is_empty()).map(|s| s.to_string());
+    let mut targets = Vec::new();
+    for row in reader.into_records() {
+        let row = row?;
+        let Some(region) = non_empty(row.get(0)).filter(|r| !r.starts_with('#')) else { continue; };
+
+        targets.push(FilterMode {
+            region,
+            city: non_empty(row.get(1)),
+            category: non_empty(row.get(2)),
+            options: params.options.clone(),
+        });
+    }
+    Ok(targets)
+}
+
+/// The name of a target in the campaign state and in its output filename: the slugs of its region, city and category
+/// joined by `-`, which the slugs never contain, so that each triple gets its own name (`lazio-roma`, `lazio--roma`).
+fn target_name(filter: &FilterMode) -> String {
+    [Some(&filter.region), filter.city.as_ref(), filter.category.as_ref()]
+        .map(|field| field.map(|f| sanitize_comune_str(f)).unwrap_or_default())
+        .join("-")
+        .trim_end_matches('-')
+        .to_string()
+}
+
+/// Checks the manifest and the state of the campaign, planning its targets for [`run_batch`].
+fn campaign_plan(cli: &Cli, params: &CampaignMode, output_path: &std::path::Path, deadline: Option<std::time::Instant>) -> Result<BatchPlan, ScraperError> {
+    if cli.state_dir.is_some() {
+        return Err(ScraperError::Parse("--state-dir non e' supportato in modalita' campaign, usa --resume".to_string()));
+    }
+    // the master gets the entries of the completed targets back from their outputs
+    if cli.resume && (cli.format != OutputFormat::Csv || cli.split_by.is_some() || cli.count_only) {
+        return Err(ScraperError::Parse("--resume in modalita' campaign richiede un file di output CSV per ogni target".to_string()));
+    }
+
+    let manifest = std::fs::read_to_string(&params.manifest_path)?;
+    let targets = campaign::parse_targets(&manifest, &params.options)?;
+    println!("Campagna di {} target.", targets.len());
+
+    let state = campaign::CampaignState::load(output_path.with_extension("campaign"), cli.resume)?;
+    Ok(BatchPlan { targets, per_target: true, combined: true, state: Some(state), deadline })
+}
+
+/// Runs a Filter scrape for each target of the plan: a `region,city,category` row read from stdin, or a campaign target.
+async fn run_batch(cli: &Cli, client: &reqwest::Client, selectors: &Selectors, mut plan: BatchPlan, output_path: &std::path::Path, output_options: &OutputOptions, rng: &mut rng::SeededRng) -> Result<(), ScraperError> {
+    let template = cli.output_template.as_deref().map(OutputTemplate::parse).transpose()?;
+
+    let mut combined = Entries::new(cli);
+    let mut combined_counts = HashMap::new();
+    let mut blocked_rows = false;
+    let mut stalled_rows = false;
+    let mut found_entries = 0;
+    let mut har_entries = Vec::new();
+    let mut combined_duplicates = Duplicates::new(cli);
+    let mut failed_targets = Vec::new();
+    let spool = if cli.count_only { None } else { Some(create_spool(output_path)?) };
+    for filter in std::mem::take(&mut plan.targets) {
+        let target = target_name(&filter);
+        println!("\n--- {target} ---");
+
+        match plan.state.as_ref().and_then(|state| state.output_of(&target)) {
+            Some(Some(output)) => {
+                println!("Target gia' completato, attivita' riprese da {}", output.display());
+                for entry in read_csv_encoded(output, cli.encoding.encoding())? {
+                    combined.insert(entry, cli.merge_strategy);
+                }
+                continue;
+            }
+            Some(None) => {
+                println!("Target gia' completato, senza attivita'");
+                continue;
+            }
+            None => {}
+        }
+
+        // a target that can't be planned (e.g. an unknown region) doesn't stop the others
+        let (mut urls, comuni, origin) = match generate_urls_with_filter_mode(client, &filter, cli.page_range(), cli.debug).await {
+            Ok(planned) => planned,
+            Err(e) => {
+                eprintln!("Target {target} saltato: {e}");
+                failed_targets.push(target);
+                continue;
+            }
+        };
+        if cli.respect_robots {
+            let disallowed = retain_robots_allowed(client, &mut urls).await?;
+            println!("Url esclusi da robots.txt: {disallowed}");
+        }
+        if cli.shuffle {
+            shuffle_urls(&mut urls, rng);
+        }
+        if cli.explain {
+            explain(cli, &[(urls.clone(), Some(&filter.options))], comuni.len(), Some(&origin));
+        }
+        let mut scraped = scrape(cli, client, selectors, &urls, Some(&filter.options), plan.deadline, spool.as_deref()).await?;
+        if cli.retry_empty {
+            retry_empty(cli, client, selectors, &mut scraped, &urls, &filter.options, plan.deadline).await?;
+        }
+        har_entries.append(&mut scraped.har);
+        let (blocked, stalled, time_limited) = (scraped.blocked, scraped.stalled, scraped.time_limited);
+        found_entries += found_entries_of(cli, &scraped);
+        let manifest = (cli.manifest && plan.per_target).then(|| RunManifest::new(urls.len(), &scraped));
+
+        let path = match &template {
+            Some(template) => {
+                let values = template::Values {
+                    region: Some(&filter.region),
+                    city: filter.city.as_deref(),
+                    category: filter.category.as_deref(),
+                    count: Some(scraped.entries.len()),
+                };
+                std::path::PathBuf::from(template.resolve(values)).with_extension(cli.format.extension())
+            }
+            None => {
+                let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+                output_path.with_file_name(format!("{stem}_{target}.{}", cli.format.extension()))
+            }
+        };
+
+        match (&mut combined_duplicates, scraped.duplicates.take()) {
+            (Some(combined), Some(duplicates)) if plan.combined => combined.extend(duplicates),
+            (_, Some(duplicates)) => println!("Gruppi di duplicati: {}", duplicates.write(&path)?),
+            _ => {}
+        }
+
+        let mut written = false;
+        if cli.count_only {
+            report(cli, &scraped, true, comuni.len());
+            if plan.per_target {
+                write_counts(&path, &scraped.found)?;
+            }
+            if plan.combined {
+                for (key, count) in scraped.found {
+                    *combined_counts.entry(key).or_insert(0) += count;
+                }
+            }
+        } else if report(cli, &scraped, true, comuni.len()) {
+            match (plan.per_target, plan.combined) {
+                (true, true) => {
+                    let mut entries = Entries::new(cli);
+                    for entry in scraped.entries.into_vec() {
+                        combined.insert(entry.clone(), cli.merge_strategy);
+                        entries.insert(entry, cli.merge_strategy);
+                    }
+                    write_output(cli, client, entries, &path, output_options).await?;
+                    written = true;
+                }
+                (true, false) => {
+                    write_output(cli, client, scraped.entries, &path, output_options).await?;
+                    written = true;
+                }
+                (false, _) => {
+                    for entry in scraped.entries.into_vec() {
+                        combined.insert(entry, cli.merge_strategy);
+                    }
+                }
+            }
+        }
+
+        if let Some(manifest) = manifest {
+            manifest.write(&path)?;
+        }
+
+        // a target cut short is scraped again from the start by the next run,
+        // while one without an output file (no results, --output-empty-file, --webhook-only) has nothing to take back
+        if let Some(state) = plan.state.as_mut().filter(|_| !blocked && !stalled && !time_limited) {
+            let output = Some(output_options.file_path(&path)).filter(|output| written && output.exists());
+            state.complete(&target, output.as_deref())?;
+        }
+
+        if blocked {
+            // the next rows would be blocked too, stop here saving what was found so far
+            blocked_rows = true;
+            break;
+        }
+        if stalled {
+            stalled_rows = true;
+            break;
+        }
+        if time_limited {
+            println!("Tempo massimo di esecuzione raggiunto: i target rimanenti non verranno cercati.");
+            break;
+        }
+    }
+
+    if !failed_targets.is_empty() {
+        eprintln!("\nTarget saltati per errori: {}", failed_targets.join(", "));
+    }
+    if let Some(path) = &cli.har {
+        har::write(std::path::Path::new(path), &mut har_entries)?;
+    }
+
+    let output_path = match template.filter(OutputTemplate::uses_count) {
+        Some(template) => {
+            let values = template::Values { count: Some(combined.len()), ..Default::default() };
+            std::path::PathBuf::from(template.resolve(values)).with_extension(cli.format.extension())
+        }
+        None => output_path.to_path_buf(),
+    };
+    if let Some(duplicates) = combined_duplicates.filter(|_| plan.combined) {
+        println!("Gruppi di duplicati: {}", duplicates.write(&output_path)?);
+    }
+
+    if plan.combined {
+        if cli.count_only {
+            write_counts(&output_path, &combined_counts)?;
+        } else {
+            write_output(cli, client, combined, &output_path, output_options).await?;
+        }
+    }
+    if let Some(spool) = spool {
+        std::fs::remove_file(spool)?;
+    }
+
+    if blocked_rows {
+        return Err(ScraperError::Blocked(BLOCKED_PAGES_THRESHOLD));
+    }
+    if stalled_rows {
+        return Err(ScraperError::Stalled(cli.stall_timeout.unwrap_or_default()));
+    }
+    check_min_entries(cli, found_entries)
+}
+
+/// Unique entries found by the scrape, or listings counted with `--count-only`.
+fn found_entries_of(cli: &Cli, scraped: &Scrape) -> usize {
+    if cli.count_only { scraped.found.values().sum() } else { scraped.entries.len() }
+}
+
+/// Fails when the run found fewer entries than `--min-entries`.
+fn check_min_entries(cli: &Cli, found: usize) -> Result<(), ScraperError> {
+    if found < cli.min_entries {
+        return Err(ScraperError::TooFewEntries { found, min: cli.min_entries });
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    let cli = Cli::parse();
+    let cookies_file = cli.cookies_file.clone().map(std::path::PathBuf::from);
+
+    let result = run(cli).await;
+    // the session is saved even when the run fails, e.g. when blocked, as it's the state the site has seen
+    let result = match &cookies_file {
+        Some(path) => result.and(cookies::save(path).map_err(ScraperError::from)),
+        None => result,
+    };
+
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            e.exit_code()
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), ScraperError> {
+    // the whole run shares the --max-runtime, however many scrapes it's made of
+    let deadline = cli.max_runtime.map(|max_runtime| std::time::Instant::now() + max_runtime);
+
+    if cli.page_range().is_empty() {
+        return Err(ScraperError::Parse(format!("--page-end deve essere maggiore di --page-start ({})", cli.page_start)));
+    }
+
+    if cli.page_range().len() > LARGE_PAGE_LIMIT {
+        println!("Attenzione: un limite di {} pagine generera' molte richieste per ogni ricerca.", cli.page_range().len());
+    }
+    
+    let template = cli.output_template.as_deref().map(OutputTemplate::parse).transpose()?;
+    if template.as_ref().is_some_and(OutputTemplate::uses_count) {
+        match cli.mode {
+            CliMode::Merge(_) => return Err(ScraperError::Parse("{count} di --output-template non e' disponibile in modalita' merge".to_string())),
+            _ if cli.resumes() => return Err(ScraperError::Parse("{count} di --output-template non e' compatibile con --resume e --state-dir".to_string())),
+            _ => {}
+        }
+    }
+
+    // the search and urls pages aren't known to be of a comune and category, so there would be nothing to count them under
+    if cli.count_only && matches!(cli.mode, CliMode::Search(_) | CliMode::Urls(_) | CliMode::Combined(_)) {
+        return Err(ScraperError::Parse("--count-only conta le attivita' per comune e categoria: e' disponibile solo nelle modalita' filter, batch e campaign".to_string()));
+    }
+
+    let output_filename = match &template {
+        Some(template) => template.resolve(template::Values::of(&cli.mode)),
+        None => cli.output_file.clone(),
+    };
+    let mut output_path = std::path::PathBuf::new();
+    output_path.push(output_filename);
+    output_path.set_extension(cli.format.extension());
+
+    // better to fail now than after the whole scrape
+    if let Some(parent) = output_path.parent().filter(|p| !p.as_os_str().is_empty() && !p.is_dir()) {
+        if cli.mkdir {
+            std::fs::create_dir_all(parent)?;
+        } else {
+            return Err(ScraperError::Parse(format!("La cartella di output {} non esiste. Creala o passa --mkdir.", parent.display())));
+        }
+    }
+
+    if let Some(path) = cli.cookies_file.as_deref().map(std::path::Path::new).filter(|p| p.exists()) {
+        println!("Cookie caricati da {}: {}", path.display(), cookies::load(path)?);
+    }
+
+    // parse selectors and output settings up front, so that a mistake is reported before any request is made
+    let selectors = Selectors::parse()?;
+    let output_options = OutputOptions::from_cli(&cli)?;
+    let mut rng = seeded_rng(&cli);
+
+    // https://stackoverflow.com/questions/51044467/how-can-i-perform-parallel-asynchronous-http-get-requests-with-reqwest/51047786#51047786
+    if cli.danger_accept_invalid_certs {
+        eprintln!("ATTENZIONE: i certificati TLS non verranno verificati.");
+    }
+
+    let accept_language = reqwest::header::HeaderValue::from_str(&cli.accept_language)
+        .map_err(|e| ScraperError::Parse(format!("--accept-language `{}` non valido: {e}", cli.accept_language)))?;
+    let mut client = reqwest::Client::builder()
+        .default_headers([(reqwest::header::ACCEPT_LANGUAGE, accept_language)].into_iter().collect())
+        .danger_accept_invalid_certs(cli.danger_accept_invalid_certs);
+    if let Some(max_idle) = cli.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    if cli.http2 {
+        client = client.http2_prior_knowledge();
+    }
+    let client = client.build()?;
+
+    // each target is a list of urls, with the Filter options when they come from the Filter mode generator
+    let (mut targets, comuni, origin) = match cli.mode {
+        CliMode::Search(ref params) => {
+            validate_search_mode(params)?;
+            suggest_category(&client, params).await;
+            (vec![(generate_urls_with_search_mode(params, cli.page_range()), None)], vec![], None)
+        }
+        CliMode::Filter(ref params) => {
+            let (urls, comuni, origin) = generate_urls_with_filter_mode(&client, params, cli.page_range(), cli.debug).await?;
+            if params.category.is_none() {
+                confirm_all_categories(&cli, urls.len())?;
+            }
+            (vec![(urls, Some(&params.options))], comuni, Some(origin))
+        }
+        CliMode::Combined(ref params) => {
+            let search = SearchMode { query: params.query.clone(), location: params.location.clone() };
+            let search_urls = generate_urls_with_search_mode(&search, cli.page_range());
+            let (filter_urls, comuni, origin) = generate_urls_with_filter_mode(&client, &params.filter, cli.page_range(), cli.debug).await?;
+            if params.filter.category.is_none() {
+                confirm_all_categories(&cli, search_urls.len() + filter_urls.len())?;
+            }
+            (vec![(search_urls, None), (filter_urls, Some(&params.filter.options))], comuni, Some(origin))
+        }
+        CliMode::Merge(ref params) => {
+            return merge_csvs(&cli, params, &output_path, &output_options);
+        }
+        CliMode::Urls(ref params) => {
+            (vec![(read_urls_from_file(params)?, None)], vec![], None)
+        }
+        CliMode::Batch(_) if cli.resumes() => {
+            return Err(ScraperError::Parse("--resume e --state-dir non sono supportati in modalita' batch".to_string()));
+        }
+        CliMode::Batch(ref params) => {
+            let plan = BatchPlan { targets: read_batch_targets(params)?, per_target: !params.combined, combined: params.combined, state: None, deadline };
+            return run_batch(&cli, &client, &selectors, plan, &output_path, &output_options, &mut rng).await;
+        }
+        CliMode::Campaign(ref params) => {
+            let plan = campaign_plan(&cli, params, &output_path, deadline)?;
+            return run_batch(&cli, &client, &selectors, plan, &output_path, &output_options, &mut rng).await;
+        }
+        CliMode::Diff(ref params) => {
+            return diff_csvs(params);
+        }
+        CliMode::Capture(ref params) => {
+            return capture_fixture(&client, params).await;
+        }
+    };
+
+    let mut resume = if cli.resumes() { Some(Resume::load(&cli, &output_path)?) } else { None };
+    if let Some(resume) = &resume {
+        for (urls, _) in &mut targets {
+            urls.retain(|u| !resume.completed.contains(u));
+        }
+    }
+
+    if cli.respect_robots {
+        let mut disallowed = 0;
+        for (urls, _) in &mut targets {
+            disallowed += retain_robots_allowed(&client, urls).await?;
+        }
+        println!("Url esclusi da robots.txt: {disallowed}");
+    }
+
+    if cli.shuffle {
+        for (urls, _) in &mut targets {
+            shuffle_urls(urls, &mut rng);
+        }
+    }
+
+    if cli.explain {
+        explain(&cli, &targets, comuni.len(), origin.as_ref());
+    }
+
+    // the targets are scraped one after the other, but their results are deduplicated together
+    let spool = if cli.count_only { None } else { Some(create_spool(&output_path)?) };
+    let mut scraped: Option<Scrape> = None;
+    for (urls, filter) in &targets {
+        if scraped.as_ref().is_some_and(|s| s.time_limited || s.blocked || s.stalled) {
+            break;
+        }
+
+        let mut target_scraped = scrape(&cli, &client, &selectors, urls, *filter, deadline, spool.as_deref()).await?;
+        if let Some(filter) = filter.filter(|_| cli.retry_empty) {
+            retry_empty(&cli, &client, &selectors, &mut target_scraped, urls, filter, deadline).await?;
+        }
+        match &mut scraped {
+            Some(scraped) => scraped.absorb(target_scraped, cli.merge_strategy),
+            None => scraped = Some(target_scraped),
+        }
+    }
+    let Some(mut scraped) = scraped else {
+        if let Some(spool) = spool {
+            std::fs::remove_file(spool)?;
+        }
+        return Ok(());
+    };
+    // the count is only known now
+    if let Some(template) = template.as_ref().filter(|t| t.uses_count()) {
+        let values = template::Values { count: Some(scraped.entries.len()), ..template::Values::of(&cli.mode) };
+        output_path = std::path::PathBuf::from(template.resolve(values)).with_extension(cli.format.extension());
+    }
+    if let Some(path) = &cli.har {
+        har::write(std::path::Path::new(path), &mut scraped.har)?;
+    }
+    if let Some(duplicates) = scraped.duplicates.take() {
+        println!("Gruppi di duplicati: {}", duplicates.write(&output_path)?);
+    }
+    if let Some(resume) = &mut resume {
+        scraped.entries = resume.restore_entries(&cli, std::mem::replace(&mut scraped.entries, Entries::new(&cli)));
+    }
+
+    let requests = targets.iter().map(|(urls, _)| urls.len()).sum();
+    let filter = targets.iter().any(|(_, filter)| filter.is_some());
+
+    let (blocked, stalled) = (scraped.blocked, scraped.stalled);
+    let found_entries = found_entries_of(&cli, &scraped);
+    let manifest = cli.manifest.then(|| RunManifest::new(requests, &scraped));
+    if cli.count_only {
+        report(&cli, &scraped, filter, comuni.len());
+        write_counts(&output_path, &scraped.found)?;
+    } else if report(&cli, &scraped, filter, comuni.len()) {
+        let completed = std::mem::take(&mut scraped.completed);
+        let took = scraped.took;
+        write_output(&cli, &client, scraped.entries, &output_path, &output_options).await?;
+        if let Some(resume) = resume {
+            resume.write_checkpoint(&completed, requests, took)?;
+        }
+    }
+
+    if let Some(manifest) = manifest {
+        manifest.write(&output_path)?;
+    }
+    if let Some(spool) = spool {
+        std::fs::remove_file(spool)?;
+    }
+
+    if blocked {
+        return Err(ScraperError::Blocked(BLOCKED_PAGES_THRESHOLD));
+    }
+    if stalled {
+        return Err(ScraperError::Stalled(cli.stall_timeout.unwrap_or_default()));
+    }
+
+    check_min_entries(&cli, found_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Filter options with their default values.
+    fn filter_options() -> FilterOptions {
+        let Cli { mode: CliMode::Filter(params), .. } = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]) else {
+            unreachable!("filter is the Filter mode");
+        };
+        params.options
+    }
+
+    /// The output options of a Filter scrape run with the given flags.
+    fn output_options(flags: &[&str]) -> OutputOptions {
+        let args = ["paginegialle-scraper"].iter().chain(flags).chain(&["filter", "lazio"]);
+        OutputOptions::from_cli(&Cli::parse_from(args)).unwrap()
+    }
+
+    /// A path in the temporary directory, unique to the test.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("paginegialle-scraper-test-{}-{name}", std::process::id()))
+    }
+
+    /// Serves each request on a local port with the response `respond` returns for its path, as raw HTTP
+    /// without the status line (e.g. `"200 OK\r\n\r\n<html>"`). Returns the base url of the server.
+    fn mock_server(respond: fn(&str) -> String) -> String {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // the headers, then the body if any
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                std::io::Read::read_exact(&mut reader, &mut vec![0; length]).unwrap();
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let response = respond(path).replacen("\r\n", "\r\nConnection: close\r\n", 1);
+                stream.write_all(format!("HTTP/1.1 {response}").as_bytes()).unwrap();
+            }
+        });
+        base
+    }
+
+    /// An entry with just the name and the packed phones.
+    fn entry(name: &str, phones: &str) -> BusinessEntry {
+        BusinessEntry { name: name.to_string(), phones: phone::unpack(phones), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn filter_urls_use_the_resolved_region() {
+        let params = FilterMode {
+            region: "Emilia-Romagna".to_string(),
+            city: Some("Bologna".to_string()),
+            category: Some("ristoranti".to_string()),
+            options: filter_options(),
+        };
+        let (urls, _, _) = generate_urls_with_filter_mode(&reqwest::Client::new(), &params, 1..3, false).await.unwrap();
+        assert_eq!(urls, [
+            "https://www.paginegialle.it/emilia_romagna/bologna/ristoranti/p-1.html",
+            "https://www.paginegialle.it/emilia_romagna/bologna/ristoranti/p-2.html",
+        ]);
+    }
+
+    #[test]
+    fn target_names_tell_the_fields_apart() {
+        let name = |region: &str, city: Option<&str>, category: Option<&str>| target_name(&FilterMode {
+            region: region.to_string(),
+            city: city.map(str::to_string),
+            category: category.map(str::to_string),
+            options: filter_options(),
+        });
+        assert_eq!(name("Lazio", Some("Roma"), Some("ristoranti")), "lazio-roma-ristoranti");
+        assert_eq!(name("Lazio", Some("Roma"), None), "lazio-roma");
+        assert_eq!(name("Lazio", None, Some("roma")), "lazio--roma");
+        assert_eq!(name("Lazio", None, None), "lazio");
+        // the `_` of the slugs can't be confused with the separator
+        assert_ne!(name("Lazio", Some("Roma Nord"), Some("bar")), name("Lazio", Some("Roma"), Some("nord bar")));
+    }
+
+    #[test]
+    fn builds_the_search_urls() {
+        let params = SearchMode { query: "da mario".to_string(), location: Some("firenze".to_string()) };
+        assert_eq!(generate_urls_with_search_mode(&params, 1..3), [
+            "https://www.paginegialle.it/ricerca/da%20mario/firenze/p-1",
+            "https://www.paginegialle.it/ricerca/da%20mario/firenze/p-2",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn region_spellings_give_the_same_urls() {
+        let urls = |region: &str, raw_region: bool| {
+            let params = FilterMode {
+                region: region.to_string(),
+                city: Some("Bologna".to_string()),
+                category: Some("ristoranti".to_string()),
+                options: FilterOptions { raw_region, ..filter_options() },
+            };
+            async move { generate_urls_with_filter_mode(&reqwest::Client::new(), &params, 1..2, false).await.unwrap().0 }
+        };
+
+        let expected = ["https://www.paginegialle.it/emilia_romagna/bologna/ristoranti/p-1.html"];
+        assert_eq!(urls("Emilia Romagna", false).await, expected);
+        assert_eq!(urls("emilia_romagna", false).await, expected);
+        assert_eq!(urls("EMILIA ROMAGNA", false).await, expected);
+        // --raw-region keeps the region as given
+        assert_eq!(urls("emiliaromagna", true).await, ["https://www.paginegialle.it/emiliaromagna/bologna/ristoranti/p-1.html"]);
+    }
+
+    #[test]
+    fn merge_unions_the_phones() {
+        let merged = entry("Pizzeria Da Mario", "055 123456 | 347 1234567").merge(entry("Pizzeria Da Mario", "+39 055 123456 | 06 7654321"));
+        // the first spelling of a number is the one kept
+        assert_eq!(phone::pack(&merged.phones), "055 123456 | 347 1234567 | 06 7654321");
+    }
+
+    #[test]
+    fn collapses_the_sponsored_card_shown_again() {
+        // the sponsored card on top repeats an organic listing further down, with its phone written differently
+        let page = r#"<html><body>
+            <div class="search-itm search-itm--sponsored">
+                <h2 class="search-itm__rag">Pizzeria Da Mario</h2>
+                <div class="search-itm__phone">055 123.456</div>
+                <a class="bttn bttn--white bttn--blank shinystat_ssxl" href="https://damario.it">Sito web</a>
+            </div>
+            <div class="search-itm">
+                <h2 class="search-itm__rag">Trattoria Roma</h2>
+                <div class="search-itm__adr">Piazza del Duomo 5 - 20121 Milano (MI)</div>
+                <div class="search-itm__phone">02 1234567</div>
+            </div>
+            <div class="search-itm">
+                <h2 class="search-itm__rag">PIZZERIA DA MARIO </h2>
+                <div class="search-itm__adr">Via Roma 1 - 50100 Firenze (FI)</div>
+                <div class="search-itm__phone">055 123456</div>
+                <a id="contattaci_btn" href="/contatti/da-mario">Contattaci</a>
+            </div>
+        </body></html>"#;
+        let (mut entries, _) = parse::parse_entries(&scraper::Html::parse_document(page), &Selectors::parse().unwrap(), false);
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(collapse_page_duplicates(&mut entries), 1);
+        let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Pizzeria Da Mario", "Trattoria Roma"]);
+        let mario = &entries[0];
+        assert_eq!(mario.address, "Via Roma 1 - 50100 Firenze (FI)");
+        assert_eq!(mario.website.as_deref(), Some("https://damario.it"));
+        assert!(mario.contact_url.is_some());
+        assert_eq!(mario.phones.len(), 1);
+    }
+
+    #[test]
+    fn merge_keeps_the_longer_address() {
+        let with_address = |address: &str| BusinessEntry { address: address.to_string(), ..entry("Pizzeria Da Mario", "055 123456") };
+        let full = "Via Roma 1 - 50100 Firenze (FI)";
+        assert_eq!(with_address("Via Roma 1").merge(with_address(full)).address, full);
+        assert_eq!(with_address(full).merge(with_address("Via Roma 1")).address, full);
+        assert_eq!(with_address("").merge(with_address("Via Roma 1")).address, "Via Roma 1");
+    }
+
+    #[test]
+    fn merge_only_fills_the_empty_fields() {
+        let first = BusinessEntry {
+            website: Some("https://damario.it".to_string()),
+            facebook: Some(String::new()),
+            ..entry("Pizzeria Da Mario", "055 123456")
+        };
+        let second = BusinessEntry {
+            website: Some("https://pizzeriadamario.com".to_string()),
+            facebook: Some("https://facebook.com/damario".to_string()),
+            instagram: Some("https://instagram.com/damario".to_string()),
+            ..entry("Pizzeria Mario", "055 123456")
+        };
+        let merged = first.merge(second);
+        assert_eq!(merged.name, "Pizzeria Da Mario");
+        assert_eq!(merged.website.as_deref(), Some("https://damario.it"));
+        assert_eq!(merged.facebook.as_deref(), Some("https://facebook.com/damario"));
+        assert_eq!(merged.instagram.as_deref(), Some("https://instagram.com/damario"));
+        assert_eq!(merged.contact_url, None);
+    }
+
+    #[test]
+    fn merged_duplicates_keep_every_category() {
+        let pizzeria = |category: &str| BusinessEntry { categories: vec![category.to_string()], ..entry("Pizzeria Da Mario", "055 123456") };
+        let merged = pizzeria("ristoranti").merge(pizzeria("pizzerie")).merge(pizzeria("ristoranti"));
+        assert_eq!(merged.categories, ["pizzerie", "ristoranti"]);
+
+        let bar = BusinessEntry { categories: vec!["bar".to_string()], ..entry("Bar Centrale", "06 1234567") };
+        let split = split_entries(vec![merged, bar], SplitKey::Category, std::path::Path::new("out/output.csv"));
+        let split = split.iter()
+            .map(|(path, entries)| (path.to_str().unwrap(), entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(split, [
+            ("out/output_bar.csv", vec!["Bar Centrale"]),
+            ("out/output_pizzerie.csv", vec!["Pizzeria Da Mario"]),
+            ("out/output_ristoranti.csv", vec!["Pizzeria Da Mario"]),
+        ]);
+    }
+
+    #[test]
+    fn low_memory_merge_in_bounded_passes() {
+        let dir = temp_path("low_memory");
+        let runs_dir = dir.join("runs");
+        std::fs::create_dir_all(&runs_dir).unwrap();
+
+        // more files than the fan-in, the pizzeria being in every one of them under a different category
+        let categories = ["bar", "enoteche", "pizzerie", "ristoranti", "trattorie"];
+        let files = categories.iter().enumerate()
+            .map(|(i, category)| {
+                let file = dir.join(format!("{i}.csv"));
+                let entries = vec![
+                    BusinessEntry { categories: vec![category.to_string()], ..entry("Pizzeria Da Mario", "055 123456") },
+                    entry(&format!("Attivita' {i}"), &format!("06 12345{i}")),
+                ];
+                write_entries(&file, entries, &output_options(&[])).unwrap();
+                file
+            })
+            .collect::<Vec<_>>();
+
+        let cli = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]);
+        let output = dir.join("merged.csv");
+        merge_runs(&cli, &files, &runs_dir, SortKey::Name, 2, &output, &output_options(&[])).unwrap();
+        let merged = read_csv(&output).unwrap();
+        // the merged runs replace the ones they were merged from
+        let runs_left = std::fs::read_dir(&runs_dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names = merged.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Attivita' 0", "Attivita' 1", "Attivita' 2", "Attivita' 3", "Attivita' 4", "Pizzeria Da Mario"]);
+        assert_eq!(merged[5].categories, categories);
+        assert_eq!(runs_left, 2);
+    }
+
+    #[test]
+    fn splits_the_json_address() {
+        let json = |address: &str| json::to_string(&JsonAddress::new(address)).unwrap();
+        assert_eq!(
+            json("Via Roma 1 - 50100 Firenze (FI)"),
+            r#"{"raw":"Via Roma 1 - 50100 Firenze (FI)","street":"Via Roma 1","cap":"50100","city":"Firenze"}"#,
+        );
+        // without a street or a city only the parts found are there
+        assert_eq!(json("20121 Milano"), r#"{"raw":"20121 Milano","cap":"20121","city":"Milano"}"#);
+        assert_eq!(json("Via Roma 1, 00186"), r#"{"raw":"Via Roma 1, 00186","street":"Via Roma 1","cap":"00186"}"#);
+        // no CAP, no parts
+        assert_eq!(json("Località Il Piano snc"), r#"{"raw":"Località Il Piano snc"}"#);
+
+        let path = temp_path("address.json");
+        let entries = vec![
+            BusinessEntry { address: "Via Roma 1 - 50100 Firenze (FI)".to_string(), ..entry("Pizzeria Da Mario", "055 123456") },
+            entry("Trattoria Roma", "02 1234567"),
+        ];
+        write_entries(&path, entries, &output_options(&["--format", "json", "--fields", "name,address"])).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.trim(), concat!(
+            r#"[{"name":"Pizzeria Da Mario","address":{"raw":"Via Roma 1 - 50100 Firenze (FI)","street":"Via Roma 1","cap":"50100","city":"Firenze"}},"#,
+            r#"{"name":"Trattoria Roma","address":null}]"#,
+        ));
+    }
+
+    #[test]
+    fn score_is_a_number() {
+        let lead = BusinessEntry { website: Some("https://damario.it".to_string()), ..entry("Pizzeria Da Mario", "055 123456 | 347 1234567") };
+        let path = temp_path("score.json");
+        write_entries(&path, vec![lead.clone()], &output_options(&["--score", "--format", "json", "--fields", "name,score"])).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(json.trim(), r#"[{"name":"Pizzeria Da Mario","score":4}]"#);
+
+        let path = temp_path("score.csv");
+        write_entries(&path, vec![lead], &output_options(&["--score"])).unwrap();
+        let scores = read_csv(&path).unwrap().iter().map(|e| e.score).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(scores, [Some(4)]);
+    }
+
+    #[test]
+    fn csv_header_is_pinned() {
+        let path = temp_path("header.csv");
+        write_entries(&path, vec![entry("Pizzeria Da Mario", "055 123456")], &output_options(&[])).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // new columns only ever go at the end, so that downstream parsers keep working
+        assert_eq!(csv.lines().next().unwrap(), "name,address,phones,whatsapp,website,contact_url,facebook,instagram,logo_url,categories");
+
+        // the columns of an option come with it
+        let options = output_options(&["--include-source-url", "--whatsapp-format", "both", "--split-address", "--score", "--split-phone-types"]);
+        write_entries(&path, vec![entry("Pizzeria Da Mario", "055 123456")], &options).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(csv.lines().next().unwrap(), "name,address,phones,whatsapp,website,contact_url,facebook,instagram,source_url,whatsapp_link,\
+            street,cap,city,score,mobile_phones,landline_phones,logo_url,categories");
+    }
+
+    #[tokio::test]
+    async fn filter_urls_of_multi_word_comuni() {
+        let url = |region: &str, city: &str| {
+            let params = FilterMode {
+                region: region.to_string(),
+                city: Some(city.to_string()),
+                category: Some("ristoranti".to_string()),
+                options: filter_options(),
+            };
+            async move { generate_urls_with_filter_mode(&reqwest::Client::new(), &params, 1..2, false).await.unwrap().0.remove(0) }
+        };
+
+        assert_eq!(url("emilia romagna", "San Giovanni in Persiceto").await, "https://www.paginegialle.it/emilia_romagna/san_giovanni_in_persiceto/ristoranti/p-1.html");
+        assert_eq!(url("campania", "Sant'Agata de' Goti").await, "https://www.paginegialle.it/campania/sant_agata_de_goti/ristoranti/p-1.html");
+        assert_eq!(url("emilia romagna", "Reggio nell'Emilia").await, "https://www.paginegialle.it/emilia_romagna/reggio_nell_emilia/ristoranti/p-1.html");
+        assert_eq!(url("umbria", "Città di Castello").await, "https://www.paginegialle.it/umbria/citta_di_castello/ristoranti/p-1.html");
+        assert_eq!(url("trentino alto adige", "San Candido - Innichen").await, "https://www.paginegialle.it/trentino_alto_adige/san_candido_innichen/ristoranti/p-1.html");
+    }
+
+    #[test]
+    fn normalizes_messy_names() {
+        let mut messy = BusinessEntry { address: "  Via Roma  1 -  50100 Firenze (FI) ".to_string(), ..entry("  PIZZERIA   da  mario ,", "055 123456") };
+        messy.normalize(false);
+        assert_eq!(messy.name, "PIZZERIA da mario");
+        assert_eq!(messy.address, "Via Roma 1 - 50100 Firenze (FI)");
+
+        let mut messy = entry("PIZZERIA   da  mario -", "055 123456");
+        messy.normalize(true);
+        assert_eq!(messy.name, "Pizzeria Da Mario");
+
+        // written as scraped without --normalize
+        let path = temp_path("normalize.csv");
+        write_entries(&path, vec![entry("  Bar  Centrale;", "06 1234567")], &output_options(&[])).unwrap();
+        let raw = read_csv(&path).unwrap();
+        write_entries(&path, vec![entry("  Bar  Centrale;", "06 1234567")], &output_options(&["--normalize"])).unwrap();
+        let normalized = read_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(raw[0].name, "  Bar  Centrale;");
+        assert_eq!(normalized[0].name, "Bar Centrale");
+    }
+
+    #[tokio::test]
+    async fn empty_pages_count_for_the_requested_comune() {
+        // a renamed comune redirects to its new name
+        let base = mock_server(|path| match path {
+            "/lazio/old_comune/ristoranti/p-1.html" => "301 Moved Permanently\r\nLocation: /lazio/new_comune/ristoranti/p-1.html\r\nContent-Length: 0\r\n\r\n".to_string(),
+            _ => "200 OK\r\nContent-Length: 13\r\n\r\n<html></html>".to_string(),
+        });
+
+        let cli = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]);
+        let urls = [format!("{base}/lazio/old_comune/ristoranti/p-1.html")];
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, Some(&filter_options()), None, None).await.unwrap();
+        assert_eq!(scraped.errors, HashMap::from([("old_comune".to_string(), 1)]));
+    }
+
+    #[test]
+    fn diff_matches_rows_sharing_the_key() {
+        let branch = |address: &str| BusinessEntry { address: address.to_string(), ..entry("Farmacia Centrale", "055 123456") };
+        let old = vec![branch("Via Roma 1"), branch("Via Po 2"), branch("Via Dante 3")];
+        let new = vec![branch("Via Po 2"), branch("Via Roma 10"), entry("Bar Centrale", "06 1234567")];
+
+        // the unchanged row is matched exactly, the others of its key in order
+        let diff = CsvDiff::new(old, new);
+        assert_eq!(diff.added.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), ["Bar Centrale"]);
+        assert_eq!(diff.removed.iter().map(|e| e.address.as_str()).collect::<Vec<_>>(), ["Via Dante 3"]);
+        assert_eq!(diff.changed.iter().map(|c| (c.old.address.as_str(), c.new.address.as_str())).collect::<Vec<_>>(), [("Via Roma 1", "Via Roma 10")]);
+    }
+
+    #[test]
+    fn diff_breaks_down_by_category() {
+        let categorized = |name: &str, categories: &[&str]| BusinessEntry {
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            ..entry(name, "055 123456")
+        };
+
+        // the categories are read back from the outputs
+        let path = temp_path("categories.csv");
+        write_entries(&path, vec![categorized("Pizzeria Da Mario", &["pizzerie", "ristoranti"])], &output_options(&[])).unwrap();
+        let old = read_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(old[0].categories, ["pizzerie", "ristoranti"]);
+
+        let new = vec![categorized("Trattoria Roma", &["ristoranti"]), entry("Bar Centrale", "06 1234567")];
+        let diff = CsvDiff::new(old, new);
+        assert_eq!(diff.by_category(), std::collections::BTreeMap::from([
+            ("", [1, 0, 0]),
+            ("pizzerie", [0, 1, 0]),
+            ("ristoranti", [1, 1, 0]),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn scrape_stops_at_the_shared_deadline() {
+        let base = mock_server(|_| "200 OK\r\nContent-Length: 13\r\n\r\n<html></html>".to_string());
+        let cli = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]);
+        let urls = [format!("{base}/lazio/roma/ristoranti/p-1.html")];
+
+        // a deadline already passed, e.g. used up by the previous batch rows
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, None, Some(std::time::Instant::now()), None).await.unwrap();
+        assert!(scraped.time_limited);
+        assert!(scraped.completed.is_empty());
+
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, None, None, None).await.unwrap();
+        assert!(!scraped.time_limited);
+        assert_eq!(scraped.completed, urls);
+    }
+
+    #[tokio::test]
+    async fn category_pause_is_outside_the_comune_budget() {
+        let base = mock_server(|_| "200 OK\r\nContent-Length: 13\r\n\r\n<html></html>".to_string());
+        let cli = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]);
+        let filter = FilterOptions {
+            category_pause_secs: Some(1),
+            timeout_per_comune: Some(std::time::Duration::from_millis(500)),
+            ..filter_options()
+        };
+        let urls = [format!("{base}/lazio/roma/ristoranti/p-1.html"), format!("{base}/lazio/roma/bar/p-1.html")];
+
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, Some(&filter), None, None).await.unwrap();
+        assert!(scraped.cut_short.is_empty());
+        assert_eq!(scraped.completed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn spools_the_new_entries_as_they_come() {
+        let base = mock_server(|path| {
+            let listing = |name: &str, phone: &str| format!(r#"<div class="search-itm"><h2 class="search-itm__rag">{name}</h2><div class="search-itm__phone">{phone}</div></div>"#);
+            let listings = match path {
+                "/lazio/roma/ristoranti/p-1.html" => listing("Pizzeria Da Mario", "06 123456"),
+                _ => listing("Pizzeria Da Mario", "06 123456") + &listing("Trattoria Roma", "06 7654321"),
+            };
+            let html = format!("<html><body>{listings}</body></html>");
+            format!("200 OK\r\nContent-Length: {}\r\n\r\n{html}", html.len())
+        });
+        let cli = Cli::parse_from(["paginegialle-scraper", "filter", "lazio"]);
+        let urls = (1..=2).map(|page| format!("{base}/lazio/roma/ristoranti/p-{page}.html")).collect::<Vec<_>>();
+
+        let spool = create_spool(&temp_path("spooled.csv")).unwrap();
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, None, None, Some(&spool)).await.unwrap();
+        let spooled = read_csv(&spool).unwrap();
+        std::fs::remove_file(&spool).unwrap();
+
+        // the duplicate isn't written twice
+        let mut names = spooled.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["Pizzeria Da Mario", "Trattoria Roma"]);
+        assert_eq!(scraped.entries.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn count_only_needs_comuni_and_categories() {
+        for mode in [&["search", "pizzeria", "roma"][..], &["urls", "urls.txt"], &["combined", "--query", "pizzeria", "lazio"]] {
+            let args = ["paginegialle-scraper", "--count-only"].iter().chain(mode);
+            let Err(ScraperError::Parse(e)) = run(Cli::parse_from(args)).await else { panic!("{mode:?} should be rejected") };
+            assert!(e.starts_with("--count-only"));
+        }
+    }
+
+    #[test]
+    fn resume_reads_back_the_encoded_output() {
+        let path = temp_path("resume.csv");
+        let options = output_options(&["--encoding", "windows1252"]);
+        write_entries(&path, vec![entry("Caffè Sant'Agata", "055 123456")], &options).unwrap();
+        let cli = Cli::parse_from(["paginegialle-scraper", "--resume", "--encoding", "windows1252", "filter", "lazio"]);
+        let resume = Resume::load(&cli, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resume.unwrap().entries[0].name, "Caffè Sant'Agata");
+    }
+
+    #[test]
+    fn resume_normalizes_the_new_entries_too() {
+        let cli = Cli::parse_from(["paginegialle-scraper", "--resume", "--normalize", "filter", "lazio"]);
+        let mut resume = Resume {
+            entries: vec![entry("Bar Centrale", "06 1234567")],
+            completed: HashSet::new(),
+            checkpoint_path: temp_path("unused.checkpoint"),
+            stats: None,
+        };
+        let mut scraped = Entries::new(&cli);
+        scraped.insert(entry("  Bar   Centrale;", "06 1234567"), cli.merge_strategy);
+
+        let entries = resume.restore_entries(&cli, scraped).into_vec();
+        assert_eq!(entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), ["Bar Centrale"]);
+    }
+
+    #[test]
+    fn state_key_ignores_the_spelling_of_the_arguments() {
+        let key = |args: &[&str]| state_key(["paginegialle-scraper"].iter().chain(args)).unwrap();
+
+        let base = key(&["--state-dir", "state", "--normalize", "--limit", "3", "filter", "lazio", "roma", "-c", "ristoranti"]);
+        assert_eq!(base, key(&["-l", "3", "--normalize", "--state-dir=other", "filter", "--category=ristoranti", "lazio", "roma"]));
+        assert_eq!(base, key(&["--resume", "--normalize", "--limit=3", "filter", "lazio", "roma", "-c", "ristoranti"]));
+        assert_ne!(base, key(&["--normalize", "--limit", "4", "filter", "lazio", "roma", "-c", "ristoranti"]));
+        assert_ne!(base, key(&["--normalize", "--limit", "3", "filter", "lazio", "roma", "-c", "bar"]));
+    }
+
+    #[test]
+    fn decodes_the_page_charset() {
+        let body = b"Caff\xe8 Sant\x92Angelo";
+        assert_eq!(decode_page(body, Some("text/html; charset=windows-1252")), "Caffè Sant’Angelo");
+        // as in browsers, latin1 is read as its windows-1252 superset
+        assert_eq!(decode_page(body, Some("text/html;Charset=\"ISO-8859-1\"")), "Caffè Sant’Angelo");
+        assert_eq!(decode_page("Caffè".as_bytes(), Some("text/html")), "Caffè");
+        assert_eq!(decode_page("Caffè".as_bytes(), None), "Caffè");
+        assert_eq!(decode_page("Caffè".as_bytes(), Some("text/html; charset=unknown")), "Caffè");
+    }
+
+    #[tokio::test]
+    async fn oversized_pages_count_as_failures() {
+        let base = mock_server(|_| "200 OK\r\nContent-Length: 13\r\n\r\n<html></html>".to_string());
+        let cli = Cli::parse_from(["paginegialle-scraper", "--max-page-bytes", "10", "filter", "lazio"]);
+        let filter = FilterOptions { category_error_limit: 2, ..filter_options() };
+        let urls = (1..=2).map(|page| format!("{base}/lazio/roma/ristoranti/p-{page}.html")).collect::<Vec<_>>();
+
+        let scraped = scrape(&cli, &reqwest::Client::new(), &Selectors::parse().unwrap(), &urls, Some(&filter), None, None).await.unwrap();
+        assert!(scraped.completed.is_empty());
+        assert_eq!(scraped.failed_categories, std::collections::BTreeSet::from(["ristoranti".to_string()]));
+        assert_eq!(scraped.too_large, urls);
+    }
+
+    #[test]
+    fn csv_round_trips_special_characters() {
+        let tricky = vec![
+            BusinessEntry {
+                address: "Via \"Roma\", 1 - 50100 Firenze (FI)".to_string(),
+                website: Some("https://example.it/?a=1,2&b=\"x\"".to_string()),
+                ..entry("Bar \"Il Ritrovo\", di Rossi & C.", "055 123456 | 347 1234567")
+            },
+            BusinessEntry {
+                address: "Piazza Duomo 5\r\nscala B - 20121 Milano (MI)".to_string(),
+                facebook: Some("https://facebook.com/a|b".to_string()),
+                ..entry("Pizzeria | Trattoria\nDa Mario;", "06 1234567")
+            },
+        ];
+
+        let path = temp_path("round_trip.csv");
+        write_entries(&path, tricky.clone(), &output_options(&[])).unwrap();
+        let read = read_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.len(), tricky.len());
+        for (read, written) in read.iter().zip(&tricky) {
+            assert_eq!(read.csv_record(), written.csv_record());
+        }
+    }
+
+    const COMUNI_CSV: &str = "nome;codice;popolazione\n\
+        Ardea;058117;49907\n\
+        Albano Laziale;058003;41715\n\
+        Ciampino;058118;38776\n\
+        Anzio;058007;55413\n\
+        Aprilia;059001;73446\n";
+
+    #[test]
+    fn parses_the_comuni_csv() {
+        let options = filter_options();
+        assert_eq!(parse_comuni_names_from_csv(COMUNI_CSV, &options).unwrap(), ["ardea", "albano_laziale", "ciampino", "anzio", "aprilia"]);
+
+        // a BOM, blank lines and rows without a name
+        let messy = format!("\u{feff}{}\n\n;058999;10\n  \n", COMUNI_CSV.replace("Ciampino;", "\nCiampino;"));
+        assert_eq!(parse_comuni_names_from_csv(&messy, &options).unwrap(), ["ardea", "albano_laziale", "ciampino", "anzio", "aprilia"]);
+
+        // only the header
+        assert!(parse_comuni_names_from_csv("nome;codice;popolazione\n", &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_bigger_half_of_the_comuni() {
+        let options = FilterOptions { big_cities_only: true, ..filter_options() };
+        assert_eq!(parse_comuni_names_from_csv(COMUNI_CSV, &options).unwrap(), ["aprilia", "anzio", "ardea"]);
+
+        // ties on the population are broken on the name
+        let ties = "nome;popolazione\nVelletri;1000\nGenzano;1000\nAriccia;1000\nNemi;10\n";
+        assert_eq!(parse_comuni_names_from_csv(ties, &options).unwrap(), ["ariccia", "genzano"]);
+    }
+
+    #[test]
+    fn reads_the_configured_comuni_columns() {
+        let options = FilterOptions { comuni_name_column: "denominazione".to_string(), ..filter_options() };
+        let csv = "denominazione;popolazione\nFrascati;22000\n";
+        assert_eq!(parse_comuni_names_from_csv(csv, &options).unwrap(), ["frascati"]);
+
+        let error = parse_comuni_names_from_csv(COMUNI_CSV, &options).unwrap_err().to_string();
+        assert!(error.contains("`denominazione`") && error.contains("nome, codice, popolazione"), "{error}");
+    }
+
+    fn split(address: &str) -> (Option<String>, Option<String>, Option<String>) {
+        let mut entry = BusinessEntry { address: address.to_string(), ..Default::default() };
+        entry.split_address();
+        (entry.street, entry.cap, entry.city)
+    }
+
+    fn some(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+
+    #[test]
+    fn splits_the_address_shapes() {
+        assert_eq!(split("Via Roma 1 - 50100 Firenze (FI)"), (some("Via Roma 1"), some("50100"), some("Firenze")));
+        assert_eq!(split("Corso Vittorio Emanuele II 23/B - 00186 Roma (RM)"), (some("Corso Vittorio Emanuele II 23/B"), some("00186"), some("Roma")));
+        assert_eq!(split("Viale della Repubblica, 120, 59100 Prato (PO)"), (some("Viale della Repubblica, 120"), some("59100"), some("Prato")));
+        assert_eq!(split("Località Il Piano snc - 53037 San Gimignano (SI)"), (some("Località Il Piano snc"), some("53037"), some("San Gimignano")));
+        // no street, no province
+        assert_eq!(split("20121 Milano"), (None, some("20121"), some("Milano")));
+    }
+
+    #[test]
+    fn keeps_the_address_without_a_cap_whole() {
+        assert_eq!(split("  Piazza del Duomo 5, Milano "), (some("Piazza del Duomo 5, Milano"), None, None));
+        assert_eq!(split(""), (None, None, None));
+    }
+
+    /// Times the sort of a large synthetic dataset: as it was, caching the whole record as the key, with the lowercased keys
+    /// computed once, lowercasing at each comparison, and skipped with --no-sort.
+    /// Run with `cargo test --release -- --ignored --nocapture bench_sort_entries`.
+    /// Median of 3 runs on 500k entries: cached record keys 934ms, lowercased keys 542ms (597ms with dedup),
+    /// comparator 2.00s, --no-sort under 1µs.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_sort_entries() {
+        const ENTRIES: usize = 500_000;
+        let mut rng = rng::SeededRng::new(384);
+        let words = ["pizzeria", "Bar", "trattoria", "Caffè", "RISTORANTE", "da", "Mario", "Centrale", "Roma", "al", "Porto", "Gelateria"];
+        let mut word = |n: usize| (0..n).map(|_| words[rng.below(words.len() as u64) as usize]).collect::<Vec<_>>().join(" ");
+        let entries = (0..ENTRIES)
+            .map(|i| BusinessEntry { address: format!("Via {} {i} - 00100 Roma (RM)", word(2)), ..entry(&word(3), &format!("06 {i:07}")) })
+            .collect::<Vec<_>>();
+
+        let time = |name: &str, sort: &dyn Fn(&mut Vec<BusinessEntry>)| {
+            let mut entries = entries.clone();
+            let start = std::time::Instant::now();
+            sort(&mut entries);
+            println!("{name:>24}: {:?}", start.elapsed());
+        };
+        // the sort before --no-sort was added, caching the whole record as the key
+        time("cached record keys", &|e| e.sort_by_cached_key(|e| (e.name.to_lowercase(), e.address.to_lowercase(), e.csv_record().map(Cow::into_owned))));
+        time("lowercased keys", &|e| sort_entries(e, SortKey::Name, false));
+        time("lowercased keys and dedup", &|e| sort_entries(e, SortKey::Name, true));
+        time("comparator", &|e| e.sort_unstable_by(|a, b| cmp_entries(a, b, SortKey::Name)));
+        time("--no-sort", &|e| sort_entries(e, SortKey::None, true));
+
+        // the same order either way
+        let (mut by_keys, mut by_comparator) = (entries.clone(), entries);
+        sort_entries(&mut by_keys, SortKey::Name, false);
+        by_comparator.sort_unstable_by(|a, b| cmp_entries(a, b, SortKey::Name));
+        assert!(by_keys.iter().zip(&by_comparator).all(|(a, b)| a.csv_record() == b.csv_record()));
+    }
+}
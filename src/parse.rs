@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+
+use crate::{phone::PhoneNumber, BusinessEntry, ScraperError, PAGINEGIALLE_URL};
+
+pub struct Selectors {
+    entries: scraper::Selector,
+    name: scraper::Selector,
+    address: scraper::Selector,
+    phone: scraper::Selector,
+    whatsapp: scraper::Selector,
+    contact: scraper::Selector,
+    website: scraper::Selector,
+    facebook: scraper::Selector,
+    instagram: scraper::Selector,
+    logo: scraper::Selector,
+}
+
+impl Selectors {
+    /// Parses every selector, reporting all the ones that failed (field and offending string) at once.
+    pub fn parse() -> Result<Self, ScraperError> {
+        let mut errors = Vec::new();
+        let mut parse = |field: &str, selector: &str| {
+            scraper::Selector::parse(selector)
+                .map_err(|e| errors.push(format!("{field}: `{selector}` ({e})")))
+                .ok()
+        };
+
+        let selectors = (
+            parse("entries", ".search-itm"),
+            parse("name", ".search-itm__rag"),
+            parse("address", ".search-itm__adr"),
+            parse("phone", ".search-itm__phone"),
+            parse("whatsapp", "a[data-pag=\"whatsapp\"]"),
+            parse("contact", "#contattaci_btn"),
+            parse("website", ".bttn.bttn--white.bttn--blank.shinystat_ssxl"),
+            parse("facebook", "a[href*=\"facebook.com\"]"),
+            parse("instagram", "a[href*=\"instagram.com\"]"),
+            parse("logo", "img"),
+        );
+
+        let (Some(entries), Some(name), Some(address), Some(phone), Some(whatsapp), Some(contact), Some(website), Some(facebook), Some(instagram), Some(logo)) = selectors else {
+            return Err(ScraperError::Selector(errors.join("\n")));
+        };
+
+        Ok(Self { entries, name, address, phone, whatsapp, contact, website, facebook, instagram, logo })
+    }
+}
+
+fn parse_phones(phones: &str) -> Vec<PhoneNumber> {
+    let mut seen = HashSet::new();
+
+    // each number is shown as its prefix followed by the rest (`055 123456 333 1234567`),
+    // the same number can be shown more than once in a listing, keep only its first occurrence
+    phones.split_whitespace()
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .map(|pair| pair.join("-"))
+        .filter(|p| seen.insert(p.clone()))
+        .map(|p| PhoneNumber::parse(&p))
+        .collect()
+}
+
+fn extract_text_from_html(element: &scraper::ElementRef, selector: &scraper::Selector) -> String {
+    // walk the matched element's text nodes directly, re-parsing its inner html is much slower
+    element.select(selector)
+        .next()
+        .map(|e| e.text()
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .fold(String::new(), |mut acc, text| {
+                if !acc.is_empty() {
+                    acc.push(' ');
+                }
+                acc.push_str(text);
+                acc
+            }))
+        .unwrap_or_default()
+}
+
+/// Texts found in CAPTCHA, rate limiting and block pages.
+const BLOCK_PAGE_MARKERS: [&str; 8] = [
+    "captcha",
+    "are you a robot",
+    "non sono un robot",
+    "access denied",
+    "accesso negato",
+    "too many requests",
+    "attention required",
+    "verifica di sicurezza",
+];
+
+/// Whether the page looks like a block page rather than a regular one, judging from its title and visible text.
+/// Scripts are left out, as regular pages can load a CAPTCHA widget too (e.g. reCAPTCHA for the contact forms).
+pub fn looks_blocked(document: &scraper::Html) -> bool {
+    let text = document.root_element()
+        .descendants()
+        .filter(|node| !node.ancestors()
+            .filter_map(|a| a.value().as_element())
+            .any(|e| matches!(e.name(), "script" | "style" | "noscript" | "template")))
+        .filter_map(|node| node.value().as_text().map(|t| &**t))
+        .collect::<String>()
+        .to_lowercase();
+
+    BLOCK_PAGE_MARKERS.iter().any(|m| text.contains(m))
+}
+
+/// How many listings each selector matched in a page, to spot partial markup changes.
+#[derive(Default)]
+pub struct SelectorCounts {
+    entries: usize,
+    name: usize,
+    address: usize,
+    phone: usize,
+    whatsapp: usize,
+    website: usize,
+    contact: usize,
+}
+
+impl std::fmt::Display for SelectorCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entries={} name={} address={} phone={} whatsapp={} website={} contact={}",
+            self.entries, self.name, self.address, self.phone, self.whatsapp, self.website, self.contact)
+    }
+}
+
+/// Empty values are dropped, as an empty CSV field is read back as a missing one.
+fn attr_of_first(element: &scraper::ElementRef, selector: &scraper::Selector, attr: &str) -> Option<String> {
+    element.select(selector)
+        .next()
+        .and_then(|n| n.attr(attr))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+/// The logo of a listing, made absolute. Lazy-loaded images keep the real url in `data-src`,
+/// while `src` holds an inline placeholder until they are shown.
+fn logo_url(element: &scraper::ElementRef, selector: &scraper::Selector) -> Option<String> {
+    let img = element.select(selector).next()?;
+    let src = [img.attr("data-src"), img.attr("src")]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|s| !s.is_empty() && !s.starts_with("data:"))?;
+
+    reqwest::Url::parse(PAGINEGIALLE_URL)
+        .and_then(|base| base.join(src))
+        .ok()
+        .map(String::from)
+}
+
+/// Number of listings of a results page, without scraping them.
+pub fn count_entries(document: &scraper::Html, selectors: &Selectors) -> usize {
+    document.select(&selectors.entries).count()
+}
+
+/// Scrapes every listing of a results page. With `preserve_raw` the name, address and phones are also kept as scraped.
+pub fn parse_entries(document: &scraper::Html, selectors: &Selectors, preserve_raw: bool) -> (Vec<BusinessEntry>, SelectorCounts) {
+    let mut counts = SelectorCounts::default();
+    let mut entries = Vec::new();
+
+    for element in document.select(&selectors.entries) {
+        counts.entries += 1;
+        let matches = |selector| element.select(selector).next().is_some() as usize;
+        counts.name += matches(&selectors.name);
+        counts.address += matches(&selectors.address);
+        counts.phone += matches(&selectors.phone);
+        counts.whatsapp += matches(&selectors.whatsapp);
+        counts.website += matches(&selectors.website);
+        counts.contact += matches(&selectors.contact);
+
+        let name = extract_text_from_html(&element, &selectors.name);
+        let raw_address = extract_text_from_html(&element, &selectors.address);
+        // https://stackoverflow.com/questions/71864137/whats-the-ideal-way-to-trim-extra-spaces-from-a-string
+        let address = raw_address.split_whitespace().collect::<Vec<_>>().join(" ");
+        let raw_phones = extract_text_from_html(&element, &selectors.phone);
+
+        let phones = parse_phones(&raw_phones);
+        let (name_raw, address_raw, phones_raw) = if preserve_raw {
+            (Some(name.clone()), Some(raw_address), Some(raw_phones))
+        } else {
+            (None, None, None)
+        };
+
+        let whatsapp = attr_of_first(&element, &selectors.whatsapp, "href")
+            .map(|s| s.chars()
+                .skip_while(|c| !c.is_numeric())
+                .take_while(|c| c.is_numeric())
+                .collect::<String>()
+            )
+            .filter(|s| !s.is_empty());
+
+        let contact_url = attr_of_first(&element, &selectors.contact, "href");
+        let website = attr_of_first(&element, &selectors.website, "href");
+
+        let facebook = attr_of_first(&element, &selectors.facebook, "href");
+        let instagram = attr_of_first(&element, &selectors.instagram, "href");
+        let logo_url = logo_url(&element, &selectors.logo);
+
+        entries.push(BusinessEntry {
+            name, phones, address, whatsapp, contact_url, website, facebook, instagram,
+            source_url: None,
+            whatsapp_link: None,
+            street: None,
+            cap: None,
+            city: None,
+            score: None,
+            mobile_phones: None,
+            landline_phones: None,
+            website_status: None,
+            name_raw, address_raw, phones_raw, logo_url,
+            categories: Vec::new(),
+        });
+    }
+
+    (entries, counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_phones(phones: &str) -> Vec<String> {
+        parse_phones(phones).into_iter().map(|p| p.raw).collect()
+    }
+
+    #[test]
+    fn pairs_the_phone_tokens() {
+        // sliding over the tokens would make up `123456-333` too
+        assert_eq!(raw_phones("055 123456 333 1234567"), ["055-123456", "333-1234567"]);
+    }
+
+    #[test]
+    fn dedups_the_phones_of_a_listing() {
+        // the same number shown both as the phone and next to the whatsapp button
+        assert_eq!(raw_phones("055 123456 055 123456"), ["055-123456"]);
+        assert_eq!(raw_phones("055 123456 333 1234567 055 123456"), ["055-123456", "333-1234567"]);
+    }
+
+    /// A results page with a complete listing and one whose phone markup changed.
+    const PAGE: &str = r#"<html><body>
+        <div class="search-itm">
+            <h2 class="search-itm__rag">Pizzeria Da Mario</h2>
+            <div class="search-itm__adr">Via Roma 1 - 50100 Firenze (FI)</div>
+            <div class="search-itm__phone">055 123456</div>
+            <a data-pag="whatsapp" href="https://wa.me/393471234567">WhatsApp</a>
+            <a class="bttn bttn--white bttn--blank shinystat_ssxl" href="https://damario.it">Sito web</a>
+        </div>
+        <div class="search-itm">
+            <h2 class="search-itm__rag">Trattoria Roma</h2>
+            <div class="search-itm__adr">Piazza del Duomo 5 - 20121 Milano (MI)</div>
+            <div class="search-itm__tel">02 1234567</div>
+            <a id="contattaci_btn" href="/contatti/trattoria-roma">Contattaci</a>
+        </div>
+    </body></html>"#;
+
+    #[test]
+    fn counts_the_selector_matches() {
+        let (entries, counts) = parse_entries(&scraper::Html::parse_document(PAGE), &Selectors::parse().unwrap(), false);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(counts.to_string(), "entries=2 name=2 address=2 phone=1 whatsapp=1 website=1 contact=1");
+    }
+
+    #[test]
+    fn detects_block_pages() {
+        let blocked = |html: &str| looks_blocked(&scraper::Html::parse_document(html));
+
+        assert!(blocked("<html><head><title>Attention Required! | Cloudflare</title></head><body>Sorry, you have been blocked</body></html>"));
+        assert!(blocked("<html><body><h1>Verifica di sicurezza</h1><p>Dimostra di non essere un robot</p></body></html>"));
+
+        // results pages load the reCAPTCHA of the contact forms
+        assert!(!blocked(&PAGE.replace("</body>", r#"<script src="https://www.google.com/recaptcha/api.js"></script>
+            <script>window.captchaReady = function() { grecaptcha.render("captcha"); };</script>
+            <noscript>Access denied without javascript</noscript></body>"#)));
+        assert!(!blocked(PAGE));
+    }
+
+    /// The implementation `extract_text_from_html` replaced, re-parsing the inner html of the matched element.
+    fn extract_text_by_fragment(element: &scraper::ElementRef, selector: &scraper::Selector) -> String {
+        let html = element.select(selector)
+            .next()
+            .map(|e| e.inner_html().trim().to_string())
+            .unwrap_or_default();
+
+        let frag = scraper::Html::parse_fragment(&html);
+        let mut tokens = Vec::new();
+        for node in frag.tree {
+            if let scraper::node::Node::Text(text) = node {
+                let trimmed = text.trim();
+                if !trimmed.is_empty() {
+                    tokens.push(trimmed.to_string());
+                }
+            }
+        }
+        tokens.join(" ")
+    }
+
+    #[test]
+    fn extracts_the_same_text_as_the_fragment_parse() {
+        let selector = scraper::Selector::parse(".field").unwrap();
+        let snippets = [
+            r#"<h2 class="field">Pizzeria Da Mario</h2>"#,
+            r#"<h2 class="field">  Pizzeria <b>Da Mario</b>&nbsp;&amp; Figli  </h2>"#,
+            r#"<div class="field"><span>Via Roma 1</span> - <span>50100 Firenze (FI)</span></div>"#,
+            r#"<div class="field">Via Roma 1<br>50100 Firenze<!-- comment --> (FI)</div>"#,
+            r#"<div class="field"><a href="/x"><span>Caff&egrave;</span></a>
+                <span>  </span>&lt;Centrale&gt;</div>"#,
+            r#"<div class="field"></div>"#,
+            r#"<div class="other">Not selected</div>"#,
+        ];
+        for snippet in snippets {
+            let document = scraper::Html::parse_fragment(&format!("<div>{snippet}</div>"));
+            let element = document.root_element();
+            assert_eq!(extract_text_from_html(&element, &selector), extract_text_by_fragment(&element, &selector), "{snippet}");
+        }
+
+        let document = scraper::Html::parse_document(PAGE);
+        let selectors = Selectors::parse().unwrap();
+        for listing in document.select(&selectors.entries) {
+            for selector in [&selectors.name, &selectors.address, &selectors.phone] {
+                assert_eq!(extract_text_from_html(&listing, selector), extract_text_by_fragment(&listing, selector));
+            }
+        }
+    }
+
+    /// Times the extraction of the name and address of the listings of a large page, with and without re-parsing
+    /// (`cargo test --release -- --ignored --nocapture bench_extract_text`).
+    /// Median of 3 runs, 100 passes over 200 listings: re-parsing the fragments 157ms, walking the text nodes 11.7ms.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_extract_text() {
+        let listing = r#"<div class="search-itm">
+            <h2 class="search-itm__rag"><a href="/pizzeria">Pizzeria <b>Da Mario</b> &amp; Figli</a></h2>
+            <div class="search-itm__adr"><span>Via Roma 1</span> - <span>50100 Firenze (FI)</span></div>
+        </div>"#;
+        let html = format!("<html><body>{}</body></html>", listing.repeat(200));
+        let document = scraper::Html::parse_document(&html);
+        let selectors = Selectors::parse().unwrap();
+        let listings = document.select(&selectors.entries).collect::<Vec<_>>();
+
+        let time = |name: &str, extract: fn(&scraper::ElementRef, &scraper::Selector) -> String| {
+            let start = std::time::Instant::now();
+            for _ in 0..100 {
+                for listing in &listings {
+                    std::hint::black_box(extract(listing, &selectors.name));
+                    std::hint::black_box(extract(listing, &selectors.address));
+                }
+            }
+            println!("{name:>16}: {:?}", start.elapsed());
+        };
+        time("fragment", extract_text_by_fragment);
+        time("text nodes", extract_text_from_html);
+    }
+}
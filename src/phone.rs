@@ -0,0 +1,135 @@
+//! Phone numbers of the listings, kept as scraped along with their digits and their type.
+//! In CSV they are packed in a single field, joined by ` | `.
+
+/// Type of an italian number, from its first digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PhoneKind {
+    /// `3...`
+    Mobile,
+    /// `0...`
+    Landline,
+    /// `80...`, numero verde
+    TollFree,
+    Other,
+}
+
+/// Ordered as written, the other fields following from it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+pub struct PhoneNumber {
+    /// as written in the listing, e.g. `055-123456`
+    pub raw: String,
+    /// the digits without separators and the italian prefix, so that differently written numbers compare equal
+    pub normalized: String,
+    pub kind: PhoneKind,
+}
+
+const SEPARATOR: &str = " | ";
+
+impl PhoneNumber {
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        let normalized = normalize(raw);
+        let kind = match normalized.as_bytes() {
+            [b'3', ..] => PhoneKind::Mobile,
+            [b'0', ..] => PhoneKind::Landline,
+            [b'8', b'0', ..] => PhoneKind::TollFree,
+            _ => PhoneKind::Other,
+        };
+
+        Self { raw: raw.to_string(), normalized, kind }
+    }
+
+    /// Whether the phone looks like an italian number: a landline (`0...`), a mobile (`3...`) or a toll free one (`8...`),
+    /// written only with digits and the usual separators.
+    pub fn is_valid(&self) -> bool {
+        if !self.raw.chars().all(|c| c.is_ascii_digit() || c.is_whitespace() || "+-/.()".contains(c)) {
+            return false;
+        }
+
+        let len = self.normalized.len();
+        match self.normalized.chars().next() {
+            Some('0') => (6..=11).contains(&len),
+            Some('3') => (9..=10).contains(&len),
+            Some('8') => (6..=10).contains(&len),
+            _ => false,
+        }
+    }
+}
+
+/// The phone digits, without separators and the italian prefix.
+pub fn normalize(phone: &str) -> String {
+    let phone = phone.trim();
+    let phone = phone.strip_prefix("+39").or_else(|| phone.strip_prefix("0039")).unwrap_or(phone);
+    phone.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+/// The phones of a packed field, empty ones skipped.
+pub fn unpack(packed: &str) -> Vec<PhoneNumber> {
+    packed.split(SEPARATOR)
+        .filter(|p| !p.trim().is_empty())
+        .map(PhoneNumber::parse)
+        .collect()
+}
+
+pub fn pack(phones: &[PhoneNumber]) -> String {
+    phones.iter()
+        .map(|p| p.raw.as_str())
+        .collect::<Vec<_>>()
+        .join(SEPARATOR)
+}
+
+/// Serializes the phones packed, for the CSV files.
+pub fn serialize_packed<S: serde::Serializer>(phones: &[PhoneNumber], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&pack(phones))
+}
+
+pub fn deserialize_packed<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<PhoneNumber>, D::Error> {
+    let packed = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(unpack(&packed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(raw: &str) -> (String, PhoneKind, bool) {
+        let phone = PhoneNumber::parse(raw);
+        let valid = phone.is_valid();
+        (phone.normalized, phone.kind, valid)
+    }
+
+    #[test]
+    fn parses_italian_numbers() {
+        assert_eq!(parsed("+39 055 123456"), ("055123456".to_string(), PhoneKind::Landline, true));
+        assert_eq!(parsed("0039 347 1234567"), ("3471234567".to_string(), PhoneKind::Mobile, true));
+        assert_eq!(parsed("800 123 456"), ("800123456".to_string(), PhoneKind::TollFree, true));
+        assert_eq!(parsed("06/1234567"), ("061234567".to_string(), PhoneKind::Landline, true));
+        assert_eq!(parsed("(02) 1234.5678"), ("0212345678".to_string(), PhoneKind::Landline, true));
+    }
+
+    #[test]
+    fn rejects_junk() {
+        assert_eq!(parsed("chiama ora"), (String::new(), PhoneKind::Other, false));
+        // digits, but not with the usual separators
+        assert!(!PhoneNumber::parse("tel: 055 123456").is_valid());
+        // too short for a mobile
+        assert_eq!(parsed("347 12"), ("34712".to_string(), PhoneKind::Mobile, false));
+        // not an italian prefix
+        assert_eq!(parsed("199 123456"), ("199123456".to_string(), PhoneKind::Other, false));
+    }
+
+    #[test]
+    fn keeps_the_raw_number() {
+        let phone = PhoneNumber::parse("  055-123456 ");
+        assert_eq!(phone.raw, "055-123456");
+    }
+
+    #[test]
+    fn packs_and_unpacks() {
+        let phones = unpack("055-123456 | 347-1234567 | ");
+        assert_eq!(phones.iter().map(|p| p.kind).collect::<Vec<_>>(), [PhoneKind::Landline, PhoneKind::Mobile]);
+        assert_eq!(pack(&phones), "055-123456 | 347-1234567");
+        assert!(unpack("").is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::sanitize_comune_str;
+
+/// Italian regions, in the same slug form used for comuni.
+const REGIONI: [&str; 20] = [
+    "abruzzo",
+    "basilicata",
+    "calabria",
+    "campania",
+    "emilia_romagna",
+    "friuli_venezia_giulia",
+    "lazio",
+    "liguria",
+    "lombardia",
+    "marche",
+    "molise",
+    "piemonte",
+    "puglia",
+    "sardegna",
+    "sicilia",
+    "toscana",
+    "trentino_alto_adige",
+    "umbria",
+    "valle_d_aosta",
+    "veneto",
+];
+
+// typos within this distance are silently corrected
+const MAX_REGION_DISTANCE: usize = 2;
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb { prev } else { 1 + prev.min(cur).min(row[j]) };
+            prev = cur;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Whether the name is exactly one of the regions (once sanitized).
+pub fn is_region(name: &str) -> bool {
+    REGIONI.contains(&sanitize_comune_str(name).as_str())
+}
+
+/// Normalizes the region name the same way comuni are sanitized, and matches it against the known regions.
+pub fn resolve_region(region: &str) -> Result<String, String> {
+    let region = sanitize_comune_str(region);
+
+    let (closest, distance) = REGIONI.iter()
+        .map(|r| (*r, levenshtein(&region, r)))
+        .min_by_key(|(_, d)| *d)
+        .unwrap_or_default();
+
+    if distance == 0 {
+        Ok(region)
+    } else if distance <= MAX_REGION_DISTANCE {
+        println!("Regione `{region}` non riconosciuta, verra' usata `{closest}`.");
+        Ok(closest.to_string())
+    } else {
+        Err(format!("Regione `{region}` sconosciuta. Regioni valide: {}", REGIONI.join(", ")))
+    }
+}
+
+/// Provincial capitals (capoluoghi di provincia) of each region.
+const CAPOLUOGHI: [(&str, &[&str]); 20] = [
+    ("abruzzo", &["L'Aquila", "Chieti", "Pescara", "Teramo"]),
+    ("basilicata", &["Potenza", "Matera"]),
+    ("calabria", &["Catanzaro", "Cosenza", "Crotone", "Reggio Calabria", "Vibo Valentia"]),
+    ("campania", &["Napoli", "Avellino", "Benevento", "Caserta", "Salerno"]),
+    ("emilia_romagna", &["Bologna", "Ferrara", "Forli", "Cesena", "Modena", "Parma", "Piacenza", "Ravenna", "Reggio Emilia", "Rimini"]),
+    ("friuli_venezia_giulia", &["Trieste", "Gorizia", "Pordenone", "Udine"]),
+    ("lazio", &["Roma", "Frosinone", "Latina", "Rieti", "Viterbo"]),
+    ("liguria", &["Genova", "Imperia", "La Spezia", "Savona"]),
+    ("lombardia", &["Milano", "Bergamo", "Brescia", "Como", "Cremona", "Lecco", "Lodi", "Mantova", "Monza", "Pavia", "Sondrio", "Varese"]),
+    ("marche", &["Ancona", "Ascoli Piceno", "Fermo", "Macerata", "Pesaro", "Urbino"]),
+    ("molise", &["Campobasso", "Isernia"]),
+    ("piemonte", &["Torino", "Alessandria", "Asti", "Biella", "Cuneo", "Novara", "Verbania", "Vercelli"]),
+    ("puglia", &["Bari", "Andria", "Barletta", "Trani", "Brindisi", "Foggia", "Lecce", "Taranto"]),
+    ("sardegna", &["Cagliari", "Nuoro", "Oristano", "Sassari", "Carbonia"]),
+    ("sicilia", &["Palermo", "Agrigento", "Caltanissetta", "Catania", "Enna", "Messina", "Ragusa", "Siracusa", "Trapani"]),
+    ("toscana", &["Firenze", "Arezzo", "Grosseto", "Livorno", "Lucca", "Massa", "Carrara", "Pisa", "Pistoia", "Prato", "Siena"]),
+    ("trentino_alto_adige", &["Trento", "Bolzano"]),
+    ("umbria", &["Perugia", "Terni"]),
+    ("valle_d_aosta", &["Aosta"]),
+    ("veneto", &["Venezia", "Belluno", "Padova", "Rovigo", "Treviso", "Verona", "Vicenza"]),
+];
+
+/// Sanitized names of the provincial capitals of an already resolved region.
+pub fn capoluoghi(region: &str) -> Vec<String> {
+    CAPOLUOGHI.iter()
+        .find(|(r, _)| *r == region)
+        .map(|(_, comuni)| comuni.iter().map(|c| sanitize_comune_str(c)).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_the_region_forms() {
+        assert_eq!(resolve_region("lazio").unwrap(), "lazio");
+        assert_eq!(resolve_region("emilia-romagna").unwrap(), "emilia_romagna");
+        assert_eq!(resolve_region("Valle d'Aosta").unwrap(), "valle_d_aosta");
+    }
+
+    #[test]
+    fn corrects_small_typos() {
+        assert_eq!(resolve_region("toscanna").unwrap(), "toscana");
+        assert_eq!(resolve_region("lombarda").unwrap(), "lombardia");
+    }
+
+    #[test]
+    fn rejects_unknown_regions() {
+        let error = resolve_region("baviera").unwrap_err();
+        assert!(error.contains("baviera") && error.contains("toscana"), "{error}");
+    }
+}
@@ -0,0 +1,74 @@
+/// The `robots.txt` rules applying to us: the ones of the `*` user agent group, as we don't send a user agent of our own.
+pub struct Robots {
+    /// (allow, path pattern)
+    rules: Vec<(bool, String)>,
+}
+
+impl Robots {
+    pub fn parse(text: &str) -> Self {
+        let mut rules = Vec::new();
+        // consecutive user-agent lines share the rules that follow them
+        let mut group_agents = Vec::new();
+        let mut in_rules = false;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else { continue; };
+            let (key, value) = (key.trim().to_lowercase(), value.trim());
+
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        group_agents.clear();
+                        in_rules = false;
+                    }
+                    group_agents.push(value.to_string());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // an empty disallow allows everything, same as no rule
+                    if group_agents.iter().any(|a| a == "*") && !value.is_empty() {
+                        rules.push((key == "allow", value.to_string()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self { rules }
+    }
+
+    /// The most specific (longest) matching rule wins, allow winning ties. No matching rule means allowed.
+    pub fn allows(&self, path: &str) -> bool {
+        self.rules.iter()
+            .filter(|(_, pattern)| matches(pattern, path))
+            .max_by_key(|(allow, pattern)| (pattern.len(), *allow))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+/// Matches a robots path pattern, where `*` is any sequence and a trailing `$` anchors the end.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern.split('*');
+    let Some(rest) = path.strip_prefix(parts.next().unwrap_or_default()) else { return false; };
+
+    let parts = parts.collect::<Vec<_>>();
+    let mut rest = rest;
+    for (i, part) in parts.iter().enumerate() {
+        // the last part of an anchored pattern must be at the very end
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    !anchored || rest.is_empty()
+}
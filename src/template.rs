@@ -0,0 +1,179 @@
+//! Output filenames built from a template with placeholders (`--output-template`), e.g. `{region}_{category}_{date}`.
+
+use crate::{cli::CliMode, error::ScraperError};
+
+const PLACEHOLDERS: [&str; 5] = ["region", "city", "category", "date", "count"];
+
+enum Part {
+    Literal(String),
+    Placeholder(&'static str),
+}
+
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+/// What the placeholders are replaced with. The missing ones are left empty.
+#[derive(Default, Clone, Copy)]
+pub struct Values<'a> {
+    pub region: Option<&'a str>,
+    pub city: Option<&'a str>,
+    pub category: Option<&'a str>,
+    /// number of businesses scraped, only known once the scrape is over
+    pub count: Option<usize>,
+}
+
+impl<'a> Values<'a> {
+    /// The values given on the command line: a Search query counts as the category, and its location as the city.
+    pub fn of(mode: &'a CliMode) -> Self {
+        match mode {
+            CliMode::Search(params) => Values { city: params.location.as_deref(), category: Some(&params.query), ..Default::default() },
+            CliMode::Filter(params) => Values {
+                region: Some(&params.region),
+                city: params.city.as_deref(),
+                category: params.category.as_deref(),
+                count: None,
+            },
+            CliMode::Combined(params) => Values {
+                region: Some(&params.filter.region),
+                city: params.filter.city.as_deref().or(params.location.as_deref()),
+                category: params.filter.category.as_deref().or(Some(&params.query)),
+                count: None,
+            },
+            _ => Values::default(),
+        }
+    }
+}
+
+impl OutputTemplate {
+    /// Parses the template, failing on unknown or unclosed placeholders.
+    pub fn parse(template: &str) -> Result<Self, ScraperError> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(ScraperError::Parse(format!("--output-template `{template}`: manca la `}}` di chiusura")));
+            };
+
+            let name = &rest[start + 1..start + end];
+            let Some(placeholder) = PLACEHOLDERS.iter().find(|p| **p == name) else {
+                return Err(ScraperError::Parse(format!(
+                    "--output-template `{template}`: segnaposto `{{{name}}}` sconosciuto, quelli disponibili sono {}",
+                    PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", "),
+                )));
+            };
+            parts.push(Part::Placeholder(placeholder));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    pub fn uses_count(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Placeholder("count")))
+    }
+
+    /// The output path, without the extension. The directories of the template are kept,
+    /// while the filename is made safe for every filesystem.
+    pub fn resolve(&self, values: Values) -> String {
+        let count = values.count.map(|c| c.to_string());
+        let date = today();
+
+        let path = self.parts.iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                // a value can't add directories
+                Part::Placeholder(placeholder) => match *placeholder {
+                    "region" => values.region,
+                    "city" => values.city,
+                    "category" => values.category,
+                    "date" => Some(date.as_str()),
+                    _ => count.as_deref(),
+                }.unwrap_or_default().replace(['/', '\\'], "_"),
+            })
+            .collect::<String>();
+
+        let (dir, filename) = match path.rfind(['/', '\\']) {
+            Some(i) => path.split_at(i + 1),
+            None => ("", path.as_str()),
+        };
+        format!("{dir}{}", sanitize_filename(filename))
+    }
+}
+
+/// Replaces the characters not allowed in filenames (or awkward in a shell) with `_`,
+/// and trims the separators left at the ends by empty placeholders.
+fn sanitize_filename(filename: &str) -> String {
+    let sanitized = filename.chars()
+        .map(|c| if c.is_control() || c.is_whitespace() || r#"<>:"/\|?*"#.contains(c) { '_' } else { c })
+        .collect::<String>();
+
+    match sanitized.trim_matches(['_', '-', '.']) {
+        "" => "output".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Today's UTC date, as `YYYY-MM-DD`.
+fn today() -> String {
+    crate::har::iso_date(std::time::SystemTime::now())[..10].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Values<'static> {
+        Values { region: Some("lazio"), city: Some("roma"), category: Some("pizzerie"), count: Some(42) }
+    }
+
+    #[test]
+    fn parses_the_placeholders() {
+        let template = OutputTemplate::parse("{region}_{category}-{count}.x").unwrap();
+        assert!(template.uses_count());
+        assert!(!OutputTemplate::parse("{region}_{city}").unwrap().uses_count());
+        assert!(!OutputTemplate::parse("plain").unwrap().uses_count());
+
+        assert!(OutputTemplate::parse("{region").is_err());
+        assert!(OutputTemplate::parse("{province}").is_err());
+        assert!(OutputTemplate::parse("{}").is_err());
+    }
+
+    #[test]
+    fn resolves_the_placeholders() {
+        let template = OutputTemplate::parse("{region}_{city}_{category}_{count}").unwrap();
+        assert_eq!(template.resolve(values()), "lazio_roma_pizzerie_42");
+        assert_eq!(OutputTemplate::parse("{date}").unwrap().resolve(values()), today());
+        assert_eq!(today().len(), 10);
+
+        // the missing values are left empty, without the separators at the ends
+        assert_eq!(template.resolve(Values { region: Some("lazio"), ..Default::default() }), "lazio");
+        let template = OutputTemplate::parse("{category}_{city}").unwrap();
+        assert_eq!(template.resolve(Values { category: Some("pizzerie"), ..Default::default() }), "pizzerie");
+        assert_eq!(template.resolve(Values::default()), "output");
+    }
+
+    #[test]
+    fn keeps_the_template_directories_only() {
+        let template = OutputTemplate::parse("out/{region}/{category}").unwrap();
+        assert_eq!(template.resolve(values()), "out/lazio/pizzerie");
+
+        let values = Values { category: Some("../bar/pizzerie"), ..values() };
+        assert_eq!(template.resolve(values), "out/lazio/bar_pizzerie");
+    }
+
+    #[test]
+    fn sanitizes_the_filename() {
+        assert_eq!(sanitize_filename("bar e caffe'"), "bar_e_caffe'");
+        assert_eq!(sanitize_filename(r#"a<b>c:d"e|f?g*h\i"#), "a_b_c_d_e_f_g_h_i");
+        assert_eq!(sanitize_filename("_-pizzerie.-_"), "pizzerie");
+        assert_eq!(sanitize_filename("tab\there"), "tab_here");
+        assert_eq!(sanitize_filename("__"), "output");
+    }
+}
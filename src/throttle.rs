@@ -0,0 +1,109 @@
+//! Adaptive rate limit of the PagineGialle requests (`--throttle-on-429`): each HTTP 429 halves the request rate,
+//! which goes back up step by step once the site stops complaining.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The dispatch rate before the first 429 is measured over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// 429s within this time from a slowdown come from requests sent at the old rate, so they don't slow down again.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+/// Time without 429s after which the rate is raised by a step.
+const QUIET_PERIOD: Duration = Duration::from_secs(30);
+const RAMP_UP_FACTOR: f64 = 1.5;
+/// Requests per second the rate is never halved below.
+const MIN_RATE: f64 = 0.2;
+
+pub struct Throttle {
+    debug: bool,
+    /// when the dispatch times started being recorded
+    window_start: Cell<Instant>,
+    /// dispatch times within the last [`RATE_WINDOW`], while unthrottled
+    dispatched: RefCell<VecDeque<Instant>>,
+    /// requests per second, `None` while unthrottled
+    rate: Cell<Option<f64>>,
+    /// the rate measured before the first 429, above which there's no need to throttle anymore
+    ceiling: Cell<f64>,
+    next_slot: Cell<Instant>,
+    /// last slowdown or ramp up
+    last_change: Cell<Instant>,
+}
+
+impl Throttle {
+    pub fn new(debug: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            debug,
+            window_start: Cell::new(now),
+            dispatched: RefCell::new(VecDeque::new()),
+            rate: Cell::new(None),
+            ceiling: Cell::new(f64::INFINITY),
+            next_slot: Cell::new(now),
+            last_change: Cell::new(now),
+        }
+    }
+
+    /// Waits for the slot of the next request. Unthrottled requests go right away.
+    pub async fn wait(&self) {
+        let now = Instant::now();
+        let Some(rate) = self.ramp_up(now) else {
+            let mut dispatched = self.dispatched.borrow_mut();
+            dispatched.push_back(now);
+            while dispatched.front().is_some_and(|&t| now - t > RATE_WINDOW) {
+                dispatched.pop_front();
+            }
+            return;
+        };
+
+        let slot = self.next_slot.get().max(now);
+        self.next_slot.set(slot + Duration::from_secs_f64(1.0 / rate));
+        tokio::time::sleep_until(slot.into()).await;
+    }
+
+    /// Halves the rate on a 429, unless it was just lowered.
+    pub fn too_many_requests(&self) {
+        let now = Instant::now();
+        let rate = match self.rate.get() {
+            Some(_) if now - self.last_change.get() < SETTLE_TIME => return,
+            Some(rate) => rate,
+            None => {
+                // a run just started has sent less than a whole window of requests
+                let elapsed = (now - self.window_start.get()).clamp(Duration::from_secs(1), RATE_WINDOW);
+                let measured = self.dispatched.borrow().len() as f64 / elapsed.as_secs_f64();
+                self.ceiling.set(measured);
+                measured
+            }
+        };
+
+        let rate = (rate / 2.0).max(MIN_RATE);
+        self.rate.set(Some(rate));
+        self.last_change.set(now);
+        if self.debug {
+            eprintln!("\rRisposta 429 da PagineGialle: velocita' ridotta a {rate:.2} richieste/s");
+        }
+    }
+
+    /// Raises the rate once it had no 429s for the quiet period, lifting the limit once back to the starting rate.
+    /// Returns the current rate.
+    fn ramp_up(&self, now: Instant) -> Option<f64> {
+        let rate = self.rate.get()?;
+        if now - self.last_change.get() < QUIET_PERIOD {
+            return Some(rate);
+        }
+
+        self.last_change.set(now);
+        let rate = Some(rate * RAMP_UP_FACTOR).filter(|&r| r < self.ceiling.get());
+        self.rate.set(rate);
+        if self.debug {
+            match rate {
+                Some(rate) => eprintln!("\rNessuna risposta 429 per {QUIET_PERIOD:?}: velocita' aumentata a {rate:.2} richieste/s"),
+                None => eprintln!("\rNessuna risposta 429 per {QUIET_PERIOD:?}: limite di velocita' rimosso"),
+            }
+        }
+        // the window restarts, so that the ceiling of the next slowdown is measured afresh
+        self.dispatched.borrow_mut().clear();
+        self.window_start.set(now);
+        rate
+    }
+}
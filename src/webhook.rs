@@ -0,0 +1,231 @@
+//! Delivery of the scraped entries to an HTTP endpoint (`--webhook`), as they are collected:
+//! the entries are POSTed in batches of NDJSON lines, one JSON object per entry.
+//! They go through the same filters and formatting as the output file, except those needing the whole scrape
+//! (--resolve-websites, --validate-websites, and merging the duplicates found later).
+
+use crate::{BusinessEntry, OutputOptions, BOOTSTRAP_RETRIES, BOOTSTRAP_RETRY_DELAY};
+
+/// What reached the webhook, for the run summary.
+#[derive(Default)]
+pub struct Delivery {
+    pub sent: usize,
+    /// entries of the batches that failed even after the retries
+    pub failed: usize,
+    pub failed_batches: usize,
+}
+
+impl Delivery {
+    pub fn absorb(&mut self, other: Delivery) {
+        self.sent += other.sent;
+        self.failed += other.failed;
+        self.failed_batches += other.failed_batches;
+    }
+}
+
+/// Handle of the task posting the batches, so that collecting the entries never waits on the endpoint.
+pub struct Webhook {
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+    task: tokio::task::JoinHandle<Delivery>,
+    /// the filters and formatting of the output, applied to each entry before it's queued
+    options: OutputOptions,
+}
+
+impl Webhook {
+    /// Starts the delivery task: a batch is posted once it has `batch_size` entries, or every `flush_interval` if not empty.
+    pub fn start(client: reqwest::Client, url: String, batch_size: usize, flush_interval: std::time::Duration, options: OutputOptions) -> Self {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<String>();
+
+        let task = tokio::spawn(async move {
+            let mut delivery = Delivery::default();
+            let mut batch = Vec::new();
+            // a zero interval would make the timer panic
+            let mut flush = tokio::time::interval(flush_interval.max(std::time::Duration::from_millis(1)));
+
+            loop {
+                tokio::select! {
+                    line = receiver.recv() => match line {
+                        Some(line) => {
+                            batch.push(line);
+                            if batch.len() >= batch_size {
+                                post(&client, &url, std::mem::take(&mut batch), &mut delivery).await;
+                            }
+                        }
+                        None => {
+                            post(&client, &url, batch, &mut delivery).await;
+                            return delivery;
+                        }
+                    },
+                    _ = flush.tick() => post(&client, &url, std::mem::take(&mut batch), &mut delivery).await,
+                }
+            }
+        });
+
+        Self { sender, task, options }
+    }
+
+    /// Queues an entry, serialized by [`to_line`], for the next batch.
+    /// The entries the output would leave out (--exclude-phones-file, --strict-phones) are not sent.
+    pub fn send(&self, entry: &BusinessEntry) {
+        let mut entry = entry.clone();
+        if entry.has_phone_in(&self.options.excluded_phones) || (self.options.strict_phones && !entry.retain_valid_phones()) {
+            return;
+        }
+        self.options.apply(&mut entry);
+        let Some(line) = to_line(&entry) else { return; };
+
+        // the task only stops once the sender is dropped
+        self.sender.send(line).expect("il task del webhook e' terminato");
+    }
+
+    /// Posts the entries left, and returns what was delivered.
+    pub async fn finish(self) -> Delivery {
+        drop(self.sender);
+        self.task.await.expect("il task del webhook e' fallito")
+    }
+}
+
+/// The NDJSON line of an entry, with every field and in the same shape as the JSON output.
+pub fn to_line(entry: &BusinessEntry) -> Option<String> {
+    let columns = (0..BusinessEntry::CSV_HEADER.len()).collect::<Vec<_>>();
+    crate::json::to_string(&crate::JsonRecord { columns: &columns, record: entry.csv_record() })
+        .map_err(|e| eprintln!("\rImpossibile serializzare l'attivita' per il webhook: {e}"))
+        .ok()
+}
+
+/// Posts a batch, retrying network and server errors with the same backoff as the bootstrap requests.
+async fn post(client: &reqwest::Client, url: &str, batch: Vec<String>, delivery: &mut Delivery) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let body = batch.join("\n") + "\n";
+    let mut delay = BOOTSTRAP_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let res = client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+
+        let err = match res {
+            Ok(_) => {
+                delivery.sent += batch.len();
+                return;
+            }
+            Err(e) => e,
+        };
+        // client errors won't go away by retrying
+        if attempt >= BOOTSTRAP_RETRIES || err.status().is_some_and(|s| s.is_client_error()) {
+            eprintln!("\rInvio al webhook fallito per {} attivita': {err}", batch.len());
+            delivery.failed += batch.len();
+            delivery.failed_batches += 1;
+            return;
+        }
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Answers each request with the status, sending its body to the returned receiver. Returns the url of the server too.
+    fn endpoint(status: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{BufRead, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender.send(String::from_utf8(body).unwrap()).unwrap();
+                stream.write_all(format!("HTTP/1.1 {status}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n").as_bytes()).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn start(url: String, batch_size: usize, flags: &[&str]) -> Webhook {
+        let args = ["paginegialle-scraper"].iter().chain(flags).chain(&["filter", "lazio"]);
+        let options = OutputOptions::from_cli(&crate::Cli::parse_from(args)).unwrap();
+        Webhook::start(reqwest::Client::new(), url, batch_size, std::time::Duration::from_secs(60), options)
+    }
+
+    fn entry(name: &str, phones: &str) -> BusinessEntry {
+        BusinessEntry { name: name.to_string(), phones: crate::phone::unpack(phones), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn posts_the_entries_in_batches() {
+        let (url, bodies) = endpoint("200 OK");
+        let webhook = start(url, 2, &[]);
+        for name in ["Pizzeria Da Mario", "Trattoria Roma", "Bar Centrale"] {
+            webhook.send(&entry(name, "055 123456"));
+        }
+        let delivery = webhook.finish().await;
+        assert_eq!((delivery.sent, delivery.failed, delivery.failed_batches), (3, 0, 0));
+
+        // a full batch, then the entry left once the channel is closed
+        let bodies = bodies.try_iter().collect::<Vec<_>>();
+        let lines = bodies.iter().map(|b| b.lines().count()).collect::<Vec<_>>();
+        assert_eq!(lines, [2, 1]);
+        assert!(bodies[0].starts_with(r#"{"name":"Pizzeria Da Mario","#) && bodies[0].ends_with("}\n"));
+        assert!(bodies[1].contains(r#""name":"Bar Centrale""#));
+    }
+
+    #[tokio::test]
+    async fn sends_the_entries_as_the_output_has_them() {
+        let (url, bodies) = endpoint("200 OK");
+        let webhook = start(url, 10, &["--strict-phones", "--normalize"]);
+        webhook.send(&entry("  Pizzeria   Da Mario ,", "055 123456 | chiama ora"));
+        // left out of the output, so not sent either
+        webhook.send(&entry("Trattoria Roma", "chiama ora"));
+        assert_eq!(webhook.finish().await.sent, 1);
+
+        let body = bodies.recv().unwrap();
+        assert!(body.starts_with(r#"{"name":"Pizzeria Da Mario","#), "{body}");
+        assert!(body.contains(r#""raw":"055 123456""#) && !body.contains("chiama ora"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn reports_the_rejected_batches() {
+        let (url, bodies) = endpoint("400 Bad Request");
+        let webhook = start(url, 2, &[]);
+        for name in ["Pizzeria Da Mario", "Trattoria Roma", "Bar Centrale"] {
+            webhook.send(&entry(name, "055 123456"));
+        }
+        let delivery = webhook.finish().await;
+        assert_eq!((delivery.sent, delivery.failed, delivery.failed_batches), (0, 3, 2));
+        // client errors are not retried
+        assert_eq!(bodies.try_iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn finishes_without_posting_when_nothing_was_sent() {
+        let (url, bodies) = endpoint("200 OK");
+        let delivery = start(url, 2, &[]).finish().await;
+        assert_eq!((delivery.sent, delivery.failed), (0, 0));
+        assert_eq!(bodies.try_iter().count(), 0);
+    }
+}